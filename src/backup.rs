@@ -0,0 +1,285 @@
+//! Backups of live tables: a compacted base and increments of the log.
+//!
+//! `Table::backup_to` writes the current contents of a table as a
+//! compacted log, which can be opened as a table itself, and beside it
+//! at `backup_point_path` the point the table log had reached:
+//!
+//!     magic | frame(version, dev, ino, offset)
+//!
+//! `Table::backup_since` copies the bytes appended to the log since a
+//! point to an increment, written as
+//!
+//!     magic | frame(version, dev, ino, start, end, crc32) | bytes
+//!
+//! An increment only follows a point in the same log, identified by
+//! its device and inode numbers, so once the log has been replaced by
+//! a compaction or checkpoint a new base must be taken. `restore`
+//! checks that each increment follows the one before and is intact,
+//! and that the log they reassemble replays cleanly.
+
+use std::fs::{File, OpenOptions, metadata, remove_file, rename};
+use std::io;
+use std::io::prelude::*;
+
+use crc32::crc32;
+use encode::{Encode, frame};
+use decode::*;
+use header::TypeDescriptor;
+use hint::hint_path;
+use table::{TableError, TableOptions};
+
+/// The first bytes of a backup point file.
+pub const POINT_MAGIC: [u8; 4] = [0x89, b'B', b'T', b'P'];
+
+/// The first bytes of a backup increment.
+pub const INCREMENT_MAGIC: [u8; 4] = [0x89, b'B', b'T', b'I'];
+
+const BACKUP_VERSION: u64 = 1;
+
+/// How far a backup of a table goes: a length of its log, which is
+/// identified by its device and inode numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupPoint {
+    pub dev: u64,
+    pub ino: u64,
+    pub offset: u64,
+}
+
+/// Returns the path the point reached by the base backup at
+/// `backup_path` is recorded at.
+pub fn backup_point_path(backup_path: &str) -> String {
+    format!("{}.point", backup_path)
+}
+
+// Writes buf to path by way of a temporary file, synced to disk first.
+pub(crate) fn write_synced(path: &str, buf: &[u8]) -> io::Result<()> {
+    let mut newpath = path.to_string();
+    newpath.push('~');
+    let mut f = OpenOptions::new().write(true).create(true).truncate(true).open(&newpath)?;
+    f.write_all(buf)?;
+    f.sync_all()?;
+    rename(newpath, path)
+}
+
+// Reads the file at path, checks it starts with magic and returns the
+// n fields framed after it and the bytes which follow.
+fn read_framed(path: &str, magic: &[u8; 4], n: usize) -> Result<(Vec<u64>, Vec<u8>), TableError> {
+    let mut buf = Vec::new();
+    match File::open(path).and_then(|mut f| f.read_to_end(&mut buf)) {
+        Err(ioerr) => return Err(TableError::IOError(ioerr)),
+        Ok(_) => (),
+    };
+    if buf.len() < magic.len() || buf[..magic.len()] != magic[..] {
+        return Err(TableError::DecodeError(DecodeError::Corrupt));
+    }
+    let mut src = &buf[magic.len()..];
+    let payload = match decode_frame(&mut src, &mut DecodeStats::default()) {
+        Err(de) => return Err(TableError::DecodeError(de)),
+        Ok(payload) => payload,
+    };
+    let mut p = &payload[..];
+    let mut fields = Vec::with_capacity(n);
+    for _ in 0..n {
+        match u64::decode(&mut p) {
+            Err(de) => return Err(TableError::DecodeError(de)),
+            Ok(x) => fields.push(x),
+        };
+    }
+    if fields[0] > BACKUP_VERSION {
+        return Err(TableError::UnsupportedVersion(fields[0]));
+    }
+    Ok((fields, src.to_vec()))
+}
+
+impl BackupPoint {
+    /// Reads the point recorded at `path`.
+    pub fn read(path: &str) -> Result<BackupPoint, TableError> {
+        match read_framed(path, &POINT_MAGIC, 4) {
+            Err(e) => Err(e),
+            Ok((f, _)) => Ok(BackupPoint { dev: f[1], ino: f[2], offset: f[3] }),
+        }
+    }
+
+    /// Records the point at `path`.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let mut payload: Vec<u8> = Vec::new();
+        for n in &[BACKUP_VERSION, self.dev, self.ino, self.offset] {
+            n.encode(&mut payload)?;
+        }
+        let mut buf: Vec<u8> = POINT_MAGIC.to_vec();
+        frame(&mut buf, &payload);
+        write_synced(path, &buf)
+    }
+
+    /// Whether a log at this point has gone on from `since`.
+    pub fn follows(&self, since: &BackupPoint) -> bool {
+        self.dev == since.dev && self.ino == since.ino && self.offset >= since.offset
+    }
+}
+
+/// Writes the log bytes between points `start` and `end` as an
+/// increment at `path`.
+pub fn write_increment(path: &str, start: &BackupPoint, end: &BackupPoint, bytes: &[u8]) -> io::Result<()> {
+    let mut payload: Vec<u8> = Vec::new();
+    for n in &[BACKUP_VERSION, start.dev, start.ino, start.offset, end.offset, crc32(bytes) as u64] {
+        n.encode(&mut payload)?;
+    }
+    let mut buf: Vec<u8> = INCREMENT_MAGIC.to_vec();
+    frame(&mut buf, &payload);
+    buf.extend_from_slice(bytes);
+    write_synced(path, &buf)
+}
+
+/// Reads the increment at `path`, returning the points it starts and
+/// ends at and its log bytes, which are checked to be intact.
+pub fn read_increment(path: &str) -> Result<(BackupPoint, BackupPoint, Vec<u8>), TableError> {
+    let (f, bytes) = match read_framed(path, &INCREMENT_MAGIC, 6) {
+        Err(e) => return Err(e),
+        Ok(r) => r,
+    };
+    let start = BackupPoint { dev: f[1], ino: f[2], offset: f[3] };
+    let end = BackupPoint { dev: f[1], ino: f[2], offset: f[4] };
+    if f[4] < f[3] || bytes.len() as u64 != f[4] - f[3] || crc32(&bytes) as u64 != f[5] {
+        return Err(TableError::DecodeError(DecodeError::Corrupt));
+    }
+    Ok((start, end, bytes))
+}
+
+impl TableOptions {
+    /// Reassembles at `path` the table backed up to the base at `base`
+    /// and then to `increments`, in order, returning the point the
+    /// restored table reaches. Each increment must follow the backup
+    /// before it, and the restored log is replayed with these options
+    /// before it is put in place. Fails with `TableError::Exists` if
+    /// there is already a file at `path`.
+    pub fn restore<K, V>(&self, base: &str, increments: &[&str], path: &str) -> Result<BackupPoint, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        if metadata(path).is_ok() {
+            return Err(TableError::Exists(path.to_string()));
+        }
+        let mut point = match BackupPoint::read(&backup_point_path(base)) {
+            Err(e) => return Err(e),
+            Ok(p) => p,
+        };
+        let mut buf = Vec::new();
+        match File::open(base).and_then(|mut f| f.read_to_end(&mut buf)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(_) => (),
+        };
+        for inc in increments {
+            let (start, end, bytes) = match read_increment(inc) {
+                Err(e) => return Err(e),
+                Ok(r) => r,
+            };
+            if start != point {
+                return Err(TableError::BackupMismatch);
+            }
+            buf.extend_from_slice(&bytes);
+            point = end;
+        }
+
+        let mut newpath = path.to_string();
+        newpath.push('~');
+        match write_synced(&newpath, &buf) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        let mut options = self.clone();
+        options.write(false);
+        options.segment_size = None;
+        let replayed = match options.open::<K, V>(&newpath) {
+            Err(e) => Err(e),
+            Ok(ref t) if t.stats().truncated() > 0 => Err(TableError::DecodeError(DecodeError::Corrupt)),
+            Ok(_) => Ok(()),
+        };
+        if let Err(e) = replayed {
+            let _ = remove_file(&newpath);
+            return Err(e);
+        }
+        let _ = remove_file(hint_path(&path));
+        match rename(&newpath, path) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(point),
+        }
+    }
+}
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_backup() {
+    use std::time::Duration;
+    use table::{Table, WriteBatch};
+
+    let path = temp_path("backup");
+    let base = temp_path("base");
+    let incs = [temp_path("inc1"), temp_path("inc2"), temp_path("inc3")];
+    let restored = temp_path("restored");
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    for i in 0..10 {
+        t.insert(i, vec![i as u8; 10]).unwrap();
+    }
+    t.remove(&3).unwrap();
+    let p0 = t.backup_to(&base).unwrap();
+    assert_eq!(BackupPoint::read(&backup_point_path(&base)).unwrap(), p0);
+    // The base is a compacted table in its own right.
+    {
+        let b: Table<i64, Vec<u8>> = Table::open(&base).unwrap();
+        assert_eq!(b.stats().discarded(), 0);
+        assert_eq!(b.get(&3), None);
+        assert_eq!(b.get(&4), Some(&vec![4; 10]));
+    }
+
+    t.insert(20, b"twenty".to_vec()).unwrap();
+    t.remove(&4).unwrap();
+    let p1 = t.backup_since(&p0, &incs[0]).unwrap();
+    assert!(p1.follows(&p0) && p1.offset > p0.offset);
+    let mut batch = WriteBatch::new();
+    batch.insert(21, b"twenty-one".to_vec());
+    batch.remove(5);
+    t.write(batch).unwrap();
+    t.insert_with_ttl(22, b"later".to_vec(), Duration::from_secs(3600)).unwrap();
+    let p2 = t.backup_since(&p1, &incs[1]).unwrap();
+
+    assert_eq!(TableOptions::new().restore::<i64, Vec<u8>>(&base, &[&incs[0], &incs[1]], &restored).unwrap(), p2);
+    {
+        let r: Table<i64, Vec<u8>> = Table::open(&restored).unwrap();
+        assert_eq!((&r).into_iter().collect::<Vec<_>>(), (&t).into_iter().collect::<Vec<_>>());
+        assert!(r.next_expiry().is_some());
+    }
+    match TableOptions::new().restore::<i64, Vec<u8>>(&base, &[], &restored) {
+        Err(TableError::Exists(_)) => (),
+        _ => panic!("expected the restored table to exist"),
+    };
+    remove_file(&restored).unwrap();
+
+    // Increments must follow on from one another, and be intact.
+    match TableOptions::new().restore::<i64, Vec<u8>>(&base, &[&incs[1]], &restored) {
+        Err(TableError::BackupMismatch) => (),
+        _ => panic!("expected a missing increment to be noticed"),
+    };
+    let mut bytes = Vec::new();
+    File::open(&incs[1]).unwrap().read_to_end(&mut bytes).unwrap();
+    let n = bytes.len();
+    bytes[n - 3] ^= 0xff;
+    File::create(&incs[1]).unwrap().write_all(&bytes).unwrap();
+    match TableOptions::new().restore::<i64, Vec<u8>>(&base, &[&incs[0], &incs[1]], &restored) {
+        Err(TableError::DecodeError(DecodeError::Corrupt)) => (),
+        _ => panic!("expected a damaged increment to be noticed"),
+    };
+    assert!(metadata(&restored).is_err());
+
+    // Once the log is replaced, increments need a new base.
+    t.compact(&path).unwrap();
+    match t.backup_since(&p2, &incs[2]) {
+        Err(TableError::BackupMismatch) => (),
+        _ => panic!("expected the replaced log to be noticed"),
+    };
+
+    drop(t);
+    for p in [path.clone(), base.clone()].iter().chain(incs.iter()) {
+        remove_table(p);
+    }
+}
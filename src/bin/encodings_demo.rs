@@ -3,7 +3,7 @@ extern crate table;
 use std::io;
 use std::collections::BTreeMap;
 
-use table::encode::{ Encode, encode, encode_to_hex };
+use table::encode::{ Encode, encode, encode_record, encode_to_hex };
 use table::decode::*;
 
 fn main() {
@@ -84,8 +84,7 @@ fn main() {
     }
     println!("data.len(): {}", d2.len());
 
-    (&17i64).encode(&mut v2).unwrap();
-    None.encode(&mut v2).unwrap();
+    encode_record(&mut v2, &17i64, None::<&Vec<u8>>).unwrap();
 
     let d2;
     {
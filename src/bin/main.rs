@@ -1,13 +1,17 @@
 extern crate table;
 extern crate readline;
 
+use std::collections::Bound;
 use std::env;
 use std::io;
 
 use table::table::Table;
+use table::index::IndexedTable;
 use table::util::repr;
 use table::encode::Encode;
+use table::expiry::Unexpired;
 use table::decode::Decode;
+use table::header::TypeDescriptor;
 use table::record::print_json;
 use table::json::*;
 
@@ -44,7 +48,7 @@ impl MyIO for i64 {
 type Bytes = Vec<u8>;
 
 struct JSONTableIter<'a> {
-    raw: std::collections::btree_map::Iter<'a, i64, Vec<u8>>,
+    raw: Unexpired<'a, i64, std::collections::btree_map::Iter<'a, i64, Vec<u8>>>,
 }
 
 type JSONRow = std::collections::BTreeMap<String, JSON>;
@@ -81,7 +85,7 @@ impl<'a> IntoIterator for &'a JSONTable<'a> {
 }
 
 
-fn tdemo<K: MyIO + Ord + Encode + Decode>(path: &str, args: &[String])
+fn tdemo<K: MyIO + Ord + Clone + Encode + Decode + TypeDescriptor>(path: &str, args: &[String])
 {
     if args.len() == 2 {
         if args[1] == "items" {
@@ -170,13 +174,9 @@ fn unwrap_vs(data: &JSON) -> Option<Vec<String>> {
 fn table_cmd(rt: &mut Table<i64,Vec<u8>>, cmd: &str, data: &JSON) {
     let mut t = JSONTable { raw: rt };
     if cmd == "insert" {
-        let key = match t.raw.max_key() {
-            Some(ref k) => *k + 1,
-            None => 0,
-        };
         let mut value: Vec<u8> = Vec::new();
         data.encode(&mut value).unwrap();
-        t.raw.insert(key, value).unwrap();
+        t.raw.insert_auto(value).unwrap();
     } else if cmd == "index" {
         if let Some(keys) = unwrap_vs(data) {
             for (pk, row) in &t {
@@ -208,7 +208,13 @@ fn table_cmd(rt: &mut Table<i64,Vec<u8>>, cmd: &str, data: &JSON) {
     }
 }
 
-fn json_table_cmd(t: &mut Table<i64,JSONRow>, cmd: &str, data: &JSON) {
+fn print_row(key: i64, row: &JSONRow) {
+    let mut mc = row.clone();
+    mc.insert(String::from("#"), JSON::Int(key));
+    println!("{}", json_encode(&JSON::Object(mc)));
+}
+
+fn json_table_cmd(t: &mut IndexedTable, cmd: &str, data: &JSON) {
     if cmd == "insert" {
         let row = match *data {
             JSON::Object(ref m) => m,
@@ -217,37 +223,51 @@ fn json_table_cmd(t: &mut Table<i64,JSONRow>, cmd: &str, data: &JSON) {
                 return;
             },
         };
-        let key = match t.max_key() {
-            Some(ref k) => *k + 1,
-            None => 0,
-        };
-        t.insert(key, row.clone()).unwrap();
+        t.insert_auto(row.clone()).unwrap();
     } else if cmd == "index" {
+        // index ["field", ...] declares an index named by its fields.
         if let Some(keys) = unwrap_vs(data) {
-            for (pk, row) in &*t {
-                let mut vj = Vec::new();
-                for key in &keys {
-                    vj.push(
-                        if key == "#" {
-                            JSON::Int(*pk)
-                        } else {
-                            match row.get(key) {
-                                Some(val) => val.clone(),
-                                None => JSON::Null,
-                            }
-                        }
-                    )
-                };
-                println!("{}", json_encode(&JSON::Array(vj)));
-            }
+            let name = keys.join(",");
+            let fields: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+            match t.create_index(&name, &fields) {
+                Err(e) => println!("error creating index: {:?}", e),
+                Ok(()) => println!("index {}", name),
+            };
         } else {
             println!("index requires an array of key strings");
         }
+    } else if cmd == "find" || cmd == "range" {
+        // find [["field", ...], value, ...] and
+        // range [["field", ...], [from, ...], [to, ...]]
+        let args = match *data {
+            JSON::Array(ref v) if v.len() > 0 => v,
+            _ => {
+                println!("{} requires an array of the index fields and values", cmd);
+                return;
+            },
+        };
+        let name = match unwrap_vs(&args[0]) {
+            Some(keys) => keys.join(","),
+            None => {
+                println!("{} requires an array of key strings", cmd);
+                return;
+            },
+        };
+        let rows = if cmd == "find" {
+            t.find(&name, &args[1..])
+        } else {
+            let bound = |i: usize| match args.get(i) {
+                Some(&JSON::Array(ref v)) => Bound::Included(&v[..]),
+                _ => Bound::Unbounded,
+            };
+            t.find_range(&name, bound(1), bound(2))
+        };
+        for (key, row) in rows {
+            print_row(key, row);
+        }
     } else if cmd == "select" {
-        for (key, m) in &*t {
-            let mut mc = m.clone();
-            mc.insert(String::from("#"), JSON::Int(*key));
-            println!("{}", json_encode(&JSON::Object(mc)));
+        for (key, m) in t.table() {
+            print_row(*key, m);
         }
     } else {
         println!("cmd: {} {:?}", cmd, data);
@@ -309,7 +329,7 @@ fn repl() {
 }
 
 fn json_repl() {
-    let mut t: Table<i64,JSONRow> = match Table::open_rw("ben.bt") {
+    let mut t = match IndexedTable::open("ben.bt") {
         Err(e) => { println!("error opening table : {:?}", e); return; },
         Ok(t) => t,
     };
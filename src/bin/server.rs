@@ -55,12 +55,12 @@ impl Task for SimpleReader {
             let mut consumed: usize = 0;
             match self.state {
                 ReaderState::Begin => {
-                    let mut s = DecodeStats { read: 0, discarded: 0 };
+                    let mut s = DecodeStats::default();
                     let mut c = Cursor::new(&self.buffer);
                     match u64::decode_stats(&mut c, &mut s) {
                         Ok(n) => {
                             self.state = ReaderState::ReadSize(n as usize);
-                            consumed = s.read;
+                            consumed = s.read();
                         }
                         Err(DecodeError::EOF) => (), // Ok, just need more data.
                         Err(_) => {
@@ -0,0 +1,204 @@
+//! Checkpoints: snapshots of a table written beside its log.
+//!
+//! Checkpoint `gen` of the table at `path` is written to
+//! `path.ckpt.gen` as
+//!
+//!     magic | frame(version, gen, body length, body crc32) | body
+//!
+//! where the body holds the table as encoded by `Encode for BTreeMap`,
+//! with its values stored as the log stores them (see `codec`) and
+//! preceded by when they expire, if they do (see `expiry`).
+//! The log then restarts with a `Control::Checkpoint(gen)` record after
+//! its header, so that opening the table loads the checkpoint and
+//! replays only the records written since. The log it replaces is kept
+//! as `path.prev`, along with the checkpoint before, so that the table
+//! can still be recovered if the newest checkpoint is damaged.
+
+use std::fs::{File, OpenOptions, read_dir, rename};
+use std::io;
+use std::io::prelude::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crc32::crc32;
+use codec::Codecs;
+use encode::{Encode, Control, frame, frame_control, frame_record};
+use expiry::Expiries;
+use decode::*;
+use header::Header;
+
+/// The first bytes of a checkpoint file.
+pub const CHECKPOINT_MAGIC: [u8; 4] = [0x89, b'B', b'T', b'C'];
+
+const CHECKPOINT_VERSION: u64 = 1;
+
+/// Returns the path of checkpoint `gen` of the table at `path`.
+pub fn checkpoint_path(path: &str, gen: u64) -> String {
+    format!("{}.ckpt.{}", path, gen)
+}
+
+/// Returns the path the log replaced by the latest checkpoint is kept at.
+pub fn previous_log_path(path: &str) -> String {
+    format!("{}.prev", path)
+}
+
+/// Lists the generations of the checkpoints of the table at `path`,
+/// newest first.
+pub fn checkpoints(path: &str) -> io::Result<Vec<u64>> {
+    let p = Path::new(path);
+    let dir = match p.parent() {
+        Some(d) if d != Path::new("") => d,
+        _ => Path::new("."),
+    };
+    let prefix = match p.file_name().and_then(|n| n.to_str()) {
+        None => return Ok(Vec::new()),
+        Some(name) => format!("{}.ckpt.", name),
+    };
+    let mut gens: Vec<u64> = Vec::new();
+    for entry in read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(name) = name.to_str() {
+            if name.starts_with(&prefix) {
+                if let Ok(gen) = name[prefix.len()..].parse() {
+                    gens.push(gen);
+                }
+            }
+        }
+    }
+    gens.sort_by(|a, b| b.cmp(a));
+    Ok(gens)
+}
+
+/// Writes `map` as checkpoint `gen` of the table at `path`, with values
+/// stored with `codecs` and preceded by when they expire, if they do.
+/// Syncs it to disk first if `sync` is set.
+pub fn write_checkpoint<K, V>(path: &str, gen: u64, map: &BTreeMap<K, V>, expiries: &Expiries<K>, codecs: &Codecs,
+                              sync: bool) -> io::Result<()>
+    where K: Encode + Ord + Clone, V: Encode
+{
+    let mut body: Vec<u8> = Vec::with_capacity(map.encode_size());
+    if codecs.is_empty() && expiries.len() == 0 {
+        map.encode(&mut body)?;
+    } else {
+        for (key, value) in map {
+            if let Some(t) = expiries.get(key) {
+                frame_control(&mut body, Control::Expires(t));
+            }
+            match codecs.apply(value) {
+                Some(stored) => frame_record(&mut body, key, Some(&stored)),
+                None => frame_record(&mut body, key, Some(value)),
+            }
+        }
+    }
+    match write_checkpoint_body(path, gen, &body, sync) {
+        Err(err) => Err(err),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Writes checkpoint `gen` of the table at `path` with `body` already
+/// encoded, syncing it to disk first if `sync` is set. Returns the
+/// offset of the body in the checkpoint file.
+pub fn write_checkpoint_body(path: &str, gen: u64, body: &[u8], sync: bool) -> io::Result<usize> {
+    let mut payload: Vec<u8> = Vec::new();
+    for n in &[CHECKPOINT_VERSION, gen, body.len() as u64, crc32(body) as u64] {
+        n.encode(&mut payload)?;
+    }
+    let mut buf: Vec<u8> = CHECKPOINT_MAGIC.to_vec();
+    frame(&mut buf, &payload);
+
+    let final_path = checkpoint_path(path, gen);
+    let mut newpath = final_path.clone();
+    newpath.push('~');
+    let mut f = OpenOptions::new().write(true).create(true).truncate(true).open(&newpath)?;
+    f.write_all(&buf)?;
+    f.write_all(body)?;
+    if sync {
+        f.sync_all()?;
+    }
+    rename(newpath, final_path)?;
+    Ok(buf.len())
+}
+
+/// Loads checkpoint `gen` of the table at `path`, with values stored
+/// with `codecs`, into `data`. Returns false, leaving `data` alone, if
+/// it is missing or damaged.
+pub fn load_checkpoint<K, V, M>(path: &str, gen: u64, data: &mut M, codecs: &Codecs) -> Result<bool, DecodeError>
+    where K: Decode, V: Decode, M: LogMap<K, V>
+{
+    let mut buf = Vec::new();
+    match File::open(checkpoint_path(path, gen)) {
+        Err(_) => return Ok(false),
+        Ok(mut f) => match f.read_to_end(&mut buf) {
+            Err(err) => return Err(DecodeError::IOError { err }),
+            Ok(_) => (),
+        },
+    };
+    if buf.len() < CHECKPOINT_MAGIC.len() || buf[..CHECKPOINT_MAGIC.len()] != CHECKPOINT_MAGIC {
+        return Ok(false);
+    }
+    let mut src = &buf[CHECKPOINT_MAGIC.len()..];
+    let mut stats = DecodeStats::default();
+    let payload = match decode_frame(&mut src, &mut stats) {
+        Err(_) => return Ok(false),
+        Ok(payload) => payload,
+    };
+    let mut p = &payload[..];
+    let mut fields = [0u64; 4];
+    for n in fields.iter_mut() {
+        *n = match u64::decode(&mut p) {
+            Err(_) => return Ok(false),
+            Ok(n) => n,
+        };
+    }
+    let [version, found, len, crc] = fields;
+    if version != CHECKPOINT_VERSION || found != gen || len != src.len() as u64 || crc != crc32(src) as u64 {
+        return Ok(false);
+    }
+    // Value locations are offsets in the checkpoint file.
+    let mut stats = DecodeStats::default();
+    stats.append(buf.len() - src.len(), 0);
+    data.replay_file(&checkpoint_path(path, gen));
+    match replay_coded(&mut src, &mut stats, data, codecs) {
+        Err(de) => Err(de),
+        Ok(()) => Ok(true),
+    }
+}
+
+/// Returns the generation of the checkpoint which the log at `path`
+/// continues from, if any.
+pub fn log_checkpoint(path: &str) -> Result<Option<u64>, DecodeError> {
+    let f = match File::open(path) {
+        Err(err) => return Err(DecodeError::IOError { err }),
+        Ok(f) => f,
+    };
+    let mut r = io::BufReader::new(f);
+    let mut stats = DecodeStats::default();
+    match Header::decode_stats(&mut r, &mut stats) {
+        Err(de) => return Err(de),
+        Ok(None) => return Ok(None),
+        Ok(Some(_)) => (),
+    };
+    let found = match r.fill_buf() {
+        Err(err) => return Err(DecodeError::IOError { err }),
+        Ok(buf) => buf.first() == Some(&0xFF),
+    };
+    if !found {
+        return Ok(None);
+    }
+    r.consume(1);
+    let payload = match decode_frame(&mut r, &mut stats) {
+        Err(_) => return Ok(None),
+        Ok(payload) => payload,
+    };
+    if payload.len() < 2 {
+        return Ok(None);
+    }
+    match u64::decode(&mut &payload[1..]) {
+        Ok(arg) => match Control::from_parts(payload[0], arg) {
+            Some(Control::Checkpoint(gen)) => Ok(Some(gen)),
+            _ => Ok(None),
+        },
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,164 @@
+//! Transforms applied to the values of a table as they are stored.
+//!
+//! A table may be given a chain of `ValueCodec`s, such as a checksum or
+//! an at-rest cipher. Each value is encoded as usual, passed through
+//! the codecs in the order they were added, and stored as the bytes
+//! which result; reading it reverses the chain. The names of the codecs
+//! are recorded in the table header, so that a table cannot be opened
+//! with a different chain to the one it was written with.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crc32::crc32;
+use encode::{Encode, encode};
+use decode::{Decode, DecodeError};
+
+/// A reversible transformation of stored values.
+pub trait ValueCodec: Send + Sync {
+    /// Identifies the codec in table headers. The name must change
+    /// whenever the bytes the codec produces would: an encrypting codec
+    /// should name its key, for example by an id or fingerprint.
+    fn name(&self) -> String;
+
+    fn encode(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Reverses `encode`, failing with `DecodeError::Checksum` or
+    /// `DecodeError::Corrupt` if `data` could not have been produced by it.
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, DecodeError>;
+}
+
+/// A chain of codecs, applied in order on write and in reverse on read.
+#[derive(Clone, Default)]
+pub struct Codecs(Vec<Arc<dyn ValueCodec>>);
+
+impl fmt::Debug for Codecs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Codecs({:?})", self.names())
+    }
+}
+
+impl Codecs {
+    pub fn new() -> Codecs {
+        Codecs(Vec::new())
+    }
+
+    /// Adds `codec` to the end of the chain.
+    pub fn push<C: ValueCodec + 'static>(&mut self, codec: C) {
+        self.0.push(Arc::new(codec));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The names of the codecs, as recorded in a table header.
+    pub fn names(&self) -> Vec<String> {
+        self.0.iter().map(|c| c.name()).collect()
+    }
+
+    /// Returns `value` as it is stored: its encoding passed through the
+    /// chain, or None if the chain is empty and it is stored as it is.
+    pub fn apply<V: Encode>(&self, value: &V) -> Option<Vec<u8>> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let mut data = encode(value);
+        for codec in &self.0 {
+            data = codec.encode(&data);
+        }
+        Some(data)
+    }
+
+    /// Decodes a value from `src` as stored with this chain: as a
+    /// length-prefixed byte string holding the output of the chain.
+    /// A removal is reported as `DecodeError::Null`.
+    pub fn decode_value<V: Decode>(&self, src: &[u8]) -> Result<V, DecodeError> {
+        if self.0.is_empty() {
+            return V::decode(&mut &src[..]);
+        }
+        let mut data = match Vec::<u8>::decode(&mut &src[..]) {
+            Err(err) => return Err(err),
+            Ok(data) => data,
+        };
+        for codec in self.0.iter().rev() {
+            data = match codec.decode(&data) {
+                Err(err) => return Err(err),
+                Ok(data) => data,
+            };
+        }
+        match V::decode(&mut &data[..]) {
+            Err(DecodeError::Null) => Err(DecodeError::Corrupt),
+            r => r,
+        }
+    }
+}
+
+/// A codec which appends a CRC-32 checksum to each value, so that
+/// damage to a stored value is detected when it is read.
+pub struct Checksum;
+
+impl ValueCodec for Checksum {
+    fn name(&self) -> String {
+        String::from("crc32")
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let crc = crc32(data);
+        let mut out = data.to_vec();
+        out.extend(&[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+        out
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        if data.len() < 4 {
+            return Err(DecodeError::Corrupt);
+        }
+        let (body, c) = data.split_at(data.len() - 4);
+        let crc = (c[0] as u32) << 24 | (c[1] as u32) << 16 | (c[2] as u32) << 8 | c[3] as u32;
+        if crc != crc32(body) {
+            return Err(DecodeError::Checksum);
+        }
+        Ok(body.to_vec())
+    }
+}
+
+// A codec for tests which flips the given bits of each byte.
+#[cfg(test)]
+pub struct Xor(pub u8);
+
+#[cfg(test)]
+impl ValueCodec for Xor {
+    fn name(&self) -> String { format!("xor-{}", self.0) }
+    fn encode(&self, data: &[u8]) -> Vec<u8> { data.iter().map(|b| b ^ self.0).collect() }
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, DecodeError> { Ok(self.encode(data)) }
+}
+
+#[test]
+fn test_codecs() {
+    let value = b"some value".to_vec();
+    let none = Codecs::new();
+    assert!(none.apply(&value).is_none());
+    assert_eq!(none.decode_value::<Vec<u8>>(&encode(&value)).unwrap(), value);
+
+    let mut codecs = Codecs::new();
+    codecs.push(Checksum);
+    codecs.push(Xor(0x5A));
+    assert_eq!(codecs.names(), vec!["crc32".to_string(), "xor-90".to_string()]);
+    let stored = encode(&codecs.apply(&value).unwrap());
+    assert_eq!(codecs.decode_value::<Vec<u8>>(&stored).unwrap(), value);
+
+    // A removal is recognised without decoding.
+    match codecs.decode_value::<Vec<u8>>(&[0xFF]) {
+        Err(DecodeError::Null) => (),
+        _ => panic!("expected a removal"),
+    };
+    // The checksum catches damage the other codecs let through.
+    let mut damaged = stored.clone();
+    let n = damaged.len();
+    damaged[n - 6] ^= 1;
+    match codecs.decode_value::<Vec<u8>>(&damaged) {
+        Err(DecodeError::Checksum) => (),
+        _ => panic!("expected a checksum error"),
+    };
+}
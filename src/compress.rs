@@ -0,0 +1,240 @@
+//! LZ77 compression of the values stored in a table log.
+//!
+//! A compressed block is the length of the uncompressed data (u64)
+//! followed by a sequence of
+//!
+//!     token | [literal length] | literals | offset | [match length]
+//!
+//! in the style of LZ4: the high and low nibbles of the token give the
+//! number of literals and the length of the match less 4, with 15
+//! meaning that more length bytes follow (each adding up to 255, the
+//! last less than 255). The offset is two bytes, little-endian, back
+//! from the end of the output so far. The last sequence has literals
+//! only and ends the block.
+
+use encode::{Encode, encode, frame_compressed, frame_record};
+use decode::{CompressionStats, Decode, DecodeError};
+
+/// How values are compressed when they are written to a table log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    /// Values of at least `MIN_COMPRESS` bytes are compressed with
+    /// `compress`, and stored so if that makes them smaller.
+    Lz,
+}
+
+/// The smallest encoded value which is compressed.
+pub const MIN_COMPRESS: usize = 32;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xFFFF;
+const HASH_BITS: u32 = 12;
+
+impl Compression {
+    /// Appends a framed record to `buf` as `encode::frame_record`
+    /// does, compressing the value if it is worth it. Returns the sizes
+    /// of the encoded value and the compressed block if it was.
+    pub fn frame_record<K: Encode, V: Encode>(&self, buf: &mut Vec<u8>, key: &K, value: Option<&V>) ->
+        Option<(usize, usize)>
+    {
+        let v = match (*self, value) {
+            (Compression::Lz, Some(v)) if v.encode_size() >= MIN_COMPRESS => v,
+            _ => {
+                frame_record(buf, key, value);
+                return None;
+            },
+        };
+        let raw = encode(v);
+        let block = compress(&raw);
+        // A compressed record takes two more bytes: a null marker and
+        // its kind.
+        if block.len() + 2 >= raw.len() {
+            frame_record(buf, key, value);
+            return None;
+        }
+        frame_compressed(buf, key, &block);
+        Some((raw.len(), block.len()))
+    }
+
+    /// Appends a framed record to `buf` as `frame_record` does, adding
+    /// the sizes of a compressed value to `stats`.
+    pub fn frame_stats<K: Encode, V: Encode>(&self, buf: &mut Vec<u8>, key: &K, value: Option<&V>,
+                                             stats: &mut CompressionStats)
+    {
+        if let Some((raw, stored)) = self.frame_record(buf, key, value) {
+            stats.records += 1;
+            stats.raw += raw;
+            stats.stored += stored;
+        }
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let n = (data[0] as u32) | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24;
+    (n.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn push_length(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize, usize)>) {
+    let lit = literals.len();
+    let mlen = match m {
+        Some((_, len)) => len - MIN_MATCH,
+        None => 0,
+    };
+    let token = (if lit < 15 { lit } else { 15 }) << 4 | (if mlen < 15 { mlen } else { 15 });
+    out.push(token as u8);
+    if lit >= 15 {
+        push_length(out, lit - 15);
+    }
+    out.extend(literals);
+    if let Some((offset, _)) = m {
+        out.push(offset as u8);
+        out.push((offset >> 8) as u8);
+        if mlen >= 15 {
+            push_length(out, mlen - 15);
+        }
+    }
+}
+
+/// Compresses `input` into a block.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(input.len() / 2 + 16);
+    (input.len() as u64).encode(&mut out).unwrap();
+    // Position + 1 of the last place each hash of 4 bytes was seen.
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let (mut i, mut anchor) = (0, 0);
+    while i + MIN_MATCH <= input.len() {
+        let h = hash(&input[i..]);
+        let candidate = table[h];
+        table[h] = i + 1;
+        if candidate > 0 {
+            let c = candidate - 1;
+            if i - c <= MAX_OFFSET && input[c..c + MIN_MATCH] == input[i..i + MIN_MATCH] {
+                let mut len = MIN_MATCH;
+                while i + len < input.len() && input[c + len] == input[i + len] {
+                    len += 1;
+                }
+                push_sequence(&mut out, &input[anchor..i], Some((i - c, len)));
+                i += len;
+                anchor = i;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    push_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn read_length(input: &[u8], pos: &mut usize, mut n: usize) -> Result<usize, DecodeError> {
+    loop {
+        let b = match input.get(*pos) {
+            None => return Err(DecodeError::Corrupt),
+            Some(b) => *b as usize,
+        };
+        *pos += 1;
+        n += b;
+        if b < 255 {
+            return Ok(n);
+        }
+    }
+}
+
+/// Decompresses a block written by `compress`.
+pub fn decompress(block: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut src = block;
+    let len = match u64::decode(&mut src) {
+        Err(_) => return Err(DecodeError::Corrupt),
+        Ok(n) => n as usize,
+    };
+    let input = src;
+    // The length is not trusted as far as allocating it all up front.
+    let mut out: Vec<u8> = Vec::with_capacity(if len < 1 << 20 { len } else { 1 << 20 });
+    let mut pos = 0;
+    loop {
+        let token = match input.get(pos) {
+            None => return Err(DecodeError::Corrupt),
+            Some(t) => *t as usize,
+        };
+        pos += 1;
+        let mut lit = token >> 4;
+        if lit == 15 {
+            lit = read_length(input, &mut pos, lit)?;
+        }
+        if pos + lit > input.len() || out.len() + lit > len {
+            return Err(DecodeError::Corrupt);
+        }
+        out.extend(&input[pos..pos + lit]);
+        pos += lit;
+        if pos == input.len() {
+            break;
+        }
+
+        if pos + 2 > input.len() {
+            return Err(DecodeError::Corrupt);
+        }
+        let offset = input[pos] as usize | (input[pos + 1] as usize) << 8;
+        pos += 2;
+        let mut mlen = token & 15;
+        if mlen == 15 {
+            mlen = read_length(input, &mut pos, mlen)?;
+        }
+        mlen += MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + mlen > len {
+            return Err(DecodeError::Corrupt);
+        }
+        // The match may overlap the bytes it produces.
+        let start = out.len() - offset;
+        for j in 0..mlen {
+            let b = out[start + j];
+            out.push(b);
+        }
+    }
+    if out.len() != len {
+        return Err(DecodeError::Corrupt);
+    }
+    Ok(out)
+}
+
+/// Returns the uncompressed length of a block written by `compress`.
+pub fn decompressed_len(block: &[u8]) -> Result<usize, DecodeError> {
+    match u64::decode(&mut &block[..]) {
+        Err(_) => Err(DecodeError::Corrupt),
+        Ok(n) => Ok(n as usize),
+    }
+}
+
+#[test]
+fn test_compress() {
+    let cases: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        b"abc".to_vec(),
+        b"abcabcabcabcabcabcabcabcabcabcabcabcabc".to_vec(),
+        vec![7u8; 1000],
+        b"{\"name\": \"x\", \"tags\": [1, 2, 3]}, {\"name\": \"y\", \"tags\": [1, 2, 3]}".repeat(20),
+        (0..5000u32).map(|i| (i * 7919 % 251) as u8).collect(),
+    ];
+    for case in &cases {
+        let block = compress(case);
+        assert_eq!(&decompress(&block).unwrap(), case);
+        assert_eq!(decompressed_len(&block).unwrap(), case.len());
+    }
+    assert!(compress(&cases[3]).len() < 20);
+
+    // Damaged blocks are reported rather than misread.
+    let block = compress(&cases[4]);
+    for n in 0..block.len() {
+        match decompress(&block[..n]) {
+            Err(DecodeError::Corrupt) => (),
+            _ => panic!("expected a cut short block to be corrupt"),
+        };
+    }
+}
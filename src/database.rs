@@ -0,0 +1,400 @@
+//! Databases: directories of named tables.
+//!
+//! A database directory holds a catalog, itself a table at `catalog.bt`,
+//! and the tables it lists. Each table is kept at `<id>.bt` by an id the
+//! catalog issues from its sequence, so renaming a table only changes
+//! the catalog, and a dropped table's files are never mistaken for a
+//! new one's. The catalog records the key and value types of each table
+//! and the options it was created with, which are used to open it.
+
+use std::fs::{create_dir_all, metadata, remove_dir_all, remove_file};
+use std::path::Path;
+use std::time::Duration;
+
+use compress::Compression;
+use encode::Encode;
+use decode::Decode;
+use checkpoint::{checkpoint_path, checkpoints, previous_log_path};
+use header::TypeDescriptor;
+use hint::hint_path;
+use json::JSON;
+use table::{AutoCompact, Durability, Table, TableError, TableOptions};
+
+/// The name of the catalog table in a database directory.
+pub const CATALOG: &str = "catalog.bt";
+
+/// A table listed in the catalog of a database.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableInfo {
+    pub name: String,
+    /// The type descriptors of the table's keys and values (see
+    /// `header::TypeDescriptor`).
+    pub key_type: String,
+    pub value_type: String,
+    /// The names of the codecs its values are stored with.
+    pub codecs: Vec<String>,
+}
+
+/// A directory of named tables.
+///
+/// The catalog is held open for writing, so only one process can open
+/// a database at a time. Tables are opened for writing, each at most
+/// once at a time.
+pub struct Database {
+    dir: String,
+    // Entries describing each table, by id.
+    catalog: Table<i64, JSON>,
+}
+
+// The value of the member of obj called name, or null.
+fn member<'a>(obj: &'a JSON, name: &str) -> &'a JSON {
+    static NULL: JSON = JSON::Null;
+    match *obj {
+        JSON::Object(ref m) => m.get(name).unwrap_or(&NULL),
+        _ => &NULL,
+    }
+}
+
+fn string(obj: &JSON, name: &str) -> String {
+    match *member(obj, name) {
+        JSON::String(ref s) => s.clone(),
+        _ => String::new(),
+    }
+}
+
+fn int(obj: &JSON, name: &str) -> Option<u64> {
+    match *member(obj, name) {
+        JSON::Int(n) if n >= 0 => Some(n as u64),
+        _ => None,
+    }
+}
+
+fn object(members: Vec<(&str, JSON)>) -> JSON {
+    JSON::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+// Returns the recorded form of the options a table is created with.
+fn options_json(options: &TableOptions) -> JSON {
+    let durability = match options.durability {
+        Durability::Sync => JSON::String("sync".to_string()),
+        Durability::Os => JSON::String("os".to_string()),
+        Durability::GroupCommit { records, interval } => object(vec![
+            ("records", JSON::Int(records as i64)),
+            ("interval_ms", JSON::Int((interval.as_secs() * 1000 + interval.subsec_millis() as u64) as i64)),
+        ]),
+    };
+    let auto_compact = match options.auto_compact {
+        None => JSON::Null,
+        Some(p) => object(vec![
+            ("ratio", JSON::Float(p.ratio)),
+            ("min_size", JSON::Int(p.min_size as i64)),
+            ("step", JSON::Int(p.step as i64)),
+        ]),
+    };
+    object(vec![
+        ("durability", durability),
+        ("buffer_size", JSON::Int(options.buffer_size as i64)),
+        ("auto_compact", auto_compact),
+        ("cache_size", JSON::Int(options.cache_size as i64)),
+        ("segment_size", match options.segment_size {
+            None => JSON::Null,
+            Some(n) => JSON::Int(n as i64),
+        }),
+        ("compression", JSON::String(match options.compression {
+            Compression::None => "none".to_string(),
+            Compression::Lz => "lz".to_string(),
+        })),
+    ])
+}
+
+// Returns the options recorded by options_json, for writing.
+fn json_options(json: &JSON) -> TableOptions {
+    let mut options = TableOptions::new();
+    options.write(true);
+    match *member(json, "durability") {
+        JSON::String(ref s) if s == "sync" => { options.durability(Durability::Sync); },
+        ref d @ JSON::Object(_) => {
+            options.durability(Durability::GroupCommit {
+                records: int(d, "records").unwrap_or(1) as usize,
+                interval: Duration::from_millis(int(d, "interval_ms").unwrap_or(0)),
+            });
+        },
+        _ => (),
+    };
+    if let Some(n) = int(json, "buffer_size") {
+        options.buffer_size(n as usize);
+    }
+    if let JSON::Object(_) = *member(json, "auto_compact") {
+        let p = member(json, "auto_compact");
+        options.auto_compact(AutoCompact {
+            ratio: match *member(p, "ratio") {
+                JSON::Float(r) => r,
+                JSON::Int(n) => n as f64,
+                _ => 0.5,
+            },
+            min_size: int(p, "min_size").unwrap_or(0) as usize,
+            step: int(p, "step").unwrap_or(1000) as usize,
+        });
+    }
+    if let Some(n) = int(json, "cache_size") {
+        options.cache_size(n as usize);
+    }
+    if let Some(n) = int(json, "segment_size") {
+        options.segmented(n as usize);
+    }
+    if string(json, "compression") == "lz" {
+        options.compression(Compression::Lz);
+    }
+    options
+}
+
+fn info(entry: &JSON) -> TableInfo {
+    TableInfo {
+        name: string(entry, "name"),
+        key_type: string(entry, "key"),
+        value_type: string(entry, "value"),
+        codecs: match *member(entry, "codecs") {
+            JSON::Array(ref v) => v.iter().filter_map(|c| match *c {
+                JSON::String(ref s) => Some(s.clone()),
+                _ => None,
+            }).collect(),
+            _ => Vec::new(),
+        },
+    }
+}
+
+impl Database {
+    /// Opens the database in directory `dir`, creating it if need be.
+    pub fn open(dir: &str) -> Result<Database, TableError> {
+        match create_dir_all(dir) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        let path = Path::new(dir).join(CATALOG);
+        let catalog = match Table::open_rw(&path.to_string_lossy()) {
+            Err(e) => return Err(e),
+            Ok(t) => t,
+        };
+        Ok(Database { dir: dir.to_string(), catalog })
+    }
+
+    // Returns the path of the table with the given id.
+    fn table_path(&self, id: i64) -> String {
+        Path::new(&self.dir).join(format!("{}.bt", id)).to_string_lossy().into_owned()
+    }
+
+    // Returns the id and catalog entry of the table called name.
+    fn find(&self, name: &str) -> Option<(i64, &JSON)> {
+        (&self.catalog).into_iter().find(|&(_, entry)| string(entry, "name") == name).map(|(id, entry)| (*id, entry))
+    }
+
+    /// Lists the tables of the database in order of name.
+    pub fn list_tables(&self) -> Vec<TableInfo> {
+        let mut tables: Vec<TableInfo> = (&self.catalog).into_iter().map(|(_, entry)| info(entry)).collect();
+        tables.sort_by(|a, b| a.name.cmp(&b.name));
+        tables
+    }
+
+    /// Returns the catalog entry for the table called `name`.
+    pub fn table_info(&self, name: &str) -> Option<TableInfo> {
+        self.find(name).map(|(_, entry)| info(entry))
+    }
+
+    /// Creates a table called `name`, opened for writing with `options`,
+    /// which are recorded for opening it later. Codecs are recorded by
+    /// name only, so a table with codecs must be opened with
+    /// `open_table_with`.
+    pub fn create_table<K, V>(&mut self, name: &str, options: &TableOptions) -> Result<Table<K, V>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        if name.is_empty() {
+            return Err(TableError::InvalidName(name.to_string()));
+        }
+        if self.find(name).is_some() {
+            return Err(TableError::Exists(name.to_string()));
+        }
+        let entry = object(vec![
+            ("name", JSON::String(name.to_string())),
+            ("key", JSON::String(K::type_descriptor())),
+            ("value", JSON::String(V::type_descriptor())),
+            ("codecs", JSON::Array(options.codecs.names().into_iter().map(JSON::String).collect())),
+            ("options", options_json(options)),
+        ]);
+        let id = match self.catalog.insert_auto(entry) {
+            Err(e) => return Err(e),
+            Ok(id) => id,
+        };
+        let mut options = options.clone();
+        options.write(true);
+        match options.open(&self.table_path(id)) {
+            Err(e) => {
+                let _ = self.catalog.remove(&id);
+                Err(e)
+            },
+            Ok(t) => Ok(t),
+        }
+    }
+
+    /// Opens the table called `name` for writing with the options it
+    /// was created with.
+    pub fn open_table<K, V>(&self, name: &str) -> Result<Table<K, V>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        let options = match self.find(name) {
+            None => return Err(TableError::NotFound(name.to_string())),
+            Some((_, entry)) => json_options(member(entry, "options")),
+        };
+        self.open_table_with(name, &options)
+    }
+
+    /// Opens the table called `name` with `options` rather than those
+    /// it was created with.
+    pub fn open_table_with<K, V>(&self, name: &str, options: &TableOptions) -> Result<Table<K, V>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        let (id, info) = match self.find(name) {
+            None => return Err(TableError::NotFound(name.to_string())),
+            Some((id, entry)) => (id, info(entry)),
+        };
+        if info.key_type != K::type_descriptor() || info.value_type != V::type_descriptor() {
+            return Err(TableError::TypeMismatch { key: info.key_type, value: info.value_type });
+        }
+        let mut options = options.clone();
+        options.create(false);
+        options.open(&self.table_path(id))
+    }
+
+    /// Renames table `from` to `to`. The table may be open.
+    pub fn rename_table(&mut self, from: &str, to: &str) -> Result<(), TableError> {
+        if to.is_empty() {
+            return Err(TableError::InvalidName(to.to_string()));
+        }
+        if self.find(to).is_some() {
+            return Err(TableError::Exists(to.to_string()));
+        }
+        let (id, mut entry) = match self.find(from) {
+            None => return Err(TableError::NotFound(from.to_string())),
+            Some((id, entry)) => (id, entry.clone()),
+        };
+        if let JSON::Object(ref mut m) = entry {
+            m.insert("name".to_string(), JSON::String(to.to_string()));
+        }
+        match self.catalog.insert(id, entry) {
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Removes the table called `name` and its files. The table must
+    /// not be open.
+    pub fn drop_table(&mut self, name: &str) -> Result<(), TableError> {
+        let id = match self.find(name) {
+            None => return Err(TableError::NotFound(name.to_string())),
+            Some((id, _)) => id,
+        };
+        // Once it is out of the catalog the table is gone, whatever
+        // becomes of its files.
+        match self.catalog.remove(&id) {
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        };
+        let path = self.table_path(id);
+        match metadata(&path) {
+            Ok(ref m) if m.is_dir() => return match remove_dir_all(&path) {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(()) => Ok(()),
+            },
+            _ => (),
+        };
+        if let Ok(gens) = checkpoints(&path) {
+            for gen in gens {
+                let _ = remove_file(checkpoint_path(&path, gen));
+            }
+        }
+        for p in &[hint_path(&path), previous_log_path(&path)] {
+            let _ = remove_file(p);
+        }
+        match remove_file(&path) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+use test_util::temp_path;
+
+#[test]
+fn test_database() {
+    use std::fs::read_dir;
+
+    let dir = temp_path("database");
+    let _ = remove_dir_all(&dir);
+    {
+        let mut db = Database::open(&dir).unwrap();
+        let mut options = TableOptions::new();
+        options.compression(Compression::Lz).durability(Durability::Sync);
+        let mut t: Table<i64, Vec<u8>> = db.create_table("numbers", &options).unwrap();
+        t.insert(1, vec![b'x'; 100]).unwrap();
+        drop(t);
+        let mut t: Table<Vec<u8>, i64> = db.create_table("names", &TableOptions::new()).unwrap();
+        t.insert(b"one".to_vec(), 1).unwrap();
+        drop(t);
+        let mut seg = TableOptions::new();
+        seg.segmented(64);
+        let mut t: Table<i64, i64> = db.create_table("segments", &seg).unwrap();
+        for i in 0..10 {
+            t.insert(i, i).unwrap();
+        }
+        drop(t);
+        match db.create_table::<i64, i64>("names", &TableOptions::new()) {
+            Err(TableError::Exists(_)) => (),
+            _ => panic!("expected the table to exist"),
+        };
+    }
+    let mut db = Database::open(&dir).unwrap();
+    let names: Vec<String> = db.list_tables().into_iter().map(|t| t.name).collect();
+    assert_eq!(names, vec!["names".to_string(), "numbers".to_string(), "segments".to_string()]);
+    assert_eq!(db.table_info("names").unwrap().key_type, "bytes");
+
+    // Tables are opened with the options they were created with.
+    let t: Table<i64, Vec<u8>> = db.open_table("numbers").unwrap();
+    assert_eq!(t.get(&1), Some(&vec![b'x'; 100]));
+    assert_eq!(t.stats().compression().records, 1);
+    drop(t);
+    match db.open_table::<i64, i64>("numbers") {
+        Err(TableError::TypeMismatch { .. }) => (),
+        _ => panic!("expected a type mismatch"),
+    };
+
+    db.rename_table("numbers", "counts").unwrap();
+    match db.rename_table("counts", "names") {
+        Err(TableError::Exists(_)) => (),
+        _ => panic!("expected the table to exist"),
+    };
+    match db.open_table::<i64, Vec<u8>>("numbers") {
+        Err(TableError::NotFound(_)) => (),
+        _ => panic!("expected no such table"),
+    };
+    let t: Table<i64, Vec<u8>> = db.open_table("counts").unwrap();
+    assert_eq!(t.get(&1), Some(&vec![b'x'; 100]));
+    drop(t);
+
+    db.drop_table("segments").unwrap();
+    db.drop_table("names").unwrap();
+    assert!(db.drop_table("names").is_err());
+    // A new table does not take on a dropped table's files.
+    let t: Table<Vec<u8>, i64> = db.create_table("names", &TableOptions::new()).unwrap();
+    assert_eq!(t.get(&b"one".to_vec()), None);
+    drop(t);
+    let names: Vec<String> = db.list_tables().into_iter().map(|t| t.name).collect();
+    assert_eq!(names, vec!["counts".to_string(), "names".to_string()]);
+    let mut files: Vec<String> = read_dir(&dir).unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    // The catalog and the two tables, with nothing left of those dropped.
+    assert_eq!(files, vec!["0.bt".to_string(), "3.bt".to_string(), CATALOG.to_string()]);
+    drop(db);
+    let _ = remove_dir_all(&dir);
+}
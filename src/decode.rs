@@ -1,10 +1,16 @@
+use std::fs::File;
 use std::io;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
 use std::collections::BTreeMap;
 
-use encode::Encode;
+use crc32::crc32;
+use codec::Codecs;
+use compress::{decompress, decompressed_len};
+use encode::{Encode, Control, COMPRESSED, record_size};
 
 #[cfg(test)]
-use encode::encode;
+use encode::{encode, encode_record};
 
 #[derive(Debug)]
 pub enum DecodeError {
@@ -12,22 +18,92 @@ pub enum DecodeError {
     EOF,
     Null,
     PartialRead,
+    Checksum,
+    Corrupt,
+}
+
+/// Counts of the compressed values in a table log (see `compress`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompressionStats {
+    /// Number of records with a compressed value.
+    pub records: usize,
+    /// Total encoded size of their values...
+    pub raw: usize,
+    /// ...and of the compressed blocks holding them.
+    pub stored: usize,
+}
+
+impl CompressionStats {
+    /// Size of the compressed values as a fraction of their encoded size.
+    pub fn ratio(&self) -> f64 {
+        if self.raw == 0 { 1.0 } else { self.stored as f64 / self.raw as f64 }
+    }
 }
 
 #[derive(Default)]
 pub struct DecodeStats {
     read: usize,
+    records: usize,
     discarded: usize,
+    truncated: usize,
+    compression: CompressionStats,
 }
 
 impl DecodeStats {
     pub fn read(&self) -> usize { self.read }
+    /// Number of records replayed, not counting any covered by a hint.
+    pub fn records(&self) -> usize { self.records }
     pub fn discarded(&self) -> usize { self.discarded }
+    /// Bytes of a torn record or uncommitted batch dropped from the
+    /// end of a table log.
+    pub fn truncated(&self) -> usize { self.truncated }
+    /// Length of the part of a table log holding complete records.
+    pub fn valid(&self) -> usize { self.read - self.truncated }
+
+    /// Fraction of the valid part of a table log taken by records
+    /// which have since been superseded.
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.valid() == 0 { 0.0 } else { self.discarded as f64 / self.valid() as f64 }
+    }
+
+    /// Accounts for `n` bytes appended to a table log, which have made
+    /// `discarded` bytes of it redundant.
+    pub fn append(&mut self, n: usize, discarded: usize) {
+        self.read += n;
+        self.discarded += discarded;
+    }
+
+    pub fn compression(&self) -> &CompressionStats { &self.compression }
+
+    /// Accounts for a value of `raw` bytes appended to a table log
+    /// compressed into `stored` bytes.
+    pub fn append_compressed(&mut self, raw: usize, stored: usize) {
+        self.compression.records += 1;
+        self.compression.raw += raw;
+        self.compression.stored += stored;
+    }
+
+    /// Adds the statistics of another log, as for the segments of a
+    /// segmented table.
+    pub fn add(&mut self, other: &DecodeStats) {
+        self.read += other.read;
+        self.records += other.records;
+        self.discarded += other.discarded;
+        self.truncated += other.truncated;
+        self.add_compression(&other.compression);
+    }
+
+    /// Accounts for compressed values described by `c`, as in a hint.
+    pub fn add_compression(&mut self, c: &CompressionStats) {
+        self.compression.records += c.records;
+        self.compression.raw += c.raw;
+        self.compression.stored += c.stored;
+    }
 }
 
 pub trait Decode : Sized {
     fn decode<T: io::Read>(src: &mut T) -> Result<Self, DecodeError> {
-        let mut stats = DecodeStats { read: 0, discarded: 0 };
+        let mut stats = DecodeStats::default();
         Self::decode_stats(src, &mut stats)
     }
 
@@ -96,7 +172,9 @@ impl Decode for i64 {
         };
         stats.read += 1;
 
-        if (buff[0] as i8) > -0x7F {
+        if buff[0] == 0xFF {
+            Err(DecodeError::Null)
+        } else if (buff[0] as i8) > -0x7F {
             Ok(buff[0] as i8 as i64)
         } else if buff[0] == 0x81 {
             let mut b2 = [0u8; 2];
@@ -158,49 +236,529 @@ impl Decode for Vec<u8> {
     }
 }
 
-type Bytes = Vec<u8>;
-type DataMap = BTreeMap<i64, Bytes>;
+// Reads into buf until it is full or the source is exhausted.
+fn read_full<T: io::Read>(src: &mut T, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match src.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(nr) => n += nr,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(n)
+}
 
-impl Decode for DataMap {
-    fn decode_stats<T: io::Read>(src: &mut T, stats: &mut DecodeStats) ->
-        Result<Self, DecodeError>
+// Reads the checksum and n byte payload of a frame (see `encode::frame`)
+// whose length has already been read.
+fn decode_payload<T: io::Read>(src: &mut T, stats: &mut DecodeStats, n: usize) ->
+    Result<Vec<u8>, DecodeError>
+{
+    let mut crc = [0u8; 4];
+    match read_full(src, &mut crc) {
+        Err(err) => return Err(DecodeError::IOError { err }),
+        Ok(nr) => {
+            stats.read += nr;
+            if nr < 4 {
+                return Err(DecodeError::PartialRead);
+            }
+        },
+    };
+    let mut payload = Vec::new();
+    match src.by_ref().take(n as u64).read_to_end(&mut payload) {
+        Err(err) => return Err(DecodeError::IOError { err }),
+        Ok(nr) => {
+            stats.read += nr;
+            if nr < n {
+                return Err(DecodeError::PartialRead);
+            }
+        },
+    };
+    let expected = (crc[0] as u32) << 24 | (crc[1] as u32) << 16 | (crc[2] as u32) << 8 | crc[3] as u32;
+    if crc32(&payload) != expected {
+        return Err(DecodeError::Checksum);
+    }
+    Ok(payload)
+}
+
+/// Reads a frame written by `encode::frame`, returning its payload. A
+/// frame cut short by the end of the source is reported as
+/// `PartialRead` and one whose checksum does not match as `Checksum`.
+pub fn decode_frame<T: io::Read>(src: &mut T, stats: &mut DecodeStats) ->
+    Result<Vec<u8>, DecodeError>
+{
+    match u64::decode_stats(src, stats) {
+        Err(err) => Err(err),
+        Ok(n) => decode_payload(src, stats, n as usize),
+    }
+}
+
+pub(crate) enum Entry<K, V> {
+    // A key, its value (None for a removal), the size of the value as
+    // stored, and whether the value is compressed.
+    Record(K, Option<V>, usize, bool),
+    Control(Control),
+}
+
+// Reads the next framed record of a table log (see `encode_record`).
+// A record cut short by the end of the source is reported as
+// `PartialRead`, one whose checksum does not match as `Checksum` and an
+// intact one which cannot be decoded as `Corrupt`. Values are decoded
+// as stored with codecs.
+pub(crate) fn decode_entry<K, V, T>(src: &mut T, stats: &mut DecodeStats, codecs: &Codecs) ->
+    Result<Entry<K, V>, DecodeError>
+    where K: Decode, V: Decode, T: io::Read
+{
+    let (control, n) = match u64::decode_stats(src, stats) {
+        Err(DecodeError::Null) => match u64::decode_stats(src, stats) {
+            Err(DecodeError::EOF) => return Err(DecodeError::PartialRead),
+            Err(DecodeError::Null) => return Err(DecodeError::Corrupt),
+            Err(err) => return Err(err),
+            Ok(n) => (true, n),
+        },
+        Err(err) => return Err(err),
+        Ok(n) => (false, n),
+    };
+    let payload = match decode_payload(src, stats, n as usize) {
+        Err(err) => return Err(err),
+        Ok(payload) => payload,
+    };
+    if !control {
+        // The payload is known to be intact; decode it separately
+        // so that stats only count bytes read from src.
+        let mut rec = io::Cursor::new(payload);
+        let mut rec_stats = DecodeStats::default();
+        let key = match K::decode_stats(&mut rec, &mut rec_stats) {
+            Err(_) => return Err(DecodeError::Corrupt),
+            Ok(key) => key,
+        };
+        let keysize = rec_stats.read;
+        let valsize = n as usize - keysize;
+        return match codecs.decode_value(&rec.get_ref()[keysize..]) {
+            Err(DecodeError::Null) => Ok(Entry::Record(key, None, valsize, false)),
+            Err(_) => Err(DecodeError::Corrupt),
+            Ok(value) => Ok(Entry::Record(key, Some(value), valsize, false)),
+        };
+    }
+    if payload.len() == 0 {
+        return Err(DecodeError::Corrupt);
+    }
+    if payload[0] == COMPRESSED {
+        let mut rec = &payload[1..];
+        let key = match K::decode(&mut rec) {
+            Err(_) => return Err(DecodeError::Corrupt),
+            Ok(key) => key,
+        };
+        return match decode_value(rec, true, codecs) {
+            Err(_) => Err(DecodeError::Corrupt),
+            Ok(value) => {
+                stats.append_compressed(decompressed_len(rec).unwrap(), rec.len());
+                Ok(Entry::Record(key, Some(value), rec.len(), true))
+            },
+        };
+    }
+    let arg = match u64::decode(&mut io::Cursor::new(&payload[1..])) {
+        Err(_) => return Err(DecodeError::Corrupt),
+        Ok(arg) => arg,
+    };
+    match Control::from_parts(payload[0], arg) {
+        None => Err(DecodeError::Corrupt),
+        Some(c) => Ok(Entry::Control(c)),
+    }
+}
+
+// Returns the length and payload of the frame at the start of buf, if
+// it is all there. Control records are framed after a null byte.
+pub(crate) fn frame_at(buf: &[u8]) -> Option<(usize, &[u8])> {
+    let mut src = if buf.first() == Some(&0xFF) { &buf[1..] } else { buf };
+    let mut stats = DecodeStats::default();
+    match u64::decode_stats(&mut src, &mut stats) {
+        Ok(n) if src.len() >= 4 && n <= (src.len() - 4) as u64 => {
+            let len = buf.len() - src.len() + 4 + n as usize;
+            Some((len, &src[4..4 + n as usize]))
+        },
+        _ => None,
+    }
+}
+
+// Reads the entry at the start of buf, returning it and its length.
+pub(crate) fn entry_at<K: Decode, V: Decode>(buf: &[u8], codecs: &Codecs) -> Option<(Entry<K, V>, usize)> {
+    // A frame must fit in the log before it is worth checking.
+    if frame_at(buf).is_none() {
+        return None;
+    }
+    let mut src = buf;
+    let mut stats = DecodeStats::default();
+    match decode_entry(&mut src, &mut stats, codecs) {
+        Err(_) => None,
+        Ok(entry) => Some((entry, stats.read())),
+    }
+}
+
+// Returns the length of the longest start of buf which holds only
+// whole frames and ends outside any batch, and not between an Expires
+// control and the record it applies to.
+pub(crate) fn whole_records(buf: &[u8]) -> usize {
+    let mut pos = 0;
+    let mut whole = 0;
+    let mut in_batch = false;
+    while let Some((len, payload)) = frame_at(&buf[pos..]) {
+        let control = match payload.first() {
+            Some(&kind) if buf[pos] == 0xFF && kind != COMPRESSED => Control::from_parts(kind, 0),
+            _ => None,
+        };
+        pos += len;
+        match control {
+            Some(Control::BatchBegin(_)) => in_batch = true,
+            Some(Control::BatchCommit(_)) => in_batch = false,
+            Some(Control::Expires(_)) => continue,
+            _ => (),
+        }
+        if !in_batch {
+            whole = pos;
+        }
+    }
+    whole
+}
+
+// How many bytes from the start of an entry cut short by the end of
+// the log are searched for an intact record, which would show that the
+// entry is damaged rather than torn. This bounds the time and memory
+// spent on a length which takes in the rest of the log.
+const TAIL_SCAN: usize = 64 * 1024;
+
+// A reader which keeps the first bytes read from src since it was last
+// cleared, so that a damaged entry can be looked past.
+struct Recorded<'a, T: 'a> {
+    src: &'a mut T,
+    buf: Vec<u8>,
+    // The most bytes kept in buf.
+    limit: usize,
+}
+
+impl<'a, T: io::Read> io::Read for Recorded<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.src.read(buf)?;
+        let kept = n.min(self.limit.saturating_sub(self.buf.len()));
+        self.buf.extend_from_slice(&buf[..kept]);
+        Ok(n)
+    }
+}
+
+/// Where the encoded value of a record lies in a table log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ValueLoc {
+    pub offset: u64,
+    pub len: usize,
+    /// Whether the value is stored as a compressed block.
+    pub compressed: bool,
+    /// When the value expires, in milliseconds since the Unix epoch, if
+    /// it was written with a time to live.
+    pub expires: Option<u64>,
+}
+
+impl ValueLoc {
+    /// Size of the record holding the value in the log, given the
+    /// encoded size of its key.
+    pub fn record_size(&self, keysize: usize) -> usize {
+        if self.compressed {
+            // Framed after a null byte, with its kind leading the payload.
+            1 + record_size(1 + keysize + self.len)
+        } else {
+            record_size(keysize + self.len)
+        }
+    }
+}
+
+/// Decodes a value stored as `raw` in a table log written with
+/// `codecs`, which is a compressed block if `compressed` is set.
+pub fn decode_value<V: Decode>(raw: &[u8], compressed: bool, codecs: &Codecs) -> Result<V, DecodeError> {
+    if !compressed {
+        return codecs.decode_value(raw);
+    }
+    match decompress(raw) {
+        Err(err) => Err(err),
+        Ok(buf) => codecs.decode_value(&buf),
+    }
+}
+
+/// A map which a table log can be replayed into by `replay`.
+pub trait LogMap<K, V> {
+    /// Records `value`, found at `loc` in the log, for `key`, returning
+    /// the size of the record in the log holding any value it replaces.
+    fn replay_insert(&mut self, key: K, value: V, loc: ValueLoc) -> Option<usize>;
+    /// Removes `key`, returning the size of the record holding its
+    /// value if any.
+    fn replay_remove(&mut self, key: &K) -> Option<usize>;
+
+    /// Records the value at `loc` in `log` for `key`, as listed in a
+    /// hint file (see `hint`), returning the size of the record holding
+    /// any value it replaces. The value is read from the log, which was
+    /// written with `codecs`, unless it is not needed.
+    fn hint_insert(&mut self, key: K, loc: ValueLoc, log: &File, codecs: &Codecs) -> Result<Option<usize>, DecodeError>
+        where V: Decode
     {
-        let mut data: DataMap = BTreeMap::new();
-        loop {
-            let pos = stats.read;
-            let key = match i64::decode_stats(src, stats) {
-                Err(err) => match err {
-                    DecodeError::EOF => return Ok(data),
-                    _ => return Err(err),
-                },
-                Ok(key) => key,
-            };
-            let keysize = stats.read - pos;
-            match Bytes::decode_stats(src, stats) {
-                Err(err) => match err {
-                    DecodeError::Null => {
-                        // the discard iteslf is wasted space.
-                        stats.discarded += keysize + 1;
-                        match data.remove(&key) {
-                            Some(value) => {
-                                // original insert and this remove are now redundant
-                                stats.discarded += keysize + value.encode_size();
-                            },
-                            None => (),
-                        }
-                    },
-                    _ => return Err(err),
+        let mut buf = vec![0u8; loc.len];
+        match log.read_exact_at(&mut buf, loc.offset) {
+            Err(err) => return Err(DecodeError::IOError { err }),
+            Ok(()) => (),
+        };
+        match decode_value(&buf, loc.compressed, codecs) {
+            Err(err) => Err(err),
+            Ok(value) => Ok(self.replay_insert(key, value, loc)),
+        }
+    }
+
+    /// Notes that the keys below `next` have been issued by the
+    /// sequence of the table. Maps without a sequence ignore it.
+    fn replay_sequence(&mut self, _next: u64) {}
+
+    /// Notes that everything replayed so far was loaded from a
+    /// checkpoint, so none of it is held in records of the log.
+    fn replay_checkpoint(&mut self) {}
+
+    /// Notes that the records replayed from now on are read from the
+    /// file at `path`, a log or checkpoint, and their value locations
+    /// are offsets in it. Maps which read no values later ignore it.
+    fn replay_file(&mut self, _path: &str) {}
+}
+
+// A plain map does not know how its values were stored, so takes them
+// to be stored as they encode.
+impl<K: Ord + Encode, V: Encode> LogMap<K, V> for BTreeMap<K, V> {
+    fn replay_insert(&mut self, key: K, value: V, _loc: ValueLoc) -> Option<usize> {
+        let keysize = key.encode_size();
+        match self.insert(key, value) {
+            Some(old) => Some(record_size(keysize + old.encode_size())),
+            None => None,
+        }
+    }
+
+    fn replay_remove(&mut self, key: &K) -> Option<usize> {
+        match self.remove(key) {
+            Some(old) => Some(record_size(key.encode_size() + old.encode_size())),
+            None => None,
+        }
+    }
+}
+
+// A replayed record: key, value, record size and value location.
+type Replayed<K, V> = (K, Option<V>, usize, ValueLoc);
+
+// Applies a replayed insert (or removal if value is None) to data.
+fn apply_record<K, V, M>(data: &mut M, rec: Replayed<K, V>, stats: &mut DecodeStats)
+    where M: LogMap<K, V>
+{
+    let (key, value, recsize, loc) = rec;
+    stats.records += 1;
+    match value {
+        None => {
+            // the discard iteslf is wasted space.
+            stats.discarded += recsize;
+            match data.replay_remove(&key) {
+                Some(size) => {
+                    // original insert and this remove are now redundant
+                    stats.discarded += size;
                 },
-                Ok(value) => {
-                    match data.insert(key, value) {
-                        Some(value) => {
-                            // original insert including key are now redundant
-                            stats.discarded += keysize + value.encode_size();
-                        },
-                        None => (),
-                    }
+                None => (),
+            }
+        },
+        Some(value) => {
+            match data.replay_insert(key, value, loc) {
+                Some(size) => {
+                    // original insert including key are now redundant
+                    stats.discarded += size;
                 },
+                None => (),
             }
+        },
+    }
+}
+
+/// Replays a table log written before records were framed, as a bare
+/// sequence of encoded keys and values, into `data`. A record cut short
+/// by the end of the log is dropped and counted in
+/// `DecodeStats::truncated`.
+pub fn replay_legacy<K, V, M, T>(src: &mut T, stats: &mut DecodeStats, data: &mut M) -> Result<(), DecodeError>
+    where K: Decode, V: Decode, M: LogMap<K, V>, T: io::Read
+{
+    loop {
+        let pos = stats.read;
+        let key = match K::decode_stats(src, stats) {
+            Err(DecodeError::EOF) => break,
+            Err(DecodeError::PartialRead) => {
+                stats.truncated = stats.read - pos;
+                break;
+            },
+            Err(err) => return Err(err),
+            Ok(key) => key,
+        };
+        let keysize = stats.read - pos;
+        let value = match V::decode_stats(src, stats) {
+            Err(DecodeError::Null) => None,
+            Err(DecodeError::EOF) | Err(DecodeError::PartialRead) => {
+                stats.truncated = stats.read - pos;
+                break;
+            },
+            Err(err) => return Err(err),
+            Ok(value) => Some(value),
+        };
+        let recsize = stats.read - pos;
+        let loc = ValueLoc { offset: (pos + keysize) as u64, len: recsize - keysize, compressed: false, expires: None };
+        apply_record(data, (key, value, recsize, loc), stats);
+    }
+    Ok(())
+}
+
+/// Replays a table log from `src` into `data`.
+///
+/// A table log is a sequence of framed (key, value) records. A value
+/// which decodes as `DecodeError::Null` marks the removal of its key, so
+/// value types must reserve the null marker (0xFF) as their first byte.
+/// Records between `BatchBegin` and `BatchCommit` control records are
+/// applied together when the commit is reached.
+///
+/// A damaged record or uncommitted batch at the very end of the log is
+/// what a crash during an append leaves behind: it is dropped and
+/// counted in `DecodeStats::truncated`. Damage followed by further data
+/// is corruption and is returned as an error, including a record whose
+/// length runs past the end of the log when an intact record can be
+/// found in the 64 KiB from its start.
+///
+/// Value locations are offsets from the start of `src`, assuming that
+/// `stats.read()` bytes had been read from it already.
+pub fn replay<K, V, M, T>(src: &mut T, stats: &mut DecodeStats, data: &mut M) -> Result<(), DecodeError>
+    where K: Decode, V: Decode, M: LogMap<K, V>, T: io::Read
+{
+    replay_coded(src, stats, data, &Codecs::new())
+}
+
+/// Replays a table log whose values were stored with `codecs` (see
+/// `codec`), as `replay` does.
+pub fn replay_coded<K, V, M, T>(src: &mut T, stats: &mut DecodeStats, data: &mut M, codecs: &Codecs) ->
+    Result<(), DecodeError>
+    where K: Decode, V: Decode, M: LogMap<K, V>, T: io::Read
+{
+    // Start position, size and records of an open batch.
+    let mut batch: Option<(usize, u64, Vec<Replayed<K, V>>)> = None;
+    // Position and time of an expiry for the next record.
+    let mut expires: Option<(usize, u64)> = None;
+    let mut src = Recorded { src, buf: Vec::new(), limit: TAIL_SCAN };
+    loop {
+        let pos = stats.read;
+        src.buf.clear();
+        let entry = match decode_entry(&mut src, stats, codecs) {
+            Err(DecodeError::EOF) => break,
+            Err(DecodeError::PartialRead) => {
+                // A damaged length can take in the rest of the log. Only
+                // the start of it is searched for an intact record.
+                let tail = &src.buf;
+                if (1..tail.len()).any(|p| entry_at::<K, V>(&tail[p..], codecs).is_some()) {
+                    return Err(DecodeError::Corrupt);
+                }
+                stats.truncated = stats.read - pos;
+                break;
+            },
+            Err(DecodeError::Checksum) | Err(DecodeError::Corrupt) => {
+                let mut rest = Vec::new();
+                match src.read_to_end(&mut rest) {
+                    Err(err) => return Err(DecodeError::IOError { err }),
+                    Ok(nr) => stats.read += nr,
+                };
+                if rest.len() > 0 {
+                    return Err(DecodeError::Corrupt);
+                }
+                stats.truncated = stats.read - pos;
+                break;
+            },
+            Err(err) => return Err(err),
+            Ok(entry) => entry,
+        };
+        let recsize = stats.read - pos;
+
+        let rec = match entry {
+            Entry::Control(Control::Expires(t)) => {
+                if expires.is_some() {
+                    return Err(DecodeError::Corrupt);
+                }
+                // It is accounted for as part of the record it applies to.
+                expires = Some((pos, t));
+                continue;
+            },
+            Entry::Control(_) if expires.is_some() => return Err(DecodeError::Corrupt),
+            Entry::Control(Control::BatchBegin(n)) => {
+                if batch.is_some() {
+                    return Err(DecodeError::Corrupt);
+                }
+                batch = Some((pos, n, Vec::new()));
+                stats.discarded += recsize;
+                continue;
+            },
+            Entry::Control(Control::BatchCommit(n)) => {
+                match batch.take() {
+                    Some((_, size, records)) => {
+                        if size != n || records.len() as u64 != n {
+                            return Err(DecodeError::Corrupt);
+                        }
+                        for rec in records {
+                            apply_record(data, rec, stats);
+                        }
+                    },
+                    None => return Err(DecodeError::Corrupt),
+                };
+                stats.discarded += recsize;
+                continue;
+            },
+            Entry::Control(Control::Sequence(n)) => {
+                data.replay_sequence(n);
+                stats.discarded += recsize;
+                continue;
+            },
+            Entry::Control(Control::Checkpoint(_)) => {
+                if batch.is_some() {
+                    return Err(DecodeError::Corrupt);
+                }
+                stats.discarded += recsize;
+                continue;
+            },
+            Entry::Record(key, value, valsize, compressed) => {
+                let loc = ValueLoc {
+                    offset: (stats.read - valsize) as u64,
+                    len: valsize,
+                    compressed,
+                    expires: expires.take().map(|(_, t)| t),
+                };
+                (key, value, recsize, loc)
+            },
+        };
+        match batch {
+            Some((_, _, ref mut records)) => records.push(rec),
+            None => apply_record(data, rec, stats),
+        };
+    }
+
+    // Neither can an expiry with no record.
+    if let Some((start, _)) = expires {
+        stats.truncated = stats.read - start;
+    }
+    // A batch with no commit can only be the last thing written.
+    if let Some((start, _, _)) = batch {
+        stats.truncated = stats.read - start;
+    }
+    Ok(())
+}
+
+impl<K, V> Decode for BTreeMap<K, V>
+    where K: Decode + Encode + Ord, V: Decode + Encode
+{
+    fn decode_stats<T: io::Read>(src: &mut T, stats: &mut DecodeStats) ->
+        Result<Self, DecodeError>
+    {
+        let mut data = BTreeMap::new();
+        match replay(src, stats, &mut data) {
+            Err(err) => Err(err),
+            Ok(()) => Ok(data),
         }
     }
 }
@@ -234,5 +792,97 @@ fn test_decoders() {
     assert_eq!(round_trip(&-123456789i64), Some(-123456789i64));
     assert_eq!(round_trip(&-0x7Fi64), Some(-0x7Fi64));
     assert_eq!(round_trip(&-0x80i64), Some(-0x80i64));
+    assert_eq!(round_trip(&-1i64), Some(-1i64));
+    match i64::decode(&mut io::Cursor::new(vec![0xFFu8])) {
+        Err(DecodeError::Null) => (),
+        _ => panic!("expected the null marker to decode as a removal"),
+    }
+
+}
+
+#[test]
+fn test_table_log() {
+    let mut data: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+    data.insert(b"Tom".to_vec(), b"Cat".to_vec());
+    data.insert(b"Jerry".to_vec(), b"Mouse".to_vec());
+    assert_eq!(round_trip(&data), Some(data.clone()));
+
+    // A null value removes the key.
+    let mut log = encode(&data);
+    encode_record(&mut log, &b"Tom".to_vec(), None::<&Vec<u8>>).unwrap();
 
+    let mut stats = DecodeStats::default();
+    let d2 = BTreeMap::<Vec<u8>, Vec<u8>>::decode_stats(&mut io::Cursor::new(log), &mut stats).unwrap();
+    assert_eq!(d2.len(), 1);
+    assert_eq!(d2.get(&b"Jerry".to_vec()), Some(&b"Mouse".to_vec()));
+    // Both the removal record and the original insert are discarded.
+    assert_eq!(stats.discarded(), record_size(4 + 1) + record_size(4 + 4));
+    assert_eq!(stats.truncated(), 0);
+}
+
+#[test]
+fn test_torn_log() {
+    let mut data: BTreeMap<i64, Vec<u8>> = BTreeMap::new();
+    data.insert(1, b"one".to_vec());
+    data.insert(2, b"two".to_vec());
+    let log = encode(&data);
+    let full = log.len();
+
+    // Every prefix cut inside the last record loses just that record.
+    let first = record_size(1 + 4);
+    for n in first..full {
+        let mut stats = DecodeStats::default();
+        let d = BTreeMap::<i64, Vec<u8>>::decode_stats(&mut io::Cursor::new(&log[..n]), &mut stats).unwrap();
+        assert_eq!(d.len(), 1);
+        assert_eq!(stats.valid(), first);
+        assert_eq!(stats.truncated(), n - first);
+    }
+
+    // A damaged last record is dropped too.
+    let mut bad = log.clone();
+    bad[full - 1] ^= 0x55;
+    let mut stats = DecodeStats::default();
+    let d = BTreeMap::<i64, Vec<u8>>::decode_stats(&mut io::Cursor::new(&bad), &mut stats).unwrap();
+    assert_eq!(d.len(), 1);
+    assert_eq!(stats.truncated(), full - first);
+
+    // But damage followed by more records is an error.
+    let mut bad = log.clone();
+    bad[first - 1] ^= 0x55;
+    match BTreeMap::<i64, Vec<u8>>::decode(&mut io::Cursor::new(&bad)) {
+        Err(DecodeError::Corrupt) => (),
+        r => panic!("expected corrupt error, got {:?}", r),
+    };
+
+    // As is a length which runs past the end of the log from before
+    // an intact record.
+    let mut longer = log.clone();
+    encode_record(&mut longer, &3i64, Some(&b"three".to_vec())).unwrap();
+    let mut bad = longer.clone();
+    bad[first] = 0xFD;
+    bad[first + 1] = 0xFF;
+    match BTreeMap::<i64, Vec<u8>>::decode(&mut io::Cursor::new(&bad)) {
+        Err(DecodeError::Corrupt) => (),
+        r => panic!("expected corrupt error, got {:?}", r),
+    };
+    // Unless nothing intact follows it.
+    let mut bad = longer.clone();
+    bad[full] = 0xFD;
+    bad[full + 1] = 0xFF;
+    let mut stats = DecodeStats::default();
+    let d = BTreeMap::<i64, Vec<u8>>::decode_stats(&mut io::Cursor::new(&bad), &mut stats).unwrap();
+    assert_eq!(d.len(), 2);
+    assert_eq!(stats.truncated(), longer.len() - full);
+
+    // A length too large to add the checksum to is no frame.
+    let mut bad = longer.clone();
+    bad[first] = 0xFE;
+    for b in &mut bad[first + 1..first + 9] {
+        *b = 0xFF;
+    }
+    assert!(frame_at(&bad[first..]).is_none());
+    match BTreeMap::<i64, Vec<u8>>::decode(&mut io::Cursor::new(&bad)) {
+        Err(DecodeError::Corrupt) => (),
+        r => panic!("expected corrupt error, got {:?}", r),
+    };
 }
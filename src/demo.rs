@@ -1,7 +1,7 @@
 use std::io;
 use std::collections::BTreeMap;
 
-use encode::{ Encode, encode, encode_to_hex };
+use encode::{ Encode, encode, encode_record, encode_to_hex };
 use decode::*;
 
 pub fn encodings_demo() {
@@ -82,8 +82,7 @@ pub fn encodings_demo() {
     }
     println!("data.len(): {}", d2.len());
 
-    (&17i64).encode(&mut v2).unwrap();
-    None.encode(&mut v2).unwrap();
+    encode_record(&mut v2, &17i64, None::<&Vec<u8>>).unwrap();
 
     let d2;
     {
@@ -0,0 +1,172 @@
+//! Diagnostics: a sink told what tables and the reactor have done.
+//!
+//! Tables report to the sink in their `TableOptions` (see
+//! `TableOptions::diagnostics`) and an `EpollReactor` to the one set by
+//! `set_diagnostics`. By default nothing is reported anywhere; a
+//! `Counters` keeps totals which can be queried while it is in use.
+
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use std::time::Duration;
+
+use decode::DecodeStats;
+
+/// Receives diagnostic events. Each method does nothing by default.
+pub trait Diagnostics: Send + Sync {
+    /// Called once a table log, or all the segments of a segmented
+    /// table, at `path` has been read on opening.
+    fn log_read(&self, _path: &str, _stats: &DecodeStats) {}
+
+    /// Called once a compaction or merge of the table at `path` has
+    /// replaced its log, `elapsed` after it started.
+    fn compacted(&self, _path: &str, _elapsed: Duration) {}
+
+    /// Called when a reactor fails to wait for events or to watch a
+    /// reader.
+    fn reactor_error(&self, _err: &io::Error) {}
+}
+
+/// Diagnostics which are ignored.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoDiagnostics;
+
+impl Diagnostics for NoDiagnostics {}
+
+/// A shared diagnostics sink, as held by `TableOptions`.
+#[derive(Clone)]
+pub struct Sink(Arc<dyn Diagnostics>);
+
+impl Sink {
+    pub fn new(diagnostics: Arc<dyn Diagnostics>) -> Sink {
+        Sink(diagnostics)
+    }
+}
+
+impl Default for Sink {
+    fn default() -> Sink {
+        Sink(Arc::new(NoDiagnostics))
+    }
+}
+
+impl Deref for Sink {
+    type Target = dyn Diagnostics;
+
+    fn deref(&self) -> &(dyn Diagnostics + 'static) {
+        &*self.0
+    }
+}
+
+impl fmt::Debug for Sink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sink")
+    }
+}
+
+/// Diagnostics which are added up. Keep a clone of the `Arc` given to
+/// `TableOptions::diagnostics` or `EpollReactor::set_diagnostics` to
+/// query the totals.
+#[derive(Debug, Default)]
+pub struct Counters {
+    logs_read: AtomicUsize,
+    records_read: AtomicUsize,
+    bytes_read: AtomicUsize,
+    bytes_discarded: AtomicUsize,
+    bytes_truncated: AtomicUsize,
+    compactions: AtomicUsize,
+    compaction_nanos: AtomicU64,
+    reactor_errors: AtomicUsize,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    /// Number of tables opened.
+    pub fn logs_read(&self) -> usize { self.logs_read.load(Ordering::Relaxed) }
+    /// Records replayed opening tables (see `DecodeStats::records`).
+    pub fn records_read(&self) -> usize { self.records_read.load(Ordering::Relaxed) }
+    pub fn bytes_read(&self) -> usize { self.bytes_read.load(Ordering::Relaxed) }
+    /// Bytes of superseded records in the logs of tables opened.
+    pub fn bytes_discarded(&self) -> usize { self.bytes_discarded.load(Ordering::Relaxed) }
+    /// Bytes of torn records dropped from the ends of logs.
+    pub fn bytes_truncated(&self) -> usize { self.bytes_truncated.load(Ordering::Relaxed) }
+    /// Number of compactions and merges completed.
+    pub fn compactions(&self) -> usize { self.compactions.load(Ordering::Relaxed) }
+    /// Total time taken by completed compactions and merges.
+    pub fn compaction_time(&self) -> Duration {
+        Duration::from_nanos(self.compaction_nanos.load(Ordering::Relaxed))
+    }
+    pub fn reactor_errors(&self) -> usize { self.reactor_errors.load(Ordering::Relaxed) }
+}
+
+impl Diagnostics for Counters {
+    fn log_read(&self, _path: &str, stats: &DecodeStats) {
+        self.logs_read.fetch_add(1, Ordering::Relaxed);
+        self.records_read.fetch_add(stats.records(), Ordering::Relaxed);
+        self.bytes_read.fetch_add(stats.read(), Ordering::Relaxed);
+        self.bytes_discarded.fetch_add(stats.discarded(), Ordering::Relaxed);
+        self.bytes_truncated.fetch_add(stats.truncated(), Ordering::Relaxed);
+    }
+
+    fn compacted(&self, _path: &str, elapsed: Duration) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compaction_nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn reactor_error(&self, _err: &io::Error) {
+        self.reactor_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_counters() {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use table::{Table, TableOptions};
+
+    let path = temp_path("counters");
+    let counters = Arc::new(Counters::new());
+    let mut options = TableOptions::new();
+    options.write(true).diagnostics(counters.clone());
+    {
+        let mut t: Table<i64, Vec<u8>> = options.open(&path).unwrap();
+        for i in 0..10 {
+            t.insert(i, vec![i as u8; 10]).unwrap();
+        }
+        t.insert(0, vec![1; 10]).unwrap();
+        t.remove(&1).unwrap();
+    }
+    assert_eq!(counters.logs_read(), 1);
+    assert_eq!(counters.records_read(), 0);
+
+    // A torn record at the end of the log is counted as it is dropped.
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0x01, 0x02]).unwrap();
+    {
+        let mut t: Table<i64, Vec<u8>> = options.open(&path).unwrap();
+        assert_eq!(counters.logs_read(), 2);
+        assert_eq!(counters.records_read(), 12);
+        assert_eq!(counters.bytes_read(), t.stats().read());
+        assert_eq!(counters.bytes_discarded(), t.stats().discarded());
+        assert_eq!(counters.bytes_truncated(), 2);
+        assert!(counters.bytes_discarded() > 0);
+
+        assert_eq!(counters.compactions(), 0);
+        t.compact(&path).unwrap();
+        assert_eq!(counters.compactions(), 1);
+        assert!(counters.compaction_time() > Duration::from_secs(0));
+    }
+
+    // The default sink ignores everything.
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!((&t).into_iter().count(), 9);
+    assert_eq!(counters.logs_read(), 2);
+
+    remove_table(&path);
+}
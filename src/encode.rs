@@ -2,6 +2,8 @@ use std::io::Write;
 use std::io;
 use std::collections::BTreeMap;
 
+use crc32::crc32;
+
 pub trait Encode {
     fn encode<T: Write>(&self, &mut T) -> io::Result<()>;
     fn encode_size(&self) -> usize;
@@ -36,13 +38,14 @@ impl Encode for u64 {
     }
 }
 
-// Signed, not null: 0x81 = i16, 0x80 = i64.
+// Signed, not null: 0x81 = i16, 0x80 = i64. -1 takes the i16 form so
+// that no value starts with the null marker 0xFF.
 
 impl Encode for i64 {
     fn encode<T: Write>(&self, out: &mut T) -> io::Result<()> {
         let mut buff = [0u8; 9];
         let n = *self;
-        return if -0x7F < n && n < 0x80 {
+        return if -0x7F < n && n < 0x80 && n != -1 {
             buff[0] = (n & 0xFF) as u8;
             out.write_all(&buff[..1])
         } else if -0x8000 <= n && n < 0x8000 {
@@ -63,7 +66,7 @@ impl Encode for i64 {
 
     fn encode_size(&self) -> usize {
         let n = *self;
-        if -0x7F < n && n < 0x80 { 1 } else if -0x8000 <= n && n < 0x8000 { 3 } else { 9 }
+        if -0x7F < n && n < 0x80 && n != -1 { 1 } else if -0x8000 <= n && n < 0x8000 { 3 } else { 9 }
     }
 }
 
@@ -108,14 +111,137 @@ impl Encode for Vec<u8> {
     }
 }
 
-impl Encode for BTreeMap<i64, Vec<u8> > {
+// A table log is a sequence of records, each framed as
+//
+//     length (u64) | crc32 (4 bytes, big-endian) | key | value
+//
+// where length and crc32 cover the key and value. A removal is written
+// as the key followed by the null marker in place of the value.
+//
+// Control records, which carry information about the log rather than
+// table data, are framed the same way after a leading null marker:
+//
+//     0xFF | length (u64) | crc32 | kind (1 byte) | argument (u64)
+//
+// A record whose value is compressed (see `compress`) is framed like a
+// control record of its own kind:
+//
+//     0xFF | length (u64) | crc32 | 'Z' | key | compressed value
+
+/// The kind of a record holding a compressed value.
+pub const COMPRESSED: u8 = b'Z';
+
+/// Log control records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Control {
+    /// Starts a batch of the given number of records, which take
+    /// effect only if the matching `BatchCommit` follows them.
+    BatchBegin(u64),
+    /// Ends a batch of the given number of records.
+    BatchCommit(u64),
+    /// Starts a log which continues from the checkpoint with the given
+    /// generation (see `checkpoint`).
+    Checkpoint(u64),
+    /// Gives the time, in milliseconds since the Unix epoch, at which
+    /// the value set by the record which follows expires.
+    Expires(u64),
+    /// Records that the keys below the given one have been issued by
+    /// the table's sequence (see `Table::insert_auto`).
+    Sequence(u64),
+}
+
+impl Control {
+    pub fn kind(&self) -> u8 {
+        match *self {
+            Control::BatchBegin(_) => b'B',
+            Control::BatchCommit(_) => b'C',
+            Control::Checkpoint(_) => b'K',
+            Control::Expires(_) => b'X',
+            Control::Sequence(_) => b'S',
+        }
+    }
+
+    pub fn arg(&self) -> u64 {
+        match *self {
+            Control::BatchBegin(n) => n,
+            Control::BatchCommit(n) => n,
+            Control::Checkpoint(n) => n,
+            Control::Expires(t) => t,
+            Control::Sequence(n) => n,
+        }
+    }
+
+    /// Returns the control record of the given kind, if it is known.
+    pub fn from_parts(kind: u8, arg: u64) -> Option<Control> {
+        match kind {
+            b'B' => Some(Control::BatchBegin(arg)),
+            b'C' => Some(Control::BatchCommit(arg)),
+            b'K' => Some(Control::Checkpoint(arg)),
+            b'X' => Some(Control::Expires(arg)),
+            b'S' => Some(Control::Sequence(arg)),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the number of bytes taken by a framed record whose key and
+/// value together encode to `payload` bytes.
+pub fn record_size(payload: usize) -> usize {
+    (payload as u64).encode_size() + 4 + payload
+}
+
+/// Appends `payload` to `buf` framed with its length and checksum.
+pub fn frame(buf: &mut Vec<u8>, payload: &[u8]) {
+    let crc = crc32(payload);
+    (payload.len() as u64).encode(buf).unwrap();
+    buf.extend(&[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8]);
+    buf.extend(payload);
+}
+
+/// Appends a framed record to `buf`: `Some(value)` records an insert
+/// and `None` a removal of `key`.
+pub fn frame_record<K: Encode, V: Encode>(buf: &mut Vec<u8>, key: &K, value: Option<&V>) {
+    let mut payload: Vec<u8> = Vec::new();
+    key.encode(&mut payload).unwrap();
+    match value {
+        Some(v) => v.encode(&mut payload).unwrap(),
+        None => None.encode(&mut payload).unwrap(),
+    };
+    frame(buf, &payload);
+}
+
+/// Appends a framed record to `buf` for `key` with a value compressed
+/// into `block`.
+pub fn frame_compressed<K: Encode>(buf: &mut Vec<u8>, key: &K, block: &[u8]) {
+    let mut payload: Vec<u8> = vec![COMPRESSED];
+    key.encode(&mut payload).unwrap();
+    payload.extend(block);
+    buf.push(0xFF);
+    frame(buf, &payload);
+}
+
+/// Appends a framed control record to `buf`.
+pub fn frame_control(buf: &mut Vec<u8>, control: Control) {
+    let mut payload: Vec<u8> = vec![control.kind()];
+    control.arg().encode(&mut payload).unwrap();
+    buf.push(0xFF);
+    frame(buf, &payload);
+}
+
+/// Writes a single framed record to `out`.
+///
+/// The record is assembled in memory and written with a single call so
+/// that a crash can leave at most a torn record at the end of a log.
+pub fn encode_record<T: Write, K: Encode, V: Encode>(out: &mut T, key: &K, value: Option<&V>) -> io::Result<()> {
+    let mut buf: Vec<u8> = Vec::new();
+    frame_record(&mut buf, key, value);
+    out.write_all(&buf)
+}
+
+impl<K: Encode, V: Encode> Encode for BTreeMap<K, V> {
     fn encode<T: Write>(&self, out: &mut T) -> io::Result<()> {
         for (key, value) in self {
-            match key.encode(out) {
-                Err(err) => return Err(err),
-                Ok(()) => (),
-            };
-            match value.as_slice().encode(out) {
+            match encode_record(out, key, Some(value)) {
                 Err(err) => return Err(err),
                 Ok(()) => (),
             };
@@ -126,8 +252,7 @@ impl Encode for BTreeMap<i64, Vec<u8> > {
     fn encode_size(&self) -> usize {
         let mut n: usize = 0;
         for (key, value) in self {
-            n += key.encode_size();
-            n += value.encode_size();
+            n += record_size(key.encode_size() + value.encode_size());
         }
         n
     }
@@ -170,4 +295,5 @@ fn test_encode() {
 
     assert_eq!(encode_to_hex(&-0x7Fi64), "81FF81");
     assert_eq!(encode_to_hex(&-0x80i64), "81FF80");
+    assert_eq!(encode_to_hex(&-1i64), "81FFFF");
 }
@@ -0,0 +1,175 @@
+//! Expiry times for the keys of a table written with a time to live.
+//!
+//! A value written with a time to live is preceded in the log by a
+//! `Control::Expires` record giving the time it expires, in milliseconds
+//! since the Unix epoch. An expired key is hidden from reads until it is
+//! swept, which writes its removal, or dropped by compaction.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Returns the time `ttl` from now in milliseconds since the Unix epoch.
+pub fn expiry_after(ttl: Duration) -> u64 {
+    now_millis() + ttl.as_secs() * 1000 + ttl.subsec_millis() as u64
+}
+
+/// The expiry times of the keys of a table, by key and in time order.
+#[derive(Clone, Debug)]
+pub struct Expiries<K> {
+    by_key: BTreeMap<K, u64>,
+    by_time: BTreeSet<(u64, K)>,
+}
+
+impl<K: Ord + Clone> Expiries<K> {
+    pub fn new() -> Expiries<K> {
+        Expiries { by_key: BTreeMap::new(), by_time: BTreeSet::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Option<u64> {
+        self.by_key.get(key).cloned()
+    }
+
+    /// Sets when `key` expires, or that it does not if `expires` is None.
+    pub fn set(&mut self, key: &K, expires: Option<u64>) {
+        if let Some(old) = self.by_key.remove(key) {
+            self.by_time.remove(&(old, key.clone()));
+        }
+        if let Some(t) = expires {
+            self.by_key.insert(key.clone(), t);
+            self.by_time.insert((t, key.clone()));
+        }
+    }
+
+    /// Whether `key` has expired at time `now`.
+    pub fn is_expired(&self, key: &K, now: u64) -> bool {
+        match self.by_key.get(key) {
+            Some(t) => *t <= now,
+            None => false,
+        }
+    }
+
+    /// The earliest time any key expires.
+    pub fn next(&self) -> Option<u64> {
+        self.by_time.iter().next().map(|&(t, _)| t)
+    }
+
+    /// Returns the number of milliseconds until a key expires, 0 if one
+    /// has already, or -1 if none will, as the reactor's
+    /// `Scheduler::due` does.
+    pub fn due(&self) -> i32 {
+        match self.next() {
+            None => -1,
+            Some(t) => {
+                let now = now_millis();
+                if t <= now {
+                    0
+                } else if t - now > i32::max_value() as u64 {
+                    i32::max_value()
+                } else {
+                    (t - now) as i32
+                }
+            },
+        }
+    }
+
+    /// Lists the keys which have expired at time `now`, earliest first.
+    pub fn expired(&self, now: u64) -> Vec<K> {
+        self.by_time.iter().take_while(|&&(t, _)| t <= now).map(|&(_, ref k)| k.clone()).collect()
+    }
+}
+
+impl<K: Ord + Clone> Default for Expiries<K> {
+    fn default() -> Expiries<K> {
+        Expiries::new()
+    }
+}
+
+/// An iterator over the entries of a table which skips expired keys.
+pub struct Unexpired<'a, K: 'a, I> {
+    inner: I,
+    expiries: &'a Expiries<K>,
+    now: u64,
+}
+
+impl<'a, K: Ord + Clone, I> Unexpired<'a, K, I> {
+    /// Iterates over the entries of `inner` which have not expired now.
+    pub fn new(inner: I, expiries: &'a Expiries<K>) -> Unexpired<'a, K, I> {
+        // Most tables have no expiring keys, so need no clock.
+        let now = if expiries.len() == 0 { 0 } else { now_millis() };
+        Unexpired::at(inner, expiries, now)
+    }
+
+    /// Iterates over the entries of `inner` which had not expired at
+    /// time `now`.
+    pub fn at(inner: I, expiries: &'a Expiries<K>, now: u64) -> Unexpired<'a, K, I> {
+        Unexpired { inner, expiries, now }
+    }
+}
+
+impl<'a, K: Ord + Clone, V: 'a, I: Iterator<Item = (&'a K, &'a V)>> Iterator for Unexpired<'a, K, I> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            match self.inner.next() {
+                Some((k, _)) if self.expiries.is_expired(k, self.now) => (),
+                item => return item,
+            }
+        }
+    }
+}
+
+impl<'a, K: Ord + Clone, V: 'a, I: DoubleEndedIterator<Item = (&'a K, &'a V)>> DoubleEndedIterator
+    for Unexpired<'a, K, I>
+{
+    fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            match self.inner.next_back() {
+                Some((k, _)) if self.expiries.is_expired(k, self.now) => (),
+                item => return item,
+            }
+        }
+    }
+}
+
+#[test]
+fn test_expiries() {
+    let mut e: Expiries<i64> = Expiries::new();
+    e.set(&1, Some(300));
+    e.set(&2, Some(100));
+    e.set(&3, Some(200));
+    e.set(&3, Some(50));
+    e.set(&4, None);
+    assert_eq!(e.len(), 3);
+    assert_eq!(e.next(), Some(50));
+    assert_eq!(e.expired(100), vec![3, 2]);
+    assert!(e.is_expired(&2, 100) && !e.is_expired(&1, 100) && !e.is_expired(&4, 100));
+    e.set(&3, None);
+    assert_eq!(e.get(&3), None);
+    assert_eq!(e.next(), Some(100));
+
+    let map: BTreeMap<i64, ()> = (1..5).map(|k| (k, ())).collect();
+    let mut e: Expiries<i64> = Expiries::new();
+    e.set(&1, Some(0));
+    e.set(&4, Some(0));
+    e.set(&3, Some(u64::max_value()));
+    let live: Vec<i64> = Unexpired::new(map.iter(), &e).map(|(k, _)| *k).collect();
+    assert_eq!(live, vec![2, 3]);
+    assert_eq!(Unexpired::new(map.iter(), &e).next_back(), Some((&3, &())));
+}
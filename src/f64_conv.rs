@@ -162,6 +162,7 @@ pub fn f64_from_bytes(v: &[u8]) -> f64 {
 }
 
 /// Errors that can occur when decoding a hex encoded string
+#[allow(dead_code)]
 #[cfg(test)]
 #[derive(Copy, Clone, Debug)]
 enum FromHexError {
@@ -181,9 +182,9 @@ fn from_hex(s: &str) -> Result<Vec<u8>, FromHexError> {
         buf <<= 4;
 
         match byte {
-            b'A'...b'F' => buf |= byte - b'A' + 10,
-            b'a'...b'f' => buf |= byte - b'a' + 10,
-            b'0'...b'9' => buf |= byte - b'0',
+            b'A'..=b'F' => buf |= byte - b'A' + 10,
+            b'a'..=b'f' => buf |= byte - b'a' + 10,
+            b'0'..=b'9' => buf |= byte - b'0',
             _ => {
                 let ch = s[idx..].chars().next().unwrap();
                 return Err(FromHexError::InvalidHexCharacter(ch, idx))
@@ -0,0 +1,274 @@
+//! Following the changes made to a table by another process.
+
+use std::fs::{File, metadata};
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use codec::Codecs;
+use encode::Encode;
+use decode::*;
+use header::TypeDescriptor;
+use table::{TableError, TableOptions};
+
+/// A change to a table: a key and its new value, or None if it was removed.
+pub type Change<K, V> = (K, Option<V>);
+
+// Applies replayed records to a map, noting the changes they make.
+struct Changes<'a, K: 'a, V: 'a> {
+    map: &'a mut BTreeMap<K, V>,
+    changes: Vec<Change<K, V>>,
+}
+
+impl<'a, K: Encode + Ord + Clone, V: Encode + Clone> LogMap<K, V> for Changes<'a, K, V> {
+    fn replay_insert(&mut self, key: K, value: V, loc: ValueLoc) -> Option<usize> {
+        self.changes.push((key.clone(), Some(value.clone())));
+        self.map.replay_insert(key, value, loc)
+    }
+
+    fn replay_remove(&mut self, key: &K) -> Option<usize> {
+        let old = self.map.replay_remove(key);
+        if old.is_some() {
+            self.changes.push((key.clone(), None));
+        }
+        old
+    }
+}
+
+/// A read-only view of a table which another process is writing,
+/// brought up to date with the records appended to its log by `poll`.
+///
+/// The follower does not lock the table, so it can follow a table open
+/// for writing with `Table::open_rw`. When the log is replaced by a
+/// compaction or checkpoint, the follower reads the new log and reports
+/// the difference it makes. Segmented tables cannot be followed.
+pub struct TableFollower<K, V> {
+    path: String,
+    file: File,
+    // Length of the complete records read from the log.
+    position: usize,
+    // Codecs the values of the log are stored with.
+    codecs: Codecs,
+    map: BTreeMap<K, V>,
+    options: TableOptions,
+    poll_interval: Duration,
+}
+
+impl TableOptions {
+    /// Opens the table at `path` as a `TableFollower`. The options are
+    /// only used to decode the log.
+    pub fn follow<K, V>(&self, path: &str) -> Result<TableFollower<K, V>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor + Clone + PartialEq
+    {
+        let mut t = TableFollower {
+            path: path.to_string(),
+            file: match File::open(path) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(f) => f,
+            },
+            position: 0,
+            codecs: Codecs::new(),
+            map: BTreeMap::new(),
+            options: self.clone(),
+            poll_interval: Duration::from_millis(50),
+        };
+        match t.load() {
+            Err(e) => Err(e),
+            Ok(_) => Ok(t),
+        }
+    }
+}
+
+impl<K, V> TableFollower<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor + Clone + PartialEq
+{
+    pub fn open(path: &str) -> Result<TableFollower<K, V>, TableError> {
+        TableOptions::new().follow(path)
+    }
+
+    /// How often `wait` checks the log for new records.
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.map.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The contents of the table as of the last poll.
+    pub fn map(&self) -> &BTreeMap<K, V> {
+        &self.map
+    }
+
+    // Replays the whole of the log in file into map, returning the old
+    // contents of map.
+    fn load(&mut self) -> Result<BTreeMap<K, V>, TableError> {
+        let mut m = BTreeMap::new();
+        let mut stats = DecodeStats::default();
+        let has_header = match self.options.load_log(&self.path, &self.file, &mut m, &mut stats) {
+            Err(e) => return Err(e),
+            Ok(h) => h,
+        };
+        self.codecs = if has_header { self.options.codecs.clone() } else { Codecs::new() };
+        self.position = stats.valid();
+        Ok(::std::mem::replace(&mut self.map, m))
+    }
+
+    // Whether the file at path is no longer the one being followed.
+    fn replaced(&self) -> Result<bool, TableError> {
+        let (following, current) = match (self.file.metadata(), metadata(&self.path)) {
+            (Ok(m1), Ok(m2)) => (m1, m2),
+            (Err(ioerr), _) => return Err(TableError::IOError(ioerr)),
+            // The file is missing only if it was removed, rather than
+            // replaced, so there is nothing to switch to.
+            (_, Err(_)) => return Ok(false),
+        };
+        Ok(following.dev() != current.dev() || following.ino() != current.ino())
+    }
+
+    /// Reads the records appended to the log since the last poll and
+    /// returns the changes they make, in the order they were made.
+    pub fn poll(&mut self) -> Result<Vec<Change<K, V>>, TableError> {
+        let mut changes = Changes { map: &mut self.map, changes: Vec::new() };
+        match self.file.seek(SeekFrom::Start(self.position as u64)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(_) => (),
+        };
+        // A record being written or a batch not yet committed is left
+        // for a later poll.
+        let mut stats = DecodeStats::default();
+        stats.append(self.position, 0);
+        match replay_coded(&mut io::BufReader::new(&self.file), &mut stats, &mut changes, &self.codecs) {
+            Err(de) => return Err(TableError::DecodeError(de)),
+            Ok(()) => (),
+        };
+        self.position = stats.valid();
+        let mut result = changes.changes;
+
+        let replaced = match self.replaced() {
+            Err(e) => return Err(e),
+            Ok(r) => r,
+        };
+        if replaced {
+            // Everything written to the old log is also in the new, so
+            // the new log changes only what differs between the two.
+            self.file = match File::open(&self.path) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(f) => f,
+            };
+            let old = match self.load() {
+                Err(e) => return Err(e),
+                Ok(old) => old,
+            };
+            for key in old.keys() {
+                if !self.map.contains_key(key) {
+                    result.push((key.clone(), None));
+                }
+            }
+            for (key, value) in self.map.iter() {
+                if old.get(key) != Some(value) {
+                    result.push((key.clone(), Some(value.clone())));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Polls until there are changes to return or `timeout` passes.
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<Change<K, V>>, TableError> {
+        let start = Instant::now();
+        loop {
+            match self.poll() {
+                Err(e) => return Err(e),
+                Ok(ref changes) if changes.is_empty() && start.elapsed() < timeout => (),
+                Ok(changes) => return Ok(changes),
+            };
+            sleep(self.poll_interval);
+        }
+    }
+}
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_follow() {
+    use table::{Table, WriteBatch};
+
+    let path = temp_path("follow");
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    t.insert(1, b"one".to_vec()).unwrap();
+    t.insert(2, b"two".to_vec()).unwrap();
+    t.flush().unwrap();
+
+    let mut f: TableFollower<i64, Vec<u8>> = TableFollower::open(&path).unwrap();
+    assert_eq!(f.len(), 2);
+    assert_eq!(f.poll().unwrap(), vec![]);
+
+    t.insert(3, b"three".to_vec()).unwrap();
+    t.remove(&1).unwrap();
+    t.remove(&10).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert(4, b"four".to_vec());
+    batch.insert(2, b"deux".to_vec());
+    t.write(batch).unwrap();
+    t.flush().unwrap();
+    assert_eq!(f.wait(Duration::from_secs(1)).unwrap(), vec![
+        (3, Some(b"three".to_vec())),
+        (1, None),
+        (4, Some(b"four".to_vec())),
+        (2, Some(b"deux".to_vec())),
+    ]);
+    assert_eq!(f.get(&2), Some(&b"deux".to_vec()));
+
+    // A record not yet completely written is left for the next poll.
+    let position = f.position;
+    let mut buf: Vec<u8> = Vec::new();
+    ::encode::frame_record(&mut buf, &5i64, Some(&b"five".to_vec()));
+    {
+        use std::io::Write;
+        let mut w = ::std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        w.write_all(&buf[..buf.len() - 2]).unwrap();
+        assert_eq!(f.poll().unwrap(), vec![]);
+        assert_eq!(f.position, position);
+        w.write_all(&buf[buf.len() - 2..]).unwrap();
+    }
+    assert_eq!(f.poll().unwrap(), vec![(5, Some(b"five".to_vec()))]);
+    drop(t);
+
+    // Compaction replaces the log, which is followed to the new one.
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    t.insert(6, b"six".to_vec()).unwrap();
+    t.flush().unwrap();
+    t.compact(&path).unwrap();
+    t.insert(7, b"seven".to_vec()).unwrap();
+    t.remove(&3).unwrap();
+    t.flush().unwrap();
+    assert_eq!(f.poll().unwrap(), vec![
+        (6, Some(b"six".to_vec())),
+        (3, None),
+        (7, Some(b"seven".to_vec())),
+    ]);
+    assert_eq!(f.poll().unwrap(), vec![]);
+
+    // As does a checkpoint.
+    t.insert(2, b"two".to_vec()).unwrap();
+    t.checkpoint().unwrap();
+    t.insert(8, b"eight".to_vec()).unwrap();
+    t.flush().unwrap();
+    assert_eq!(f.poll().unwrap(), vec![(2, Some(b"two".to_vec())), (8, Some(b"eight".to_vec()))]);
+    assert_eq!(f.map(), &(&t).into_iter().map(|(k, v)| (*k, v.clone())).collect());
+
+    remove_table(&path);
+}
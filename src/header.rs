@@ -0,0 +1,172 @@
+//! The header at the start of a table file.
+//!
+//! A header identifies a file as a table log, gives the version of
+//! the log format and names the key and value types it was written
+//! with, so that a table cannot be opened with the wrong types.
+//!
+//! It is written as the magic number followed by a frame (see
+//! `encode::frame`) holding the version and the two type descriptors,
+//! followed from version 2 by the names of the codecs values are stored
+//! with (see `codec`). A table without codecs is written as version 1,
+//! which earlier readers understand.
+
+use std::io;
+use std::io::Write;
+
+use encode::{Encode, frame};
+use decode::{Decode, DecodeError, DecodeStats, decode_frame};
+
+/// The first bytes of every table file with a header. The high bit set
+/// in the first byte keeps the file from being mistaken for text.
+pub const MAGIC: [u8; 4] = [0x89, b'B', b'T', b'L'];
+
+/// The newest version of the log format written by this crate.
+pub const VERSION: u64 = 2;
+
+/// Names a type which can be stored in a table.
+pub trait TypeDescriptor {
+    fn type_descriptor() -> String;
+}
+
+impl TypeDescriptor for u64 {
+    fn type_descriptor() -> String { String::from("u64") }
+}
+
+impl TypeDescriptor for i64 {
+    fn type_descriptor() -> String { String::from("i64") }
+}
+
+impl TypeDescriptor for Vec<u8> {
+    fn type_descriptor() -> String { String::from("bytes") }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub version: u64,
+    pub key_type: String,
+    pub value_type: String,
+    pub codecs: Vec<String>,
+}
+
+impl Header {
+    /// Returns the current header for a table from `K` to `V`.
+    pub fn new<K: TypeDescriptor, V: TypeDescriptor>() -> Header {
+        Header {
+            version: 1,
+            key_type: K::type_descriptor(),
+            value_type: V::type_descriptor(),
+            codecs: Vec::new(),
+        }
+    }
+
+    /// Returns the header for a table whose values are stored with the
+    /// codecs named by `codecs`.
+    pub fn with_codecs(mut self, codecs: Vec<String>) -> Header {
+        self.version = if codecs.is_empty() { 1 } else { 2 };
+        self.codecs = codecs;
+        self
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+        self.version.encode(&mut payload).unwrap();
+        self.key_type.as_bytes().encode(&mut payload).unwrap();
+        self.value_type.as_bytes().encode(&mut payload).unwrap();
+        if self.version >= 2 {
+            (self.codecs.len() as u64).encode(&mut payload).unwrap();
+            for name in &self.codecs {
+                name.as_bytes().encode(&mut payload).unwrap();
+            }
+        }
+        payload
+    }
+
+    /// Reads a header from the start of `src`. If `src` does not start
+    /// with the magic number nothing is consumed and `None` is returned.
+    pub fn decode_stats<T: io::BufRead>(src: &mut T, stats: &mut DecodeStats) ->
+        Result<Option<Header>, DecodeError>
+    {
+        let found = match src.fill_buf() {
+            Err(err) => return Err(DecodeError::IOError { err }),
+            Ok(buf) => buf.len() >= MAGIC.len() && buf[..MAGIC.len()] == MAGIC,
+        };
+        if !found {
+            return Ok(None);
+        }
+        src.consume(MAGIC.len());
+        stats.append(MAGIC.len(), 0);
+
+        let payload = match decode_frame(src, stats) {
+            Err(DecodeError::EOF) => return Err(DecodeError::PartialRead),
+            Err(err) => return Err(err),
+            Ok(payload) => payload,
+        };
+        let mut p = io::Cursor::new(payload);
+        let version = match u64::decode(&mut p) {
+            Err(_) => return Err(DecodeError::Corrupt),
+            Ok(v) => v,
+        };
+        let mut names = Vec::new();
+        let mut count = 2;
+        while names.len() < count {
+            match Vec::<u8>::decode(&mut p) {
+                Err(_) => return Err(DecodeError::Corrupt),
+                Ok(v) => match String::from_utf8(v) {
+                    Err(_) => return Err(DecodeError::Corrupt),
+                    Ok(s) => names.push(s),
+                },
+            };
+            // From version 2 the types are followed by the codecs.
+            if names.len() == 2 && version >= 2 {
+                count += match u64::decode(&mut p) {
+                    Err(_) => return Err(DecodeError::Corrupt),
+                    Ok(n) => n as usize,
+                };
+            }
+        }
+        let codecs = names.split_off(2);
+        let value_type = names.pop().unwrap();
+        let key_type = names.pop().unwrap();
+        Ok(Some(Header { version, key_type, value_type, codecs }))
+    }
+}
+
+impl Encode for Header {
+    fn encode<T: Write>(&self, out: &mut T) -> io::Result<()> {
+        let mut buf: Vec<u8> = MAGIC.to_vec();
+        frame(&mut buf, &self.payload());
+        out.write_all(&buf)
+    }
+
+    fn encode_size(&self) -> usize {
+        let n = self.payload().len();
+        MAGIC.len() + (n as u64).encode_size() + 4 + n
+    }
+}
+
+#[test]
+fn test_header() {
+    use encode::encode;
+
+    let h = Header::new::<i64, Vec<u8>>();
+    let data = encode(&h);
+    assert_eq!(data.len(), h.encode_size());
+
+    let mut stats = DecodeStats::default();
+    let mut src = io::Cursor::new(&data[..]);
+    assert_eq!(Header::decode_stats(&mut src, &mut stats).unwrap(), Some(h));
+    assert_eq!(stats.read(), data.len());
+
+    // Anything else is left alone.
+    let mut stats = DecodeStats::default();
+    let mut src = io::Cursor::new(&data[1..]);
+    assert_eq!(Header::decode_stats(&mut src, &mut stats).unwrap(), None);
+    assert_eq!(src.position(), 0);
+
+    let h = Header::new::<i64, Vec<u8>>().with_codecs(vec!["crc32".to_string()]);
+    assert_eq!(h.version, 2);
+    let data = encode(&h);
+    assert_eq!(data.len(), h.encode_size());
+    let mut stats = DecodeStats::default();
+    assert_eq!(Header::decode_stats(&mut &data[..], &mut stats).unwrap(), Some(h));
+}
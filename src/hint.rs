@@ -0,0 +1,258 @@
+//! Hint files, which let a table be opened without replaying its log.
+//!
+//! A hint describes the first `covered` bytes of a table log as where
+//! the latest value of each key written there lies, or that the key was
+//! removed. Opening a table applies the hint and replays only the part
+//! of the log written after it. A hint file is written as
+//!
+//!     magic | frame(version, dev, ino, covered, check, discarded,
+//!                   compressed records, raw size, stored size,
+//!                   sequence, count)
+//!           | count * frame(key | offset | length | compressed | expires)
+//!
+//! with a null offset for a removal and a null expiry for a value
+//! which does not expire. The device and inode numbers of
+//! the log, and a checksum of the bytes just before `covered`, identify
+//! the log a hint was written for; a hint which does not match its log
+//! is ignored.
+
+use std::fs::{File, OpenOptions, rename};
+use std::io;
+use std::io::Write;
+use std::collections::BTreeMap;
+use std::os::unix::fs::{FileExt, MetadataExt};
+
+use codec::Codecs;
+use crc32::crc32;
+use encode::{Encode, frame};
+use decode::*;
+use header::Header;
+
+/// The first bytes of a hint file.
+pub const HINT_MAGIC: [u8; 4] = [0x89, b'B', b'T', b'H'];
+
+const HINT_VERSION: u64 = 4;
+
+// Number of bytes before the end of the covered part of a log which
+// are checked against a hint.
+const CHECK_LEN: usize = 64;
+
+/// The keys written to a log, with where the latest value of each lies
+/// or None if its latest record is a removal, and the sequence recorded
+/// there (see `Control::Sequence`).
+pub struct KeyLocations<K>(pub BTreeMap<K, Option<ValueLoc>>, pub u64);
+
+impl<K: Encode + Ord + Clone, V> LogMap<K, V> for KeyLocations<K> {
+    fn replay_insert(&mut self, key: K, _value: V, loc: ValueLoc) -> Option<usize> {
+        let keysize = key.encode_size();
+        match self.0.insert(key, Some(loc)) {
+            Some(Some(old)) => Some(old.record_size(keysize)),
+            _ => None,
+        }
+    }
+
+    fn replay_remove(&mut self, key: &K) -> Option<usize> {
+        match self.0.insert(key.clone(), None) {
+            Some(Some(old)) => Some(old.record_size(key.encode_size())),
+            _ => None,
+        }
+    }
+
+    fn replay_sequence(&mut self, next: u64) {
+        self.1 = self.1.max(next);
+    }
+}
+
+pub struct Hint<K> {
+    /// Length of the part of the log described by the hint.
+    pub covered: usize,
+    /// Bytes of that part made redundant by later records within it.
+    pub discarded: usize,
+    /// The compressed values in that part.
+    pub compression: CompressionStats,
+    /// The latest sequence recorded in that part, or 0 if none was.
+    pub sequence: u64,
+    pub entries: BTreeMap<K, Option<ValueLoc>>,
+}
+
+/// Returns the path of the hint file for the log at `log_path`.
+pub fn hint_path(log_path: &str) -> String {
+    format!("{}.hint", log_path)
+}
+
+// Returns the checksum of the bytes of log just before covered.
+fn covered_check(log: &File, covered: usize) -> io::Result<u32> {
+    let n = if covered < CHECK_LEN { covered } else { CHECK_LEN };
+    let mut buf = vec![0u8; n];
+    log.read_exact_at(&mut buf, (covered - n) as u64)?;
+    Ok(crc32(&buf))
+}
+
+impl<K: Encode + Decode + Ord + Clone> Hint<K> {
+    /// Replays the complete records of `log`, whose values were stored
+    /// with `codecs`, to build a hint for it.
+    pub fn build<V: Decode>(log: &File, codecs: &Codecs) -> Result<Hint<K>, DecodeError> {
+        let mut r = io::BufReader::new(log);
+        let mut stats = DecodeStats::default();
+        match Header::decode_stats(&mut r, &mut stats) {
+            Err(de) => return Err(de),
+            Ok(_) => (),
+        };
+        let mut keys = KeyLocations(BTreeMap::new(), 0);
+        match replay_coded::<K, V, _, _>(&mut r, &mut stats, &mut keys, codecs) {
+            Err(de) => return Err(de),
+            Ok(()) => (),
+        };
+        Ok(Hint {
+            covered: stats.valid(),
+            discarded: stats.discarded(),
+            compression: *stats.compression(),
+            sequence: keys.1,
+            entries: keys.0,
+        })
+    }
+
+    /// Writes the hint for `log` to the file at `path`, replacing any
+    /// hint already there.
+    pub fn write(&self, path: &str, log: &File) -> io::Result<()> {
+        let meta = log.metadata()?;
+        let check = covered_check(log, self.covered)?;
+        let mut payload: Vec<u8> = Vec::new();
+        let c = &self.compression;
+        for n in &[HINT_VERSION, meta.dev(), meta.ino(), self.covered as u64, check as u64, self.discarded as u64,
+                   c.records as u64, c.raw as u64, c.stored as u64, self.sequence, self.entries.len() as u64] {
+            n.encode(&mut payload)?;
+        }
+        let mut buf: Vec<u8> = HINT_MAGIC.to_vec();
+        frame(&mut buf, &payload);
+        for (key, loc) in &self.entries {
+            payload.clear();
+            key.encode(&mut payload)?;
+            match *loc {
+                Some(loc) => {
+                    loc.offset.encode(&mut payload)?;
+                    (loc.len as u64).encode(&mut payload)?;
+                    (loc.compressed as u64).encode(&mut payload)?;
+                    loc.expires.encode(&mut payload)?;
+                },
+                None => None.encode(&mut payload)?,
+            };
+            frame(&mut buf, &payload);
+        }
+
+        let mut newpath = path.to_string();
+        newpath.push('~');
+        let mut f = OpenOptions::new().write(true).create(true).truncate(true).open(&newpath)?;
+        f.write_all(&buf)?;
+        rename(newpath, path)
+    }
+
+    /// Reads the hint at `path` for `log`. Returns None if there is no
+    /// hint, or it is damaged or was not written for `log`.
+    pub fn read(path: &str, log: &File) -> Option<Hint<K>> {
+        let f = match File::open(path) {
+            Err(_) => return None,
+            Ok(f) => f,
+        };
+        let mut r = io::BufReader::new(f);
+        let mut stats = DecodeStats::default();
+        let mut magic = [0u8; 4];
+        match io::Read::read_exact(&mut r, &mut magic) {
+            Ok(()) if magic == HINT_MAGIC => (),
+            _ => return None,
+        };
+        let payload = match decode_frame(&mut r, &mut stats) {
+            Err(_) => return None,
+            Ok(payload) => payload,
+        };
+        let mut p = &payload[..];
+        let mut fields = [0u64; 11];
+        for n in fields.iter_mut() {
+            *n = match u64::decode(&mut p) {
+                Err(_) => return None,
+                Ok(n) => n,
+            };
+        }
+        let [version, dev, ino, covered, check, discarded, records, raw, stored, sequence, count] = fields;
+        let meta = match log.metadata() {
+            Err(_) => return None,
+            Ok(meta) => meta,
+        };
+        if version != HINT_VERSION || dev != meta.dev() || ino != meta.ino() || covered > meta.len() {
+            return None;
+        }
+        match covered_check(log, covered as usize) {
+            Ok(c) if c as u64 == check => (),
+            _ => return None,
+        };
+
+        let mut entries = BTreeMap::new();
+        for _ in 0..count {
+            let payload = match decode_frame(&mut r, &mut stats) {
+                Err(_) => return None,
+                Ok(payload) => payload,
+            };
+            let mut p = &payload[..];
+            let key = match K::decode(&mut p) {
+                Err(_) => return None,
+                Ok(key) => key,
+            };
+            let loc = match u64::decode(&mut p) {
+                Err(DecodeError::Null) => None,
+                Err(_) => return None,
+                Ok(offset) => match (u64::decode(&mut p), u64::decode(&mut p), u64::decode(&mut p)) {
+                    (Ok(len), Ok(compressed), expires) => Some(ValueLoc {
+                        offset,
+                        len: len as usize,
+                        compressed: compressed == 1,
+                        expires: match expires {
+                            Err(DecodeError::Null) => None,
+                            Err(_) => return None,
+                            Ok(t) => Some(t),
+                        },
+                    }),
+                    _ => return None,
+                },
+            };
+            entries.insert(key, loc);
+        }
+        Some(Hint {
+            covered: covered as usize,
+            discarded: discarded as usize,
+            compression: CompressionStats { records: records as usize, raw: raw as usize, stored: stored as usize },
+            sequence,
+            entries,
+        })
+    }
+
+    /// Applies the hint to `data` as replaying the part of `log` it
+    /// covers would, accounting in `stats` for its compressed values
+    /// and what its records make redundant in `data` (but not for the
+    /// covered bytes). Values are decoded as stored with `codecs`.
+    pub fn apply<V, M>(self, log: &File, data: &mut M, stats: &mut DecodeStats, codecs: &Codecs) ->
+        Result<(), DecodeError>
+        where V: Decode, M: LogMap<K, V>
+    {
+        stats.add_compression(&self.compression);
+        if self.sequence > 0 {
+            data.replay_sequence(self.sequence);
+        }
+        // Values are read in the order they lie in the log, so that
+        // reading them is one pass through it rather than a seek each.
+        let mut entries: Vec<(K, Option<ValueLoc>)> = self.entries.into_iter().collect();
+        entries.sort_by_key(|e| e.1.map(|loc| loc.offset));
+        for (key, loc) in entries {
+            let old = match loc {
+                Some(loc) => match data.hint_insert(key, loc, log, codecs) {
+                    Err(de) => return Err(de),
+                    Ok(old) => old,
+                },
+                None => data.replay_remove(&key),
+            };
+            if let Some(size) = old {
+                stats.append(0, size);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,652 @@
+//! Secondary indexes on the fields of a table of JSON rows.
+//!
+//! An index is declared on one or more fields of the rows of an
+//! `IndexedTable`, each named by a path of object keys separated by
+//! dots, or `#` for the key of the row itself. A row lacking a field
+//! has it indexed as `null`.
+//!
+//! Index `name` of the table at `path` is kept in a table of its own at
+//! `path.index.name`, whose keys are arrays holding the indexed fields
+//! of a row followed by the row key, and whose values hold the row key.
+//! These sort by the fields as the `Ord` of `JSON` does, which lookups
+//! rely on. The fields of each index are recorded in the table at
+//! `path.indexes`.
+//!
+//! Indexes are updated after the table on every write, so a crash can
+//! leave them behind it; opening the table brings them up to date. The
+//! point of the table log each index is known to agree with is recorded
+//! beside it at `index_point_path` once the index is on disk, so that
+//! only the rows changed by the records appended since need be
+//! reindexed. An index with no such point, or whose point is in a log
+//! since replaced by a compaction or checkpoint, is rebuilt in full.
+
+use std::collections::{BTreeMap, BTreeSet, Bound};
+use std::fs::remove_file;
+
+use backup::{BackupPoint, backup_point_path};
+use codec::Codecs;
+use decode::*;
+use json::JSON;
+use mem_table::{JSONRow, TableObserver};
+use hint::hint_path;
+use table::{Table, TableError, TableOptions, WriteBatch};
+
+/// Returns the path of index `name` of the table at `path`.
+pub fn index_path(path: &str, name: &str) -> String {
+    format!("{}.index.{}", path, name)
+}
+
+/// Returns the path the point of the log of the table at `path` which
+/// index `name` agrees with is recorded at.
+pub fn index_point_path(path: &str, name: &str) -> String {
+    backup_point_path(&index_path(path, name))
+}
+
+/// Returns the path of the table of the indexes of the table at `path`.
+pub fn indexes_path(path: &str) -> String {
+    format!("{}.indexes", path)
+}
+
+/// An index on some fields of the rows of a table.
+pub struct Index {
+    fields: Vec<String>,
+    table: Table<JSON, i64>,
+    // The first failure to update the index as an observer.
+    error: Option<TableError>,
+    // Whether an update has ever failed, leaving the index behind the
+    // table.
+    failed: bool,
+}
+
+impl Index {
+    /// The paths of the indexed fields.
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Returns the values of the indexed fields of `row`, whose key is
+    /// `key`.
+    pub fn values(&self, key: i64, row: &JSONRow) -> Vec<JSON> {
+        self.fields.iter().map(|path| field(key, row, path)).collect()
+    }
+
+    // The key of the index entry for row.
+    fn entry(&self, key: i64, row: &JSONRow) -> JSON {
+        let mut values = self.values(key, row);
+        values.push(JSON::Int(key));
+        JSON::Array(values)
+    }
+
+    /// Returns the keys of the rows whose indexed fields start with
+    /// `values`, in index order.
+    pub fn get(&self, values: &[JSON]) -> Vec<i64> {
+        self.range(Bound::Included(values), Bound::Included(values))
+    }
+
+    /// Returns the keys of the rows whose indexed fields lie between
+    /// `lo` and `hi`, in index order. A bound with fewer values than
+    /// there are fields bounds only the leading fields.
+    pub fn range(&self, lo: Bound<&[JSON]>, hi: Bound<&[JSON]>) -> Vec<i64> {
+        let start = match lo {
+            Bound::Included(v) | Bound::Excluded(v) => Bound::Included(JSON::Array(v.to_vec())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut keys = Vec::new();
+        for (entry, key) in self.table.range((start, Bound::Unbounded)) {
+            let values = match *entry {
+                JSON::Array(ref v) => &v[..v.len() - 1],
+                _ => continue,
+            };
+            if let Bound::Excluded(v) = lo {
+                if prefix(values, v) == v {
+                    continue;
+                }
+            }
+            let past = match hi {
+                Bound::Included(v) => prefix(values, v) > v,
+                Bound::Excluded(v) => prefix(values, v) >= v,
+                Bound::Unbounded => false,
+            };
+            if past {
+                break;
+            }
+            keys.push(*key);
+        }
+        keys
+    }
+
+    /// Updates the index for the row with key `key` changing from `old`
+    /// to `new`, where None is no row.
+    pub fn apply(&mut self, key: i64, old: Option<&JSONRow>, new: Option<&JSONRow>) -> Result<(), TableError> {
+        let old = old.map(|row| self.entry(key, row));
+        let new = new.map(|row| self.entry(key, row));
+        if old == new {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        if let Some(entry) = old {
+            batch.remove(entry);
+        }
+        if let Some(entry) = new {
+            batch.insert(entry, key);
+        }
+        self.table.write(batch)
+    }
+
+    // Makes the index agree with table, which it may not if a crash
+    // came between writing the two, or if it is new. If the index agreed
+    // with the log of table at point since, only the rows changed after
+    // it are reindexed.
+    fn sync(&mut self, table: &mut Table<i64, JSONRow>, since: Option<BackupPoint>, codecs: &Codecs) ->
+        Result<(), TableError>
+    {
+        let bytes = match since.map(|p| table.appended_since(&p, usize::max_value())) {
+            None | Some(Err(TableError::BackupMismatch)) | Some(Err(TableError::Unsupported(_))) => {
+                return self.rebuild(table);
+            },
+            Some(Err(e)) => return Err(e),
+            Some(Ok((_, bytes))) => bytes,
+        };
+        let mut changed = ChangedKeys(BTreeSet::new());
+        match replay_coded(&mut &bytes[..], &mut DecodeStats::default(), &mut changed, codecs) {
+            Err(de) => return Err(TableError::DecodeError(de)),
+            Ok(()) => (),
+        };
+        let keys = changed.0;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::new();
+        for (entry, key) in &self.table {
+            let key = *key;
+            if !keys.contains(&key) {
+                continue;
+            }
+            if table.get(&key).map(|row| self.entry(key, row)).as_ref() != Some(entry) {
+                batch.remove(entry.clone());
+            }
+        }
+        for key in keys {
+            if let Some(row) = table.get(&key) {
+                let entry = self.entry(key, row);
+                if self.table.get(&entry).is_none() {
+                    batch.insert(entry, key);
+                }
+            }
+        }
+        self.table.write(batch)
+    }
+
+    // Rebuilds the index from the rows of table.
+    fn rebuild(&mut self, table: &Table<i64, JSONRow>) -> Result<(), TableError> {
+        let expected: BTreeMap<JSON, i64> = table.into_iter().map(|(k, row)| (self.entry(*k, row), *k)).collect();
+        let mut batch = WriteBatch::new();
+        for (entry, _) in &self.table {
+            if !expected.contains_key(entry) {
+                batch.remove(entry.clone());
+            }
+        }
+        for (entry, key) in expected {
+            if self.table.get(&entry).is_none() {
+                batch.insert(entry, key);
+            }
+        }
+        self.table.write(batch)
+    }
+
+    // Records at path that the index agrees with the table log at
+    // point, once the index is on disk.
+    fn save_point(&mut self, path: &str, point: &BackupPoint) -> Result<(), TableError> {
+        match self.table.sync() {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        match point.write(path) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Returns, and clears, the first error met while updating the
+    /// index as a `TableObserver`.
+    pub fn take_error(&mut self) -> Option<TableError> {
+        self.error.take()
+    }
+}
+
+// Notes the keys of the records replayed.
+struct ChangedKeys(BTreeSet<i64>);
+
+impl LogMap<i64, JSONRow> for ChangedKeys {
+    fn replay_insert(&mut self, key: i64, _value: JSONRow, _loc: ValueLoc) -> Option<usize> {
+        self.0.insert(key);
+        None
+    }
+
+    fn replay_remove(&mut self, key: &i64) -> Option<usize> {
+        self.0.insert(*key);
+        None
+    }
+}
+
+impl TableObserver for Index {
+    fn update(&mut self, key: i64, old: Option<&JSONRow>, new: Option<&JSONRow>) {
+        // After a failure the index is left as it is until reopened.
+        if self.error.is_none() {
+            if let Err(e) = self.apply(key, old, new) {
+                self.error = Some(e);
+                self.failed = true;
+            }
+        }
+    }
+}
+
+// The value of the field at path, which is `#` for the key of the row.
+fn field(key: i64, row: &JSONRow, path: &str) -> JSON {
+    if path == "#" {
+        return JSON::Int(key);
+    }
+    let mut names = path.split('.');
+    let mut value = match names.next().and_then(|name| row.get(name)) {
+        None => return JSON::Null,
+        Some(v) => v,
+    };
+    for name in names {
+        value = match *value {
+            JSON::Object(ref m) => match m.get(name) {
+                None => return JSON::Null,
+                Some(v) => v,
+            },
+            _ => return JSON::Null,
+        };
+    }
+    value.clone()
+}
+
+// The leading values of values compared against a bound of n of them.
+fn prefix<'a>(values: &'a [JSON], bound: &[JSON]) -> &'a [JSON] {
+    &values[..bound.len().min(values.len())]
+}
+
+/// A table of JSON rows with secondary indexes, which are kept up to
+/// date as rows are inserted and removed.
+pub struct IndexedTable {
+    path: String,
+    table: Table<i64, JSONRow>,
+    // The fields of each index, by name.
+    defs: Table<Vec<u8>, JSON>,
+    indexes: BTreeMap<String, Index>,
+    options: TableOptions,
+}
+
+impl TableOptions {
+    /// Opens the table of JSON rows at `path` along with its indexes,
+    /// which are opened with the same options.
+    pub fn open_indexed(&self, path: &str) -> Result<IndexedTable, TableError> {
+        let mut t = IndexedTable {
+            path: path.to_string(),
+            table: match self.open(path) {
+                Err(e) => return Err(e),
+                Ok(t) => t,
+            },
+            defs: match self.open(&indexes_path(path)) {
+                Err(e) => return Err(e),
+                Ok(t) => t,
+            },
+            indexes: BTreeMap::new(),
+            options: self.clone(),
+        };
+        let defs: Vec<(Vec<u8>, JSON)> = (&t.defs).into_iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        for (name, fields) in defs {
+            let name = match String::from_utf8(name) {
+                Err(_) => continue,
+                Ok(name) => name,
+            };
+            let fields = match fields {
+                JSON::Array(v) => v.into_iter().filter_map(|f| match f {
+                    JSON::String(s) => Some(s),
+                    _ => None,
+                }).collect(),
+                _ => continue,
+            };
+            match t.open_index(&name, fields) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        Ok(t)
+    }
+}
+
+impl IndexedTable {
+    pub fn open(path: &str) -> Result<IndexedTable, TableError> {
+        TableOptions::new().write(true).open_indexed(path)
+    }
+
+    // Opens and syncs the index name on fields.
+    fn open_index(&mut self, name: &str, fields: Vec<String>) -> Result<(), TableError> {
+        let mut index = Index {
+            fields,
+            table: match self.options.open(&index_path(&self.path, name)) {
+                Err(e) => return Err(e),
+                Ok(t) => t,
+            },
+            error: None,
+            failed: false,
+        };
+        if self.options.write {
+            let point_path = index_point_path(&self.path, name);
+            let since = BackupPoint::read(&point_path).ok();
+            match index.sync(&mut self.table, since, &self.options.codecs) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+            let point = match self.table.log_point() {
+                Err(e) => return Err(e),
+                Ok(p) => p,
+            };
+            match index.save_point(&point_path, &point) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        self.indexes.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    // Records the point of the table log each index agrees with. The
+    // point of an index which failed to be updated is removed instead,
+    // so that it is rebuilt when next opened.
+    fn save_points(&mut self) -> Result<(), TableError> {
+        let point = match self.table.log_point() {
+            Err(e) => return Err(e),
+            Ok(p) => p,
+        };
+        for (name, index) in self.indexes.iter_mut() {
+            let point_path = index_point_path(&self.path, name);
+            if index.failed {
+                let _ = remove_file(&point_path);
+                continue;
+            }
+            match index.save_point(&point_path, &point) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        Ok(())
+    }
+
+    /// The table of rows, which must be written through the
+    /// `IndexedTable` to keep its indexes up to date.
+    pub fn table(&self) -> &Table<i64, JSONRow> {
+        &self.table
+    }
+
+    pub fn get(&self, key: &i64) -> Option<&JSONRow> {
+        self.table.get(key)
+    }
+
+    /// Returns the index called `name`, if there is one.
+    pub fn index(&self, name: &str) -> Option<&Index> {
+        self.indexes.get(name)
+    }
+
+    /// Returns the names of the indexes of the table.
+    pub fn index_names(&self) -> Vec<String> {
+        self.indexes.keys().cloned().collect()
+    }
+
+    /// Declares index `name` on the fields at `fields`, filling it from
+    /// the rows of the table. Declaring an index again on the same
+    /// fields does nothing, but on others fails with
+    /// `TableError::Exists`.
+    pub fn create_index(&mut self, name: &str, fields: &[&str]) -> Result<(), TableError> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
+            return Err(TableError::InvalidName(name.to_string()));
+        }
+        let fields: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+        if let Some(index) = self.indexes.get(name) {
+            if index.fields == fields {
+                return Ok(());
+            }
+            return Err(TableError::Exists(name.to_string()));
+        }
+        let def = JSON::Array(fields.iter().map(|f| JSON::String(f.clone())).collect());
+        match self.defs.insert(name.as_bytes().to_vec(), def) {
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        };
+        // Left by an index of the name dropped before its file was.
+        let _ = remove_file(index_point_path(&self.path, name));
+        self.open_index(name, fields)
+    }
+
+    /// Removes index `name` and its file, returning whether it existed.
+    pub fn drop_index(&mut self, name: &str) -> Result<bool, TableError> {
+        if self.indexes.remove(name).is_none() {
+            return Ok(false);
+        }
+        match self.defs.remove(&name.as_bytes().to_vec()) {
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        };
+        let path = index_path(&self.path, name);
+        let _ = remove_file(hint_path(&path));
+        let _ = remove_file(index_point_path(&self.path, name));
+        match remove_file(&path) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(true),
+        }
+    }
+
+    // Passes a change to every index, returning the first error.
+    fn notify(&mut self, key: i64, old: Option<&JSONRow>) -> Result<(), TableError> {
+        let new = self.table.get(&key);
+        let mut result = Ok(());
+        for index in self.indexes.values_mut() {
+            index.update(key, old, new);
+            if let Some(e) = index.take_error() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    pub fn insert(&mut self, key: i64, row: JSONRow) -> Result<Option<JSONRow>, TableError> {
+        let old = match self.table.insert(key, row) {
+            Err(e) => return Err(e),
+            Ok(old) => old,
+        };
+        match self.notify(key, old.as_ref()) {
+            Err(e) => Err(e),
+            Ok(()) => Ok(old),
+        }
+    }
+
+    /// Inserts `row` under the next key of the table's sequence (see
+    /// `Table::insert_auto`) and returns the key.
+    pub fn insert_auto(&mut self, row: JSONRow) -> Result<i64, TableError> {
+        let key = match self.table.insert_auto(row) {
+            Err(e) => return Err(e),
+            Ok(key) => key,
+        };
+        match self.notify(key, None) {
+            Err(e) => Err(e),
+            Ok(()) => Ok(key),
+        }
+    }
+
+    pub fn remove(&mut self, key: &i64) -> Result<Option<JSONRow>, TableError> {
+        let old = match self.table.remove(key) {
+            Err(e) => return Err(e),
+            Ok(old) => old,
+        };
+        match self.notify(*key, old.as_ref()) {
+            Err(e) => Err(e),
+            Ok(()) => Ok(old),
+        }
+    }
+
+    /// Returns the rows whose fields indexed by `name` start with
+    /// `values`, in index order.
+    pub fn find(&self, name: &str, values: &[JSON]) -> Vec<(i64, &JSONRow)> {
+        self.find_range(name, Bound::Included(values), Bound::Included(values))
+    }
+
+    /// Returns the rows whose fields indexed by `name` lie between `lo`
+    /// and `hi` (see `Index::range`), in index order.
+    pub fn find_range(&self, name: &str, lo: Bound<&[JSON]>, hi: Bound<&[JSON]>) -> Vec<(i64, &JSONRow)> {
+        match self.indexes.get(name) {
+            None => Vec::new(),
+            Some(index) => index.range(lo, hi).into_iter().filter_map(|k| self.table.get(&k).map(|row| (k, row))).collect(),
+        }
+    }
+
+    /// Hands any buffered writes, to the table and its indexes, to the OS.
+    pub fn flush(&mut self) -> Result<(), TableError> {
+        match self.table.flush() {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        for index in self.indexes.values_mut() {
+            match index.table.flush() {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        self.defs.flush()
+    }
+
+    /// Compacts the table and its indexes.
+    pub fn compact(&mut self) -> Result<(), TableError> {
+        match self.compact_all() {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        // The table log has been replaced.
+        self.save_points()
+    }
+
+    fn compact_all(&mut self) -> Result<(), TableError> {
+        let path = self.path.clone();
+        match self.table.compact(&path) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        for (name, index) in self.indexes.iter_mut() {
+            match index.table.compact(&index_path(&path, name)) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        self.defs.compact(&indexes_path(&path))
+    }
+}
+
+impl Drop for IndexedTable {
+    fn drop(&mut self) {
+        if self.options.write {
+            // Should this fail, the indexes are caught up from an
+            // earlier point when next opened.
+            let _ = self.save_points();
+        }
+    }
+}
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_index() {
+    use json::json_decode;
+
+    let path = temp_path("index");
+    let _ = remove_file(indexes_path(&path));
+    let _ = remove_file(index_path(&path, "age"));
+    let _ = remove_file(index_path(&path, "city"));
+    let row = |s: &str| match json_decode(s) {
+        Ok(JSON::Object(m)) => m,
+        _ => panic!("expected an object"),
+    };
+    let keys = |rows: Vec<(i64, &JSONRow)>| -> Vec<i64> { rows.into_iter().map(|(k, _)| k).collect() };
+    let int = |n| [JSON::Int(n)];
+    let city = |s: &str| [JSON::String(s.to_string())];
+    {
+        let mut t = IndexedTable::open(&path).unwrap();
+        t.insert(1, row(r#"{"name": "ann", "age": 40, "home": {"city": "Perth"}}"#)).unwrap();
+        t.insert(2, row(r#"{"name": "bob", "age": 25.5, "home": {"city": "Hobart"}}"#)).unwrap();
+        t.create_index("age", &["age"]).unwrap();
+        t.create_index("city", &["home.city", "age"]).unwrap();
+        t.insert(3, row(r#"{"name": "cat", "age": 33, "home": {"city": "Perth"}}"#)).unwrap();
+        t.insert(4, row(r#"{"name": "dan"}"#)).unwrap();
+        t.insert(2, row(r#"{"name": "bob", "age": 26, "home": {"city": "Hobart"}}"#)).unwrap();
+        t.remove(&1).unwrap();
+
+        // A missing field is indexed as null, which sorts first.
+        assert_eq!(t.index("age").unwrap().range(Bound::Unbounded, Bound::Unbounded), vec![4, 2, 3]);
+        assert_eq!(keys(t.find("age", &int(26))), vec![2]);
+        assert_eq!(keys(t.find("age", &int(40))), vec![]);
+        // Integers and floats compare as numbers.
+        let lo = [JSON::Float(25.5)];
+        assert_eq!(keys(t.find_range("age", Bound::Excluded(&lo), Bound::Included(&int(33)))), vec![2, 3]);
+        assert_eq!(keys(t.find_range("age", Bound::Included(&int(0)), Bound::Excluded(&int(33)))), vec![2]);
+        assert_eq!(keys(t.find("city", &city("Perth"))), vec![3]);
+        assert_eq!(keys(t.find("city", &[JSON::String("Hobart".to_string()), JSON::Int(26)])), vec![2]);
+
+        assert!(t.create_index("age", &["age"]).is_ok());
+        match t.create_index("age", &["name"]) {
+            Err(TableError::Exists(_)) => (),
+            _ => panic!("expected the index to exist"),
+        };
+        match t.create_index("../age", &["name"]) {
+            Err(TableError::InvalidName(_)) => (),
+            _ => panic!("expected an invalid name"),
+        };
+    }
+    // The entries of removed rows are not read back from the index.
+    {
+        let index: Table<JSON, i64> = Table::open(&index_path(&path, "age")).unwrap();
+        let entries: Vec<i64> = (&index).into_iter().map(|(_, key)| *key).collect();
+        assert_eq!(entries, vec![4, 2, 3]);
+    }
+    // Indexes persist, and are brought up to date from the point they
+    // agreed with the table when opened.
+    let logged = |path: &str| ::std::fs::metadata(path).unwrap().len();
+    assert_eq!(BackupPoint::read(&index_point_path(&path, "age")).unwrap().offset, logged(&path));
+    {
+        let mut rows: Table<i64, JSONRow> = Table::open_rw(&path).unwrap();
+        rows.insert(5, row(r#"{"name": "eve", "age": 33, "home": {"city": "Perth"}}"#)).unwrap();
+        rows.remove(&4).unwrap();
+        rows.insert(2, row(r#"{"name": "bob", "age": 27, "home": {"city": "Hobart"}}"#)).unwrap();
+    }
+    let mut t = IndexedTable::open(&path).unwrap();
+    assert_eq!(t.index_names(), vec!["age".to_string(), "city".to_string()]);
+    assert_eq!(keys(t.find("age", &int(33))), vec![3, 5]);
+    assert_eq!(keys(t.find("age", &int(26))), vec![]);
+    assert_eq!(t.index("age").unwrap().range(Bound::Unbounded, Bound::Unbounded), vec![2, 3, 5]);
+    assert_eq!(keys(t.find("city", &city("Perth"))), vec![3, 5]);
+    assert_eq!(BackupPoint::read(&index_point_path(&path, "city")).unwrap().offset, logged(&path));
+    t.compact().unwrap();
+    assert_eq!(BackupPoint::read(&index_point_path(&path, "age")).unwrap().offset, logged(&path));
+    t.insert(6, row(r#"{"name": "fay", "age": 26}"#)).unwrap();
+    drop(t);
+    // Without its point an index is rebuilt.
+    remove_file(index_point_path(&path, "age")).unwrap();
+    let mut t = IndexedTable::open(&path).unwrap();
+    assert_eq!(keys(t.find("age", &int(26))), vec![6]);
+    assert_eq!(t.index("age").unwrap().range(Bound::Unbounded, Bound::Unbounded), vec![6, 2, 3, 5]);
+    assert!(t.drop_index("city").unwrap());
+    assert!(!t.drop_index("city").unwrap());
+    drop(t);
+    let t = IndexedTable::open(&path).unwrap();
+    assert_eq!(t.index_names(), vec!["age".to_string()]);
+    assert_eq!(keys(t.find("age", &int(27))), vec![2]);
+    assert!(BackupPoint::read(&index_point_path(&path, "city")).is_err());
+    drop(t);
+
+    for p in &[path.clone(), indexes_path(&path), index_path(&path, "age")] {
+        remove_table(p);
+    }
+}
@@ -1,8 +1,14 @@
 use std::collections::BTreeMap;
 use std::cmp::Ordering;
+use std::io;
+use std::io::Write;
 
 use util::repr;
 use cmp_fi::*;
+use encode::Encode;
+use decode::{Decode, DecodeError, DecodeStats};
+use f64_conv::{f64_from_bytes, f64_to_bytes};
+use header::TypeDescriptor;
 
 #[derive(Clone,Debug)]
 pub enum JSON {
@@ -233,7 +239,7 @@ fn unescape_str<'a>(s: &'a str) -> String {
     return su;
 }
 
-fn json_object<'a, I>(mut ti: &mut I) -> Result<JSON,JSONParseError>
+fn json_object<'a, I>(ti: &mut I) -> Result<JSON,JSONParseError>
     where I: Iterator<Item=&'a (Token, &'a str)>
 {
     // The assumption on entry is that ti just produced a '{' token.
@@ -311,7 +317,7 @@ fn json_object<'a, I>(mut ti: &mut I) -> Result<JSON,JSONParseError>
     Ok(JSON::Object(m))
 }
 
-fn json_array<'a, I>(mut ti: &mut I) -> Result<JSON,JSONParseError>
+fn json_array<'a, I>(ti: &mut I) -> Result<JSON,JSONParseError>
     where I: Iterator<Item=&'a (Token, &'a str)>
 {
     // The assumption on entry is that ti just produced a '[' token.
@@ -368,7 +374,7 @@ fn json_array<'a, I>(mut ti: &mut I) -> Result<JSON,JSONParseError>
     Ok(JSON::Array(v))
 }
 
-fn json_value<'a, I>(mut ti: &mut I) -> Result<JSON,JSONParseError>
+fn json_value<'a, I>(ti: &mut I) -> Result<JSON,JSONParseError>
     where I: Iterator<Item=&'a (Token, &'a str)>
 {
     match ti.next() {
@@ -456,9 +462,7 @@ impl PartialEq for JSON {
     }
 }
 
-impl Eq for JSON {
-    fn assert_receiver_is_total_eq(&self) {}
-}
+impl Eq for JSON {}
 
 impl PartialOrd for JSON {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
@@ -528,3 +532,210 @@ impl Ord for JSON
     }
 }
 
+
+// Stored JSON values begin with a tag giving their type:
+//
+//     'n' null, 'f' false, 't' true, 'I' infinity,
+//     'i' i64, 'd' f64 (IEEE 754 bytes, big-endian),
+//     'b' bytes, 's' string (as bytes),
+//     'a' count (u64) | values, 'o' object
+//
+// and an object, which is also how a row is stored, is
+//
+//     count (u64) | (name (as bytes) | value)*
+
+impl Encode for JSON {
+    fn encode<T: Write>(&self, out: &mut T) -> io::Result<()> {
+        match *self {
+            JSON::Null => out.write_all(b"n"),
+            JSON::Bool(b) => out.write_all(if b { b"t" } else { b"f" }),
+            JSON::Int(n) => out.write_all(b"i").and_then(|_| n.encode(out)),
+            JSON::Float(x) => out.write_all(b"d").and_then(|_| out.write_all(&f64_to_bytes(x))),
+            JSON::Binary(ref v) => out.write_all(b"b").and_then(|_| v.encode(out)),
+            JSON::String(ref s) => out.write_all(b"s").and_then(|_| s.as_bytes().encode(out)),
+            JSON::Array(ref v) => {
+                match out.write_all(b"a").and_then(|_| (v.len() as u64).encode(out)) {
+                    Err(err) => return Err(err),
+                    Ok(()) => (),
+                };
+                for x in v {
+                    match x.encode(out) {
+                        Err(err) => return Err(err),
+                        Ok(()) => (),
+                    };
+                }
+                Ok(())
+            },
+            JSON::Object(ref m) => out.write_all(b"o").and_then(|_| encode_object(m, out)),
+            JSON::Infinity => out.write_all(b"I"),
+        }
+    }
+
+    fn encode_size(&self) -> usize {
+        1 + match *self {
+            JSON::Null | JSON::Bool(_) | JSON::Infinity => 0,
+            JSON::Int(n) => n.encode_size(),
+            JSON::Float(_) => 8,
+            JSON::Binary(ref v) => v.encode_size(),
+            JSON::String(ref s) => s.as_bytes().encode_size(),
+            JSON::Array(ref v) => v.iter().fold((v.len() as u64).encode_size(), |n, x| n + x.encode_size()),
+            JSON::Object(ref m) => object_size(m),
+        }
+    }
+}
+
+fn encode_object<T: Write>(m: &BTreeMap<String, JSON>, out: &mut T) -> io::Result<()> {
+    match (m.len() as u64).encode(out) {
+        Err(err) => return Err(err),
+        Ok(()) => (),
+    };
+    for (k, v) in m {
+        match k.as_bytes().encode(out).and_then(|_| v.encode(out)) {
+            Err(err) => return Err(err),
+            Ok(()) => (),
+        };
+    }
+    Ok(())
+}
+
+fn object_size(m: &BTreeMap<String, JSON>) -> usize {
+    m.iter().fold((m.len() as u64).encode_size(), |n, (k, v)| n + k.as_bytes().encode_size() + v.encode_size())
+}
+
+// Reads exactly buf.len() bytes.
+fn read_bytes<T: io::Read>(src: &mut T, stats: &mut DecodeStats, buf: &mut [u8]) -> Result<(), DecodeError> {
+    match src.read_exact(buf) {
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(DecodeError::PartialRead),
+        Err(err) => Err(DecodeError::IOError { err }),
+        Ok(()) => {
+            stats.append(buf.len(), 0);
+            Ok(())
+        },
+    }
+}
+
+fn decode_string<T: io::Read>(src: &mut T, stats: &mut DecodeStats) -> Result<String, DecodeError> {
+    match Vec::<u8>::decode_stats(src, stats) {
+        Err(err) => Err(err),
+        Ok(v) => match String::from_utf8(v) {
+            Err(_) => Err(DecodeError::Corrupt),
+            Ok(s) => Ok(s),
+        },
+    }
+}
+
+fn decode_object<T: io::Read>(src: &mut T, stats: &mut DecodeStats) -> Result<BTreeMap<String, JSON>, DecodeError> {
+    let n = match u64::decode_stats(src, stats) {
+        Err(err) => return Err(err),
+        Ok(n) => n,
+    };
+    let mut m = BTreeMap::new();
+    for _ in 0..n {
+        let k = match decode_string(src, stats) {
+            Err(err) => return Err(err),
+            Ok(k) => k,
+        };
+        match JSON::decode_stats(src, stats) {
+            Err(err) => return Err(err),
+            Ok(v) => m.insert(k, v),
+        };
+    }
+    Ok(m)
+}
+
+impl Decode for JSON {
+    fn decode_stats<T: io::Read>(src: &mut T, stats: &mut DecodeStats) ->
+        Result<Self, DecodeError>
+    {
+        let mut tag = [0u8; 1];
+        match src.read(&mut tag) {
+            Ok(nread) => if nread == 0 { return Err(DecodeError::EOF); },
+            Err(err) => return Err(DecodeError::IOError { err }),
+        };
+        stats.append(1, 0);
+        match tag[0] {
+            b'n' => Ok(JSON::Null),
+            b'f' => Ok(JSON::Bool(false)),
+            b't' => Ok(JSON::Bool(true)),
+            b'I' => Ok(JSON::Infinity),
+            b'i' => i64::decode_stats(src, stats).map(JSON::Int),
+            b'd' => {
+                let mut b8 = [0u8; 8];
+                read_bytes(src, stats, &mut b8).map(|_| JSON::Float(f64_from_bytes(&b8)))
+            },
+            b'b' => Vec::<u8>::decode_stats(src, stats).map(JSON::Binary),
+            b's' => decode_string(src, stats).map(JSON::String),
+            b'a' => {
+                let n = match u64::decode_stats(src, stats) {
+                    Err(err) => return Err(err),
+                    Ok(n) => n,
+                };
+                let mut v = Vec::new();
+                for _ in 0..n {
+                    match JSON::decode_stats(src, stats) {
+                        Err(err) => return Err(err),
+                        Ok(x) => v.push(x),
+                    };
+                }
+                Ok(JSON::Array(v))
+            },
+            b'o' => decode_object(src, stats).map(JSON::Object),
+            // The null marker standing for a removed value.
+            0xFF => Err(DecodeError::Null),
+            _ => Err(DecodeError::Corrupt),
+        }
+    }
+}
+
+impl TypeDescriptor for JSON {
+    fn type_descriptor() -> String { String::from("json") }
+}
+
+// A row of a table, as in `mem_table::JSONRow`.
+
+impl Encode for BTreeMap<String, JSON> {
+    fn encode<T: Write>(&self, out: &mut T) -> io::Result<()> {
+        encode_object(self, out)
+    }
+
+    fn encode_size(&self) -> usize {
+        object_size(self)
+    }
+}
+
+impl Decode for BTreeMap<String, JSON> {
+    fn decode_stats<T: io::Read>(src: &mut T, stats: &mut DecodeStats) ->
+        Result<Self, DecodeError>
+    {
+        decode_object(src, stats)
+    }
+}
+
+impl TypeDescriptor for BTreeMap<String, JSON> {
+    fn type_descriptor() -> String { String::from("json-row") }
+}
+
+#[test]
+fn test_json_encoding() {
+    use encode::encode;
+
+    let row = match json_decode(r#"{"a": [1, 2.5, "x", null, true], "b": {"c": -70000}, "d": infinity}"#) {
+        Ok(JSON::Object(m)) => m,
+        _ => panic!("expected an object"),
+    };
+    let data = encode(&row);
+    assert_eq!(data.len(), row.encode_size());
+    assert_eq!(BTreeMap::<String, JSON>::decode(&mut &data[..]).unwrap(), row);
+    let value = JSON::Object(row);
+    let data = encode(&value);
+    assert_eq!(data.len(), value.encode_size());
+    assert_eq!(JSON::decode(&mut &data[..]).unwrap(), value);
+    match JSON::decode(&mut &data[..data.len() - 1]) {
+        Err(DecodeError::EOF) => (),
+        r => panic!("expected the end of the data, got {:?}", r),
+    };
+    match JSON::decode(&mut &[0xFFu8][..]) {
+        Err(DecodeError::Null) => (),
+        r => panic!("expected a removal, got {:?}", r),
+    };
+}
@@ -0,0 +1,1018 @@
+//! A table which keeps only the locations of its values in memory.
+//!
+//! A `LazyTable` reads the same logs and checkpoints as a `Table` and
+//! supports the same writes, but reads each value from its file when
+//! it is asked for, sharing it as an `Arc` with a cache of those most
+//! recently used. A table opened from a log which continues from a
+//! checkpoint reads the values written before it from the checkpoint
+//! file, until compaction copies them to the log.
+
+use std::fs::{File, OpenOptions, rename};
+use std::io;
+use std::collections::BTreeMap;
+use std::collections::btree_map::{Iter, Range};
+use std::iter::Rev;
+use std::ops::{Bound, RangeBounds};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use checkpoint::{checkpoint_path, write_checkpoint_body};
+use codec::Codecs;
+use compress::decompressed_len;
+use encode::{Encode, Control, frame, frame_compressed, frame_control, frame_record};
+use expiry::{Expiries, expiry_after, now_millis};
+use decode::*;
+use header::TypeDescriptor;
+use lock::{LockKind, lock};
+use log::LogWriter;
+use lru::LruCache;
+use table::{TableError, TableOptions, WriteBatch, next_checkpoint, write_hint};
+use table::Durability;
+
+// The in-memory map of a LazyTable as replayed from its log and any
+// checkpoint it continues from: the file each key's value lies in, as
+// an index into files, and where it lies there; when values written
+// with a time to live expire; the table's sequence (see
+// `Table::insert_auto`); and the paths of the files replayed, the last
+// being the one being replayed.
+struct Locations<K> {
+    map: BTreeMap<K, (usize, ValueLoc)>,
+    expiries: Expiries<K>,
+    sequence: u64,
+    files: Vec<String>,
+}
+
+impl<K: Encode + Ord + Clone> Locations<K> {
+    fn new() -> Locations<K> {
+        Locations { map: BTreeMap::new(), expiries: Expiries::new(), sequence: 0, files: Vec::new() }
+    }
+
+    // Records that the value of key lies at loc in the file being
+    // replayed, returning the size of the record holding any value it
+    // replaces there.
+    fn set(&mut self, key: K, loc: ValueLoc) -> Option<usize> {
+        let file = self.files.len().saturating_sub(1);
+        if loc.expires.is_some() || self.expiries.get(&key).is_some() {
+            self.expiries.set(&key, loc.expires);
+        }
+        let keysize = key.encode_size();
+        match self.map.insert(key, (file, loc)) {
+            Some((f, old)) if f == file => Some(old.record_size(keysize)),
+            _ => None,
+        }
+    }
+}
+
+impl<K: Encode + Ord + Clone, V> LogMap<K, V> for Locations<K> {
+    fn replay_insert(&mut self, key: K, _value: V, loc: ValueLoc) -> Option<usize> {
+        self.set(key, loc)
+    }
+
+    fn replay_remove(&mut self, key: &K) -> Option<usize> {
+        let file = self.files.len().saturating_sub(1);
+        if self.expiries.get(key).is_some() {
+            self.expiries.set(key, None);
+        }
+        match self.map.remove(key) {
+            Some((f, old)) if f == file => Some(old.record_size(key.encode_size())),
+            _ => None,
+        }
+    }
+
+    fn replay_sequence(&mut self, next: u64) {
+        self.sequence = self.sequence.max(next);
+    }
+
+    fn replay_file(&mut self, path: &str) {
+        self.files.push(path.to_string());
+    }
+
+    // Values are read when asked for, so are not read from the log.
+    fn hint_insert(&mut self, key: K, loc: ValueLoc, _log: &File, _codecs: &Codecs) ->
+        Result<Option<usize>, DecodeError>
+        where V: Decode
+    {
+        Ok(self.set(key, loc))
+    }
+}
+
+// Whether the value at loc has expired at time now.
+fn expired(loc: &ValueLoc, now: u64) -> bool {
+    match loc.expires {
+        Some(t) => t <= now,
+        None => false,
+    }
+}
+
+/// An iterator over the keys of a `LazyTable` which have not expired,
+/// in order.
+pub struct Keys<'a, K: 'a> {
+    inner: Iter<'a, K, (usize, ValueLoc)>,
+    now: u64,
+}
+
+impl<'a, K> Iterator for Keys<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        loop {
+            match self.inner.next() {
+                Some((_, &(_, ref loc))) if expired(loc, self.now) => (),
+                item => return item.map(|(k, _)| k),
+            }
+        }
+    }
+}
+
+impl<'a, K> DoubleEndedIterator for Keys<'a, K> {
+    fn next_back(&mut self) -> Option<&'a K> {
+        loop {
+            match self.inner.next_back() {
+                Some((_, &(_, ref loc))) if expired(loc, self.now) => (),
+                item => return item.map(|(k, _)| k),
+            }
+        }
+    }
+}
+
+/// An iterator over the entries of a `LazyTable` which have not
+/// expired, in key order, which reads each value as it is reached.
+pub struct Entries<'a, K: 'a, V: 'a> {
+    table: &'a LazyTable<K, V>,
+    inner: Range<'a, K, (usize, ValueLoc)>,
+    now: u64,
+}
+
+impl<'a, K, V> Iterator for Entries<'a, K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    type Item = Result<(&'a K, Arc<V>), TableError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next() {
+                None => return None,
+                Some((_, &(_, ref loc))) if expired(loc, self.now) => (),
+                Some((key, &(file, loc))) => return Some(self.table.read_value(file, loc).map(|v| (key, v))),
+            }
+        }
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Entries<'a, K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.inner.next_back() {
+                None => return None,
+                Some((_, &(_, ref loc))) if expired(loc, self.now) => (),
+                Some((key, &(file, loc))) => return Some(self.table.read_value(file, loc).map(|v| (key, v))),
+            }
+        }
+    }
+}
+
+/// A persistent map like `Table` which holds only the keys in memory,
+/// along with where each value lies in the log or checkpoint. Values
+/// are read from the file when asked for and the most recently used are
+/// cached (see `TableOptions::cache_size`), so they can be read through
+/// a shared reference.
+pub struct LazyTable<K, V> {
+    path: String,
+    file: Option<LogWriter>,
+    // Positional reads of values go through these handles, one for
+    // each file values lie in. The last is the log, whose handle also
+    // holds the lock of a read-only table.
+    readers: Vec<File>,
+    // The file each value lies in, by its index in readers, and where.
+    map: BTreeMap<K, (usize, ValueLoc)>,
+    expiries: Expiries<K>,
+    sequence: u64,
+    stats: DecodeStats,
+    options: TableOptions,
+    // Values by the file and offset they were read from.
+    cache: Mutex<LruCache<(usize, u64), Arc<V>>>,
+}
+
+impl TableOptions {
+    /// Opens the table at `path` as a `LazyTable`. Segmented tables
+    /// cannot be opened lazily.
+    pub fn open_lazy<K, V>(&self, path: &str) -> Result<LazyTable<K, V>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        if self.segment_size.is_some() {
+            return Err(TableError::Unsupported("opening a segmented table lazily"));
+        }
+        let mut m = Locations::new();
+        let mut stats = DecodeStats::default();
+        let (f, has_header) = match self.open_log::<K, V, _>(path, &mut m, &mut stats) {
+            Err(e) => return Err(e),
+            Ok(r) => r,
+        };
+        // The log is replayed last, after any checkpoint.
+        let mut readers = Vec::with_capacity(m.files.len());
+        for p in m.files.iter().take(m.files.len().saturating_sub(1)) {
+            match File::open(p) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(r) => readers.push(r),
+            };
+        }
+        let file = if self.write {
+            match f.try_clone() {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(w) => Some(LogWriter::new(w, self.buffer_size, self.durability)),
+            }
+        } else {
+            None
+        };
+        readers.push(f);
+        let mut t = LazyTable {
+            path: path.to_string(),
+            file,
+            readers,
+            map: m.map,
+            expiries: m.expiries,
+            sequence: m.sequence,
+            stats,
+            options: self.clone(),
+            cache: Mutex::new(LruCache::new(self.cache_size)),
+        };
+        if self.write && !has_header {
+            match t.compact() {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        Ok(t)
+    }
+}
+
+impl<K, V> LazyTable<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    pub fn open(path: &str) -> Result<LazyTable<K, V>, TableError> {
+        TableOptions::new().open_lazy(path)
+    }
+
+    pub fn open_rw(path: &str) -> Result<LazyTable<K, V>, TableError> {
+        TableOptions::new().write(true).open_lazy(path)
+    }
+
+    /// Returns the statistics gathered when the table log was read,
+    /// kept up to date as the table is written and compacted.
+    pub fn stats(&self) -> &DecodeStats {
+        &self.stats
+    }
+
+    /// Counts the keys which have not expired.
+    pub fn len(&self) -> usize {
+        self.keys().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys().next().is_none()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        match self.map.get(key) {
+            Some(&(_, ref loc)) => !expired(loc, now_millis()),
+            None => false,
+        }
+    }
+
+    /// Returns the keys of the table in order.
+    pub fn keys(&self) -> Keys<'_, K> {
+        Keys { inner: self.map.iter(), now: now_millis() }
+    }
+
+    /// Hands any buffered writes to the OS.
+    pub fn flush(&mut self) -> Result<(), TableError> {
+        match self.file {
+            None => Err(TableError::NotWritable),
+            Some(ref mut w) => match w.flush() {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(()) => Ok(()),
+            },
+        }
+    }
+
+    /// Flushes buffered writes and waits until they are on disk.
+    pub fn sync(&mut self) -> Result<(), TableError> {
+        match self.file {
+            None => Err(TableError::NotWritable),
+            Some(ref mut w) => match w.sync() {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(()) => Ok(()),
+            },
+        }
+    }
+
+    /// Returns the number of milliseconds until writes held back by
+    /// `Durability::GroupCommit` are due to be synced, as
+    /// `Table::sync_due` does.
+    pub fn sync_due(&self) -> i32 {
+        match self.file {
+            None => -1,
+            Some(ref w) => w.sync_due(),
+        }
+    }
+
+    /// Syncs the writes held back by `Durability::GroupCommit` if they
+    /// are due, returning whether it did.
+    pub fn sync_pending(&mut self) -> Result<bool, TableError> {
+        match self.file {
+            None => Ok(false),
+            Some(ref mut w) => match w.sync_pending() {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(synced) => Ok(synced),
+            },
+        }
+    }
+
+    // The index in readers of the log.
+    fn log(&self) -> usize {
+        self.readers.len() - 1
+    }
+
+    // A panic while the cache is locked can at worst lose entries, so
+    // it is used regardless.
+    fn cache(&self) -> MutexGuard<'_, LruCache<(usize, u64), Arc<V>>> {
+        match self.cache.lock() {
+            Err(poisoned) => poisoned.into_inner(),
+            Ok(cache) => cache,
+        }
+    }
+
+    // Reads the encoded value at loc in file, taking the part of it
+    // still in the write buffer, if any, from there.
+    fn read_raw(&self, file: usize, loc: ValueLoc) -> Result<Vec<u8>, TableError> {
+        let mut buf = vec![0u8; loc.len];
+        let start = loc.offset as usize;
+        let mut on_disk = loc.len;
+        if let (true, Some(w)) = (file == self.log(), self.file.as_ref()) {
+            let pending = w.buffer();
+            let flushed = self.stats.valid() - pending.len();
+            if start + loc.len > flushed {
+                on_disk = flushed.saturating_sub(start);
+                let from = start + on_disk - flushed;
+                buf[on_disk..].copy_from_slice(&pending[from..from + loc.len - on_disk]);
+            }
+        }
+        match self.readers[file].read_exact_at(&mut buf[..on_disk], loc.offset) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(buf),
+        }
+    }
+
+    // Returns the value at loc in file, reading it unless it is in the
+    // cache.
+    fn read_value(&self, file: usize, loc: ValueLoc) -> Result<Arc<V>, TableError> {
+        if let Some(value) = self.cache().get(&(file, loc.offset)) {
+            return Ok(value.clone());
+        }
+        let buf = match self.read_raw(file, loc) {
+            Err(e) => return Err(e),
+            Ok(buf) => buf,
+        };
+        let value: Arc<V> = match decode_value(&buf, loc.compressed, &self.options.codecs) {
+            Err(de) => return Err(TableError::DecodeError(de)),
+            Ok(v) => Arc::new(v),
+        };
+        self.cache().insert((file, loc.offset), value.clone());
+        Ok(value)
+    }
+
+    /// Returns the value for `key`, reading it from its file unless it
+    /// is in the cache.
+    pub fn get(&self, key: &K) -> Result<Option<Arc<V>>, TableError> {
+        match self.map.get(key) {
+            None => Ok(None),
+            Some(&(_, ref loc)) if expired(loc, now_millis()) => Ok(None),
+            Some(&(file, loc)) => match self.read_value(file, loc) {
+                Err(e) => Err(e),
+                Ok(value) => Ok(Some(value)),
+            },
+        }
+    }
+
+    /// Returns the entry with the smallest key, if any.
+    pub fn first(&self) -> Result<Option<(&K, Arc<V>)>, TableError> {
+        self.iter().next().transpose()
+    }
+
+    /// Returns the entry with the largest key, if any.
+    pub fn last(&self) -> Result<Option<(&K, Arc<V>)>, TableError> {
+        self.iter().next_back().transpose()
+    }
+
+    /// Iterates in key order over the entries of the table.
+    pub fn iter(&self) -> Entries<'_, K, V> {
+        self.range(..)
+    }
+
+    /// Iterates in key order over the entries with keys in `range`.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Entries<'_, K, V> {
+        Entries { table: self, inner: self.map.range(range), now: now_millis() }
+    }
+
+    /// Iterates in reverse key order over the entries with keys in `range`.
+    pub fn range_rev<R: RangeBounds<K>>(&self, range: R) -> Rev<Entries<'_, K, V>> {
+        self.range(range).rev()
+    }
+
+    /// Iterates in key order over the entries from `key` onwards.
+    pub fn iter_from(&self, key: &K) -> Entries<'_, K, V> {
+        self.range((Bound::Included(key), Bound::Unbounded))
+    }
+
+    /// Returns when the next key to expire does, if any key has a time
+    /// to live.
+    pub fn next_expiry(&self) -> Option<SystemTime> {
+        self.expiries.next().map(|t| UNIX_EPOCH + Duration::from_millis(t))
+    }
+
+    /// Returns the number of milliseconds until `sweep_expired` has
+    /// keys to remove, as `Table::sweep_due` does.
+    pub fn sweep_due(&self) -> i32 {
+        self.expiries.due()
+    }
+
+    /// Removes the keys which have expired, as a single batch, and
+    /// returns how many there were.
+    pub fn sweep_expired(&mut self) -> Result<usize, TableError> {
+        let keys = self.expiries.expired(now_millis());
+        let n = keys.len();
+        if n == 0 {
+            return Ok(0);
+        }
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.remove(key);
+        }
+        match self.write(batch) {
+            Err(e) => Err(e),
+            Ok(()) => Ok(n),
+        }
+    }
+
+    // The size of the record in the log holding the value of key, if
+    // it is held there.
+    fn logged_size(&self, key: &K) -> Option<usize> {
+        match self.map.get(key) {
+            Some(&(file, ref loc)) if file == self.log() => Some(loc.record_size(key.encode_size())),
+            _ => None,
+        }
+    }
+
+    // Appends the records for value, which expires at expires if set,
+    // to buf, returning where in the log the value will lie once buf
+    // has been appended to it.
+    fn frame_value(&self, buf: &mut Vec<u8>, key: &K, value: &V, expires: Option<u64>,
+                   compression: &mut CompressionStats) -> ValueLoc
+    {
+        if let Some(t) = expires {
+            frame_control(buf, Control::Expires(t));
+        }
+        let start = buf.len();
+        let (records, stored) = (compression.records, compression.stored);
+        self.options.frame_record(buf, key, Some(value), compression);
+        let compressed = compression.records > records;
+        let valsize = if compressed {
+            compression.stored - stored
+        } else {
+            // The value ends the payload of the record, which starts
+            // with its length.
+            u64::decode(&mut &buf[start..]).unwrap() as usize - key.encode_size()
+        };
+        let offset = (self.stats.valid() + buf.len() - valsize) as u64;
+        ValueLoc { offset, len: valsize, compressed, expires }
+    }
+
+    // Records that the value of key now lies at loc in the log, or that
+    // it has none, returning whether it had one which had not expired.
+    fn set(&mut self, key: K, loc: Option<ValueLoc>) -> bool {
+        let expires = loc.and_then(|l| l.expires);
+        if expires.is_some() || self.expiries.get(&key).is_some() {
+            self.expiries.set(&key, expires);
+        }
+        let log = self.log();
+        let old = match loc {
+            Some(loc) => self.map.insert(key, (log, loc)),
+            None => self.map.remove(&key),
+        };
+        match old {
+            Some((file, old)) => {
+                self.cache().remove(&(file, old.offset));
+                !expired(&old, now_millis())
+            },
+            None => false,
+        }
+    }
+
+    // Writes buf to the log, accounting for it in stats.
+    fn append(&mut self, buf: &[u8], discarded: usize) -> Result<(), TableError> {
+        match self.file {
+            None => return Err(TableError::NotWritable),
+            Some(ref mut w) => match w.write_all(buf) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            },
+        };
+        self.stats.append(buf.len(), discarded);
+        Ok(())
+    }
+
+    // Applies the durability and compaction policies after a write.
+    fn written(&mut self) -> Result<(), TableError> {
+        match self.file {
+            Some(ref mut w) => match w.committed() {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            },
+            None => (),
+        };
+        let policy = match self.options.auto_compact {
+            None => return Ok(()),
+            Some(policy) => policy,
+        };
+        if self.stats.valid() < policy.min_size || self.stats.garbage_ratio() < policy.ratio {
+            return Ok(());
+        }
+        self.compact()
+    }
+
+    /// Sets the value for `key`, returning whether it replaced another.
+    pub fn insert(&mut self, key: K, value: V) -> Result<bool, TableError> {
+        self.insert_record(key, value, None)
+    }
+
+    /// Sets the value for `key` until `ttl` from now, after which it is
+    /// hidden until it is swept by `sweep_expired` or compaction.
+    pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> Result<bool, TableError> {
+        self.insert_record(key, value, Some(expiry_after(ttl)))
+    }
+
+    // Inserts the value for key, which expires at expires if set.
+    fn insert_record(&mut self, key: K, value: V, expires: Option<u64>) -> Result<bool, TableError> {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut compression = CompressionStats::default();
+        let loc = self.frame_value(&mut buf, &key, &value, expires, &mut compression);
+        let discarded = self.logged_size(&key).unwrap_or(0);
+        match self.append(&buf, discarded) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        self.stats.add_compression(&compression);
+        let replaced = self.set(key, Some(loc));
+        let log = self.log();
+        self.cache().insert((log, loc.offset), Arc::new(value));
+        match self.written() {
+            Err(e) => Err(e),
+            Ok(()) => Ok(replaced),
+        }
+    }
+
+    /// Removes `key`, returning whether it was present.
+    pub fn remove(&mut self, key: &K) -> Result<bool, TableError> {
+        let mut buf: Vec<u8> = Vec::new();
+        frame_record(&mut buf, key, None::<&V>);
+        // The removal is as redundant as what it removes.
+        let discarded = self.logged_size(key).unwrap_or(0) + buf.len();
+        match self.append(&buf, discarded) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        let removed = self.set(key.clone(), None);
+        match self.written() {
+            Err(e) => Err(e),
+            Ok(()) => Ok(removed),
+        }
+    }
+
+    /// Applies all the changes in `batch` as a single atomic write, as
+    /// `Table::write` does.
+    pub fn write(&mut self, batch: WriteBatch<K, V>) -> Result<(), TableError> {
+        if batch.ops.len() == 0 {
+            return Ok(());
+        }
+        match self.file {
+            None => return Err(TableError::NotWritable),
+            Some(_) => (),
+        };
+        let n = batch.ops.len() as u64;
+        let mut buf: Vec<u8> = Vec::new();
+        frame_control(&mut buf, Control::BatchBegin(n));
+        let mut discarded = buf.len();
+        let mut compression = CompressionStats::default();
+        // Where the value of each change will lie.
+        let mut locs = Vec::with_capacity(batch.ops.len());
+        {
+            // Later changes in the batch replace earlier ones, and a
+            // removal leaves no record to be replaced.
+            let mut pending: BTreeMap<&K, Option<usize>> = BTreeMap::new();
+            for &(ref key, ref value) in &batch.ops {
+                let start = buf.len();
+                let loc = match *value {
+                    Some(ref v) => Some(self.frame_value(&mut buf, key, v, None, &mut compression)),
+                    None => {
+                        frame_record(&mut buf, key, None::<&V>);
+                        None
+                    },
+                };
+                let size = buf.len() - start;
+                let old = match pending.get(key) {
+                    Some(s) => *s,
+                    None => self.logged_size(key),
+                };
+                discarded += old.unwrap_or(0) + if value.is_none() { size } else { 0 };
+                pending.insert(key, if value.is_some() { Some(size) } else { None });
+                locs.push(loc);
+            }
+        }
+        let start = buf.len();
+        frame_control(&mut buf, Control::BatchCommit(n));
+        discarded += buf.len() - start;
+        match self.append(&buf, discarded) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        self.stats.add_compression(&compression);
+        for ((key, _), loc) in batch.ops.into_iter().zip(locs) {
+            self.set(key, loc);
+        }
+        self.written()
+    }
+
+    // Writes a copy of each entry which has not expired at time now to
+    // out, which is written stats.read() bytes into its file, adding
+    // the copies to stats. Values are copied without being decoded.
+    // Returns where each value copied lies, in key order, with None for
+    // the entries left out.
+    fn copy_entries<W: io::Write>(&self, out: &mut W, stats: &mut DecodeStats, now: u64) ->
+        Result<Vec<Option<ValueLoc>>, TableError>
+    {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut locs: Vec<Option<ValueLoc>> = Vec::with_capacity(self.map.len());
+        for (key, &(file, loc)) in &self.map {
+            if expired(&loc, now) {
+                locs.push(None);
+                continue;
+            }
+            if let Some(t) = loc.expires {
+                let start = buf.len();
+                frame_control(&mut buf, Control::Expires(t));
+                stats.append(buf.len() - start, 0);
+            }
+            let raw = match self.read_raw(file, loc) {
+                Err(e) => return Err(e),
+                Ok(raw) => raw,
+            };
+            let start = buf.len();
+            if loc.compressed {
+                frame_compressed(&mut buf, key, &raw);
+                match decompressed_len(&raw) {
+                    Err(de) => return Err(TableError::DecodeError(de)),
+                    Ok(n) => stats.append_compressed(n, raw.len()),
+                };
+            } else {
+                let mut payload: Vec<u8> = Vec::new();
+                key.encode(&mut payload).unwrap();
+                payload.extend(raw);
+                frame(&mut buf, &payload);
+            }
+            let offset = stats.read() + buf.len() - start - loc.len;
+            stats.append(buf.len() - start, 0);
+            locs.push(Some(ValueLoc { offset: offset as u64, ..loc }));
+            if buf.len() >= self.options.buffer_size {
+                match out.write_all(&buf) {
+                    Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                    Ok(()) => (),
+                };
+                buf.clear();
+            }
+        }
+        match out.write_all(&buf) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        Ok(locs)
+    }
+
+    // Records that the values of the entries lie in file where
+    // copy_entries put them, dropping those it left out.
+    fn relocate(&mut self, file: usize, locs: Vec<Option<ValueLoc>>) {
+        let mut locs = locs.into_iter();
+        let expiries = &mut self.expiries;
+        self.map.retain(|key, entry| match locs.next() {
+            Some(Some(new)) => {
+                *entry = (file, new);
+                true
+            },
+            _ => {
+                if expiries.get(key).is_some() {
+                    expiries.set(key, None);
+                }
+                false
+            },
+        });
+        self.cache().clear();
+    }
+
+    /// Rewrites the log holding only the current contents of the
+    /// table, leaving out expired keys. Values are copied without being
+    /// decoded.
+    pub fn compact(&mut self) -> Result<(), TableError> {
+        match self.file {
+            None => return Err(TableError::NotWritable),
+            Some(_) => (),
+        };
+        let mut newpath = self.path.clone();
+        newpath.push('~');
+        let f_or_e = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&newpath);
+        let f = match f_or_e {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(f) => f,
+        };
+        match lock(&f, LockKind::Exclusive, Duration::from_secs(0)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(false) => return Err(TableError::Locked),
+            Ok(true) => (),
+        };
+        let reader = match f.try_clone() {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(r) => r,
+        };
+        let mut w = io::BufWriter::with_capacity(self.options.buffer_size, f);
+        let mut stats = DecodeStats::default();
+        let mut buf: Vec<u8> = Vec::new();
+        self.options.header::<K, V>().encode(&mut buf).unwrap();
+        stats.append(buf.len(), 0);
+        match io::Write::write_all(&mut w, &buf) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+
+        // Everything written so far must be readable from the file.
+        match self.flush() {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        let locs = match self.copy_entries(&mut w, &mut stats, now_millis()) {
+            Err(e) => return Err(e),
+            Ok(locs) => locs,
+        };
+        if self.sequence > 0 {
+            buf.clear();
+            frame_control(&mut buf, Control::Sequence(self.sequence));
+            stats.append(buf.len(), buf.len());
+            match io::Write::write_all(&mut w, &buf) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+        }
+        let f = match w.into_inner() {
+            Err(e) => return Err(TableError::IOError(e.into())),
+            Ok(f) => f,
+        };
+        if self.options.durability != Durability::Os {
+            match f.sync_all() {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+        }
+        match rename(newpath, &self.path) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        match write_hint::<K, V>(&self.path, &self.options.codecs) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        self.file = Some(LogWriter::new(f, self.options.buffer_size, self.options.durability));
+        self.readers = vec![reader];
+        self.relocate(0, locs);
+        self.stats = stats;
+        Ok(())
+    }
+
+    /// Writes the table to a new checkpoint and restarts the log from
+    /// it, as `Table::checkpoint` does, leaving out expired keys.
+    /// Values are copied without being decoded. Returns the generation
+    /// of the checkpoint.
+    pub fn checkpoint(&mut self) -> Result<u64, TableError> {
+        match self.file {
+            None => return Err(TableError::NotWritable),
+            Some(_) => (),
+        };
+        let durable = self.options.durability != Durability::Os;
+        match if durable { self.sync() } else { self.flush() } {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        let gen = match next_checkpoint(&self.path) {
+            Err(e) => return Err(e),
+            Ok(gen) => gen,
+        };
+        let mut body: Vec<u8> = Vec::new();
+        let locs = match self.copy_entries(&mut body, &mut DecodeStats::default(), now_millis()) {
+            Err(e) => return Err(e),
+            Ok(locs) => locs,
+        };
+        let start = match write_checkpoint_body(&self.path, gen, &body, durable) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(start) => start as u64,
+        };
+        let reader = match File::open(checkpoint_path(&self.path, gen)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(r) => r,
+        };
+        let (file, stats) = match self.options.restart_log::<K, V>(&self.path, gen, self.sequence) {
+            Err(e) => return Err(e),
+            Ok(r) => r,
+        };
+        let log = match file.get_ref().try_clone() {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(r) => r,
+        };
+        self.file = Some(file);
+        self.readers = vec![reader, log];
+        let locs = locs.into_iter().map(|l| l.map(|loc| ValueLoc { offset: loc.offset + start, ..loc })).collect();
+        self.relocate(0, locs);
+        self.stats = stats;
+        Ok(gen)
+    }
+}
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_lazy_table() {
+    let path = temp_path("lazy");
+    {
+        let mut t: LazyTable<i64, Vec<u8>> = TableOptions::new().write(true).cache_size(2).open_lazy(&path).unwrap();
+        for i in 0..10 {
+            t.insert(i, format!("v{}", i).into_bytes()).unwrap();
+        }
+        assert_eq!(t.insert(3, b"three".to_vec()).unwrap(), true);
+        assert_eq!(t.remove(&4).unwrap(), true);
+        // Values still in the write buffer are readable.
+        assert_eq!(t.get(&1).unwrap().as_deref(), Some(&b"v1".to_vec()));
+        assert_eq!(t.get(&3).unwrap().as_deref(), Some(&b"three".to_vec()));
+        assert_eq!(t.get(&4).unwrap(), None);
+        assert_eq!(t.cache().len(), 2);
+    }
+    {
+        let t: LazyTable<i64, Vec<u8>> = TableOptions::new().cache_size(2).open_lazy(&path).unwrap();
+        assert_eq!(t.len(), 9);
+        assert_eq!(t.cache().len(), 0);
+        for i in 0..10 {
+            let expected = match i {
+                3 => Some(b"three".to_vec()),
+                4 => None,
+                _ => Some(format!("v{}", i).into_bytes()),
+            };
+            assert_eq!(t.get(&i).unwrap().as_deref(), expected.as_ref());
+        }
+        assert_eq!(t.cache().len(), 2);
+    }
+    {
+        let mut t: LazyTable<i64, Vec<u8>> = LazyTable::open_rw(&path).unwrap();
+        t.compact().unwrap();
+        assert_eq!(t.stats().discarded(), 0);
+        assert_eq!(t.get(&3).unwrap().as_deref(), Some(&b"three".to_vec()));
+        t.insert(10, b"v10".to_vec()).unwrap();
+    }
+    // The compacted log reads back the same as a Table.
+    let t: ::table::Table<i64, Vec<u8>> = ::table::Table::open(&path).unwrap();
+    assert_eq!(t.get(&9), Some(&b"v9".to_vec()));
+    assert_eq!(t.get(&10), Some(&b"v10".to_vec()));
+    assert_eq!(t.into_iter().count(), 10);
+    match TableOptions::new().segmented(1 << 20).open_lazy::<i64, Vec<u8>>(&path) {
+        Err(TableError::Unsupported(_)) => (),
+        _ => panic!("expected a segmented table not to open lazily"),
+    };
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_lazy_writes() {
+    use table::AutoCompact;
+
+    let path = temp_path("lazy-writes");
+    let hour = Duration::from_secs(3600);
+    let value = |i: i64| format!("value {}", i).into_bytes();
+    let entries = |t: &LazyTable<i64, Vec<u8>>| -> Vec<(i64, Vec<u8>)> {
+        t.iter().map(|e| e.map(|(k, v)| (*k, (*v).clone()))).collect::<Result<_, _>>().unwrap()
+    };
+    {
+        let mut t: LazyTable<i64, Vec<u8>> = LazyTable::open_rw(&path).unwrap();
+        let mut batch = WriteBatch::new();
+        for i in 0..5 {
+            batch.insert(i, value(i));
+        }
+        batch.remove(2);
+        batch.insert(2, b"two".to_vec());
+        batch.remove(3);
+        t.write(batch).unwrap();
+        assert_eq!(t.get(&2).unwrap().as_deref(), Some(&b"two".to_vec()));
+        assert_eq!(t.get(&3).unwrap(), None);
+
+        t.insert_with_ttl(5, value(5), Duration::from_millis(0)).unwrap();
+        t.insert_with_ttl(6, value(6), hour).unwrap();
+        assert_eq!(t.get(&5).unwrap(), None);
+        assert_eq!(t.sweep_due(), 0);
+
+        // Scans skip expired keys and read values still in the buffer.
+        assert_eq!(entries(&t), vec![(0, value(0)), (1, value(1)), (2, b"two".to_vec()), (4, value(4)),
+                                     (6, value(6))]);
+        let keys: Vec<i64> = t.range_rev(1..5).map(|e| *e.unwrap().0).collect();
+        assert_eq!(keys, vec![4, 2, 1]);
+        assert_eq!(*t.iter_from(&3).next().unwrap().unwrap().0, 4);
+        assert_eq!(t.last().unwrap().map(|(k, _)| *k), Some(6));
+        assert_eq!(t.sweep_expired().unwrap(), 1);
+        assert_eq!(t.sweep_expired().unwrap(), 0);
+    }
+    // The log reads back the same as a Table, expiry included.
+    let expected = {
+        let t: LazyTable<i64, Vec<u8>> = LazyTable::open(&path).unwrap();
+        assert!(t.next_expiry().unwrap() > SystemTime::now());
+        entries(&t)
+    };
+    {
+        let t: ::table::Table<i64, Vec<u8>> = ::table::Table::open(&path).unwrap();
+        let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+        assert_eq!(items, expected);
+    }
+
+    // Writes compact the log as the policy says.
+    {
+        let mut t: LazyTable<i64, Vec<u8>> = TableOptions::new()
+            .write(true)
+            .auto_compact(AutoCompact { ratio: 0.5, min_size: 100, step: 2 })
+            .open_lazy(&path)
+            .unwrap();
+        for i in 0..1000 {
+            t.insert(i % 5, value(i)).unwrap();
+        }
+        assert!(t.stats().read() < 400);
+        assert_eq!(t.get(&4).unwrap().as_deref(), Some(&value(999)));
+        assert!(t.get(&6).unwrap().is_some());
+    }
+    let t: LazyTable<i64, Vec<u8>> = LazyTable::open(&path).unwrap();
+    assert_eq!(t.len(), 6);
+    assert_eq!(t.get(&4).unwrap().as_deref(), Some(&value(999)));
+    remove_table(&path);
+}
+
+#[test]
+fn test_lazy_checkpoints() {
+    use checkpoint::previous_log_path;
+
+    let path = temp_path("lazy-checkpoints");
+    let value = |i: i64| format!("v{}", i).into_bytes();
+    {
+        let mut t: LazyTable<i64, Vec<u8>> = LazyTable::open_rw(&path).unwrap();
+        for i in 0..20 {
+            t.insert(i, value(i)).unwrap();
+        }
+        assert_eq!(t.checkpoint().unwrap(), 1);
+        // Values are read from the checkpoint, or the log once replaced.
+        assert_eq!(t.get(&5).unwrap().as_deref(), Some(&value(5)));
+        t.insert(1, b"one".to_vec()).unwrap();
+        t.remove(&2).unwrap();
+        assert_eq!(t.get(&1).unwrap().as_deref(), Some(&b"one".to_vec()));
+        assert_eq!(t.checkpoint().unwrap(), 2);
+        t.insert(3, b"three".to_vec()).unwrap();
+    }
+    {
+        // Only the records since the last checkpoint are replayed.
+        let t: LazyTable<i64, Vec<u8>> = LazyTable::open(&path).unwrap();
+        assert!(t.stats().read() < 100);
+        assert_eq!(t.len(), 19);
+        assert_eq!(t.get(&1).unwrap().as_deref(), Some(&b"one".to_vec()));
+        assert_eq!(t.get(&2).unwrap(), None);
+        assert_eq!(t.get(&3).unwrap().as_deref(), Some(&b"three".to_vec()));
+    }
+    // A Table opens the checkpoint too, and compacting a lazy table
+    // copies the values from the checkpoint to the log.
+    let expected: Vec<(i64, Vec<u8>)> = {
+        let t: ::table::Table<i64, Vec<u8>> = ::table::Table::open(&path).unwrap();
+        t.into_iter().collect()
+    };
+    {
+        let mut t: LazyTable<i64, Vec<u8>> = LazyTable::open_rw(&path).unwrap();
+        t.compact().unwrap();
+        assert_eq!(t.get(&19).unwrap().as_deref(), Some(&value(19)));
+    }
+    for p in &[previous_log_path(&path), ::checkpoint::checkpoint_path(&path, 1),
+               ::checkpoint::checkpoint_path(&path, 2)] {
+        ::std::fs::remove_file(p).unwrap();
+    }
+    let t: ::table::Table<i64, Vec<u8>> = ::table::Table::open(&path).unwrap();
+    let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+    assert_eq!(items, expected);
+    remove_table(&path);
+}
@@ -1,6 +1,33 @@
+pub mod backup;
+pub mod checkpoint;
+pub mod cmp_fi;
+pub mod codec;
+pub mod compress;
+pub mod crc32;
+pub mod database;
+pub mod diagnostics;
 pub mod encode;
+pub mod epoll;
+pub mod expiry;
+pub mod f64_conv;
+pub mod follow;
+pub mod header;
+pub mod hint;
+pub mod index;
+pub mod json;
+pub mod lock;
+pub mod log;
+pub mod segment;
+pub mod lru;
+pub mod mem_table;
 pub mod decode;
 // pub mod record;
 pub mod demo;
+pub mod lazy_table;
+pub mod reactor;
+pub mod replication;
+pub mod salvage;
 pub mod table;
+#[cfg(test)]
+mod test_util;
 pub mod util;
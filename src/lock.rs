@@ -0,0 +1,46 @@
+//! Advisory whole-file locks using `flock(2)`.
+
+extern crate libc;
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+// How long to sleep between attempts to take a lock held elsewhere.
+const RETRY_MS: u64 = 10;
+
+/// Takes a lock of the given kind on `f`, waiting up to `timeout` for
+/// it if it is held elsewhere. Returns false if it could not be taken.
+///
+/// The lock is released when `f` (and every handle cloned from it)
+/// is closed.
+pub fn lock(f: &File, kind: LockKind, timeout: Duration) -> io::Result<bool> {
+    let op = match kind {
+        LockKind::Shared => libc::LOCK_SH,
+        LockKind::Exclusive => libc::LOCK_EX,
+    } | libc::LOCK_NB;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if unsafe { libc::flock(f.as_raw_fd(), op) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(err);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let pause = deadline - now;
+        thread::sleep(if pause > Duration::from_millis(RETRY_MS) { Duration::from_millis(RETRY_MS) } else { pause });
+    }
+}
@@ -0,0 +1,141 @@
+//! Appending to a table log file under a durability policy.
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// When the writes made to a table reach the disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Every write is flushed and synced to disk before it returns.
+    Sync,
+    /// Writes are buffered and synced together once `records` writes
+    /// are pending or `interval` has passed since the oldest of them.
+    /// The interval is checked when writing; a table left idle relies
+    /// on a scheduler calling `sync_pending` when `sync_due` says.
+    GroupCommit { records: usize, interval: Duration },
+    /// Writes are buffered and handed to the OS when the buffer fills
+    /// or on `flush`; the OS decides when they reach the disk.
+    Os,
+}
+
+/// A buffered writer for a table log.
+///
+/// Buffered writes are flushed, and synced unless the durability is
+/// `Durability::Os`, when the writer is dropped.
+pub struct LogWriter {
+    file: io::BufWriter<File>,
+    durability: Durability,
+    // Writes since the last sync, and when the first of them was made.
+    unsynced: usize,
+    unsynced_since: Option<Instant>,
+}
+
+impl LogWriter {
+    /// Returns a writer appending to `file` at its current position.
+    pub fn new(file: File, buffer_size: usize, durability: Durability) -> LogWriter {
+        LogWriter {
+            file: io::BufWriter::with_capacity(buffer_size, file),
+            durability,
+            unsynced: 0,
+            unsynced_since: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &File {
+        self.file.get_ref()
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Number of bytes written but not yet handed to the OS.
+    pub fn buffered(&self) -> usize {
+        self.file.buffer().len()
+    }
+
+    /// The bytes written but not yet handed to the OS, which follow
+    /// those in the file.
+    pub fn buffer(&self) -> &[u8] {
+        self.file.buffer()
+    }
+
+    /// Appends `buf`, which should hold whole records, to the log. Call
+    /// `committed` once a write is complete.
+    pub fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all(buf)
+    }
+
+    /// Hands any buffered writes to the OS.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Flushes buffered writes and waits until they are on disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        self.unsynced_since = None;
+        Ok(())
+    }
+
+    /// Returns the number of milliseconds until the pending writes are
+    /// due to be synced, 0 if they are, or -1 if there are none, as the
+    /// reactor's `Scheduler::due` does.
+    pub fn sync_due(&self) -> i32 {
+        let interval = match self.durability {
+            Durability::GroupCommit { interval, .. } => interval,
+            _ => return -1,
+        };
+        match self.unsynced_since {
+            None => -1,
+            Some(t) => {
+                let left = interval.checked_sub(t.elapsed()).unwrap_or_default();
+                let ms = left.as_secs() * 1000 + left.subsec_millis() as u64;
+                if ms > i32::max_value() as u64 { i32::max_value() } else { ms as i32 }
+            },
+        }
+    }
+
+    /// Syncs the pending writes if they are due (see `sync_due`),
+    /// returning whether it did.
+    pub fn sync_pending(&mut self) -> io::Result<bool> {
+        if self.sync_due() != 0 {
+            return Ok(false);
+        }
+        self.sync().map(|()| true)
+    }
+
+    /// Applies the durability policy once a write is complete.
+    pub fn committed(&mut self) -> io::Result<()> {
+        match self.durability {
+            Durability::Sync => self.sync(),
+            Durability::GroupCommit { records, interval } => {
+                self.unsynced += 1;
+                let since = match self.unsynced_since {
+                    Some(t) => t,
+                    None => {
+                        let now = Instant::now();
+                        self.unsynced_since = Some(now);
+                        now
+                    },
+                };
+                if self.unsynced >= records || since.elapsed() >= interval {
+                    self.sync()
+                } else {
+                    Ok(())
+                }
+            },
+            Durability::Os => Ok(()),
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        let _ = if self.durability == Durability::Os { self.flush() } else { self.sync() };
+    }
+}
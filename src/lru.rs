@@ -0,0 +1,98 @@
+//! A bounded cache which evicts its least recently used entries.
+
+use std::collections::BTreeMap;
+
+pub struct LruCache<K, V> {
+    capacity: usize,
+    // Each entry with the tick at which it was last used.
+    entries: BTreeMap<K, (V, u64)>,
+    // Keys by the tick at which they were last used.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Ord + Clone, V> LruCache<K, V> {
+    /// Returns an empty cache holding up to `capacity` entries.
+    pub fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity,
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the value for `key`, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.order.remove(&entry.1);
+                self.order.insert(tick, key.clone());
+                entry.1 = tick;
+                Some(&entry.0)
+            },
+            None => None,
+        }
+    }
+
+    /// Adds an entry, evicting the least recently used if the cache is full.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+        if self.capacity == 0 {
+            return;
+        }
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.order.iter().next() {
+                Some((tick, _)) => *tick,
+                None => break,
+            };
+            if let Some(k) = self.order.remove(&oldest) {
+                self.entries.remove(&k);
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        match self.entries.remove(key) {
+            Some((value, tick)) => {
+                self.order.remove(&tick);
+                Some(value)
+            },
+            None => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[test]
+fn test_lru() {
+    let mut c = LruCache::new(2);
+    c.insert(1, "one");
+    c.insert(2, "two");
+    assert_eq!(c.get(&1), Some(&"one"));
+    c.insert(3, "three");
+    // 2 was the least recently used.
+    assert_eq!(c.get(&2), None);
+    assert_eq!(c.get(&1), Some(&"one"));
+    assert_eq!(c.get(&3), Some(&"three"));
+    assert_eq!(c.len(), 2);
+    assert_eq!(c.remove(&1), Some("one"));
+    assert_eq!(c.len(), 1);
+}
@@ -3,16 +3,29 @@ extern crate table;
 use std::env;
 
 use table::demo::encodings_demo;
-use table::table::Table;
+use table::salvage::SalvageReport;
+use table::table::{Table, TableOptions};
 use table::util::repr;
 
+fn print_report(report: &SalvageReport<i64>) {
+    println!("{} bytes, {} records recovered making {} entries", report.length, report.records, report.entries);
+    for d in &report.damage {
+        println!("lost bytes {}..{} keys: {:?}", d.start, d.end, d.keys);
+    }
+    if report.is_clean() {
+        println!("no damage found.");
+    } else {
+        println!("{} bytes lost.", report.lost());
+    }
+}
+
 fn main() {
     let mut args = Vec::new();
     args.extend(env::args());
 
     if args.len() == 2 {
         if args[1] == "items" {
-            let t = match Table::open("foo.bt") {
+            let t: Table<i64, Vec<u8>> = match Table::open("foo.bt") {
                 Err(e) => { println!("error opening table : {:?}", e); return; },
                 Ok(t) => t,
             };
@@ -22,7 +35,7 @@ fn main() {
         } else if args[1] == "demo" {
             encodings_demo();
         } else if args[1] == "compact" {
-            let mut t = match Table::open_rw("foo.bt") {
+            let mut t: Table<i64, Vec<u8>> = match Table::open_rw("foo.bt") {
                 Err(e) => { println!("error opening table : {:?}", e); return; },
                 Ok(t) => t,
             };
@@ -37,7 +50,7 @@ fn main() {
                 Err(e) => { println!("arg 2 must be a number : {:?}", e); return; },
                 Ok(n) => n,
             };
-            let t = match Table::open("foo.bt") {
+            let t: Table<i64, Vec<u8>> = match Table::open("foo.bt") {
                 Err(e) => { println!("error opening table : {:?}", e); return; },
                 Ok(t) => t,
             };
@@ -45,12 +58,17 @@ fn main() {
                 Some(v) => println!("value: {:?}", v),
                 None => println!("no value for key: {}", key),
             };
+        } else if args[1] == "fsck" {
+            match TableOptions::new().check::<i64, Vec<u8>>(&args[2]) {
+                Err(e) => { println!("error checking table : {:?}", e); },
+                Ok(report) => print_report(&report),
+            };
         } else if args[1] == "remove" {
             let key = match args[2].parse::<i64>() {
                 Err(e) => { println!("arg 2 must be a number : {:?}", e); return; },
                 Ok(n) => n,
             };
-            let mut t = match Table::open_rw("foo.bt") {
+            let mut t: Table<i64, Vec<u8>> = match Table::open_rw("foo.bt") {
                 Err(e) => { println!("error opening table : {:?}", e); return; },
                 Ok(t) => t,
             };
@@ -65,7 +83,7 @@ fn main() {
                 Err(e) => { println!("arg 2 must be a number : {:?}", e); return; },
                 Ok(n) => n,
             };
-            let mut t = match Table::open_rw("foo.bt") {
+            let mut t: Table<i64, Vec<u8>> = match Table::open_rw("foo.bt") {
                 Err(e) => { println!("error opening table : {:?}", e); return; },
                 Ok(t) => t,
             };
@@ -73,6 +91,14 @@ fn main() {
                 Err(e) => { println!("error inserting: {:?}", e); },
                 Ok(_) => { println!("table updated."); },
             };
+        } else if args[1] == "salvage" {
+            match TableOptions::new().salvage::<i64, Vec<u8>>(&args[2], &args[3]) {
+                Err(e) => { println!("error salvaging table : {:?}", e); },
+                Ok(report) => {
+                    print_report(&report);
+                    println!("salvaged table written to {}.", args[3]);
+                },
+            };
         }
     }
 }
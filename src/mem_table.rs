@@ -1,5 +1,4 @@
-use std::collections::{BTreeMap, Bound};
-use std::collections::btree_map::Range;
+use std::collections::BTreeMap;
 
 use json::JSON;
 
@@ -11,7 +10,7 @@ pub trait TableObserver {
 
 pub struct MemTable {
     map: BTreeMap<i64,JSONRow>,
-    obs: Box<TableObserver>,
+    obs: Box<dyn TableObserver>,
 }
 
 fn opt_ref<'a, T>(opt: &'a Option<T>) -> Option<&'a T> {
@@ -4,7 +4,9 @@ use std::os::unix::io::{RawFd, AsRawFd};
 use std::collections::BTreeMap;
 use std::io;
 use std::ops::DerefMut;
+use std::sync::Arc;
 
+use diagnostics::{Diagnostics, NoDiagnostics};
 use epoll::*;
 
 // We need a BTreeMap<i64, Box<Task>> of tasks.
@@ -22,6 +24,12 @@ pub trait Task {
 }
 
 pub trait Reader : AsRawFd + Task {
+    // Called once the fd can be written to, while the reactor has been
+    // asked to watch for that (see ReactorAction::WatchWrites). run is
+    // called when it can be read from.
+    fn writable(&mut self) -> Vec<ReactorAction> {
+        Vec::new()
+    }
 }
 
 pub trait Scheduler : Task {
@@ -32,28 +40,34 @@ pub trait Scheduler : Task {
 pub trait Reactor {
     fn run(&mut self);
     fn stop(&mut self);
-    fn add_reader(&mut self, Box<Reader>);
-    fn remove_reader(&mut self, RawFd);
+    fn add_reader(&mut self, reader: Box<dyn Reader>);
+    fn remove_reader(&mut self, fd: RawFd);
+    fn watch_writes(&mut self, fd: RawFd, watch: bool);
 }
 
 pub struct EpollReactor {
-    readers: BTreeMap<RawFd,Box<Reader>>,
+    readers: BTreeMap<RawFd,Box<dyn Reader>>,
     run: bool,
     epoll_fd: RawFd,
-    scheduler: Box<Scheduler>,
+    scheduler: Box<dyn Scheduler>,
+    diagnostics: Arc<dyn Diagnostics>,
 }
 
 pub enum ReactorAction {
-    Add(Box<Reader>),
+    Add(Box<dyn Reader>),
     Remove(RawFd),
+    // Starts (true) or stops (false) watching a reader's fd for being
+    // writable.
+    WatchWrites(RawFd, bool),
     Stop,
 }
 
-fn do_todos(reactor: &mut Reactor, todo: Vec<ReactorAction>) {
+fn do_todos(reactor: &mut dyn Reactor, todo: Vec<ReactorAction>) {
     for action in todo {
         match action {
             ReactorAction::Add(r) => reactor.add_reader(r),
             ReactorAction::Remove(fd) => reactor.remove_reader(fd),
+            ReactorAction::WatchWrites(fd, watch) => reactor.watch_writes(fd, watch),
             ReactorAction::Stop => reactor.stop(),
         }
     }
@@ -68,15 +82,20 @@ impl Reactor for EpollReactor {
 
             match epoll_wait(self.epoll_fd, timeout, 1) {
                 Err(e) => {
-                    println!("unexpected error in epoll_wait() {:?}", e);
+                    self.diagnostics.reactor_error(&e);
                     break;
                 }
                 Ok(events) => {
                     for ev in events {
                         let fd = ev.u64 as RawFd;
                         let opt_r = self.readers.get_mut(&fd);
-                        if let Some(mut reader) = opt_r {
-                            todo.extend(reader.deref_mut().run());
+                        if let Some(reader) = opt_r {
+                            if ev.events & !(libc::EPOLLOUT as u32) != 0 {
+                                todo.extend(reader.deref_mut().run());
+                            }
+                            if ev.events & (libc::EPOLLOUT as u32) != 0 {
+                                todo.extend(reader.deref_mut().writable());
+                            }
                         }
                     }
                     if self.scheduler.due() == 0 {
@@ -95,16 +114,37 @@ impl Reactor for EpollReactor {
         self.run = false;
     }
 
-    fn add_reader(&mut self, reader: Box<Reader>) {
+    fn add_reader(&mut self, reader: Box<dyn Reader>) {
         let fd = reader.as_raw_fd();
-        self.readers.insert(fd, reader);
-
         let mut ev = libc::epoll_event { events: libc::EPOLLIN as u32, u64: fd as u64 };
-        epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut ev).unwrap();
+        // A reader which cannot be watched is dropped.
+        match epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut ev) {
+            Err(e) => self.diagnostics.reactor_error(&e),
+            Ok(()) => {
+                self.readers.insert(fd, reader);
+            },
+        };
     }
 
     fn remove_reader(&mut self, fd: RawFd) {
-        self.readers.remove(&fd);
+        if self.readers.contains_key(&fd) {
+            // Before the reader closes it: were the fd duplicated, it
+            // would stay watched once closed.
+            let mut ev = libc::epoll_event { events: 0, u64: fd as u64 };
+            let _ = epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_DEL, fd, &mut ev);
+            self.readers.remove(&fd);
+        }
+    }
+
+    fn watch_writes(&mut self, fd: RawFd, watch: bool) {
+        if !self.readers.contains_key(&fd) {
+            return;
+        }
+        let events = if watch { libc::EPOLLIN | libc::EPOLLOUT } else { libc::EPOLLIN };
+        let mut ev = libc::epoll_event { events: events as u32, u64: fd as u64 };
+        if let Err(e) = epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut ev) {
+            self.diagnostics.reactor_error(&e);
+        }
     }
 }
 
@@ -130,7 +170,7 @@ impl EpollReactor {
         Self::new_with_scheduler(Box::new(NullScheduler{}))
     }
 
-    pub fn new_with_scheduler(s: Box<Scheduler>) -> io::Result<EpollReactor> {
+    pub fn new_with_scheduler(s: Box<dyn Scheduler>) -> io::Result<EpollReactor> {
         match epoll_create(false) {
             Ok(fd) => Ok(
                 EpollReactor {
@@ -138,11 +178,17 @@ impl EpollReactor {
                     run: true,
                     epoll_fd: fd,
                     scheduler: s,
+                    diagnostics: Arc::new(NoDiagnostics),
                 }
             ),
             Err(e) => Err(e)
         }
     }
+
+    /// Where errors are reported; by default they are ignored.
+    pub fn set_diagnostics(&mut self, diagnostics: Arc<dyn Diagnostics>) {
+        self.diagnostics = diagnostics;
+    }
 }
 
 
@@ -0,0 +1,1008 @@
+//! Replication: shipping a table log from a primary to read replicas.
+//!
+//! A `Primary` holds a table open for writing and serves replicas over
+//! TCP from a `reactor::EpollReactor`. A replica subscribes with the
+//! point it has reached in the primary's log (see `backup::BackupPoint`)
+//! and is caught up with the records appended since or, if the log has
+//! been replaced since by a compaction or checkpoint, or the replica
+//! has nothing yet, with a compacted snapshot of the table. From then on
+//! `Primary::ship` sends it the records appended to the log, which it
+//! applies to its read-only copy of the table and appends to a log of
+//! its own, then reports the point it has applied. Messages are framed
+//! as log records are (see `encode::frame`):
+//!
+//!     frame(kind | dev | ino | offset | bytes)
+//!
+//! where kind is `S` to subscribe from the point, `A` for the point
+//! applied, `R` for records following the point and `T` for a snapshot
+//! reaching it, or the last part of one whose other parts come before it
+//! as `P`.
+//!
+//! The primary never waits to write to a replica: what a connection will
+//! not take yet is queued, and the reactor asked to say when it will take
+//! more. A replica is not shipped more until its queue has been written,
+//! and a replica which is behind is shipped a message of about
+//! `MESSAGE_SIZE` bytes at a time, so that neither the log read for it
+//! nor the queue grows with how far behind it is.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{OpenOptions, metadata, remove_file, rename};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use backup::{BackupPoint, backup_point_path, write_synced};
+use encode::{Encode, frame};
+use decode::*;
+use header::TypeDescriptor;
+use hint::hint_path;
+use reactor::{ReactorAction, Reader, Scheduler, Task};
+use table::{Durability, Snapshot, Table, TableError, TableOptions};
+
+// The point of a replica which has no copy of the table yet.
+const NOWHERE: BackupPoint = BackupPoint { dev: 0, ino: 0, offset: 0 };
+
+/// About the most bytes of records, or of a snapshot, a primary sends a
+/// replica in one message. A record or batch longer than this is sent
+/// whole.
+pub const MESSAGE_SIZE: usize = 1 << 20;
+
+/// A message between a primary and a replica.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// The replica has the primary's log up to the point and asks for
+    /// what follows.
+    Subscribe(BackupPoint),
+    /// The replica has applied the primary's log up to the point.
+    Applied(BackupPoint),
+    /// Records appended to the primary's log after the point.
+    Records(BackupPoint, Vec<u8>),
+    /// The primary's table, as a compacted log, as it was at the point,
+    /// or the last part of it.
+    Snapshot(BackupPoint, Vec<u8>),
+    /// A part of a snapshot which those after it, up to the `Snapshot`
+    /// which ends it, continue.
+    SnapshotPart(BackupPoint, Vec<u8>),
+}
+
+impl Message {
+    /// Appends the message to `buf`, framed.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let (kind, point, bytes) = match *self {
+            Message::Subscribe(ref p) => (b'S', p, &[][..]),
+            Message::Applied(ref p) => (b'A', p, &[][..]),
+            Message::Records(ref p, ref b) => (b'R', p, &b[..]),
+            Message::Snapshot(ref p, ref b) => (b'T', p, &b[..]),
+            Message::SnapshotPart(ref p, ref b) => (b'P', p, &b[..]),
+        };
+        let mut payload = vec![kind];
+        for n in &[point.dev, point.ino, point.offset] {
+            n.encode(&mut payload).unwrap();
+        }
+        payload.extend_from_slice(bytes);
+        frame(buf, &payload);
+    }
+
+    /// Decodes the message at the start of `buf`, returning it and its
+    /// length, or None if `buf` does not hold all of it yet.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>, DecodeError> {
+        let mut src = buf;
+        let mut stats = DecodeStats::default();
+        let payload = match decode_frame(&mut src, &mut stats) {
+            Err(DecodeError::EOF) | Err(DecodeError::PartialRead) => return Ok(None),
+            Err(de) => return Err(de),
+            Ok(payload) => payload,
+        };
+        if payload.is_empty() {
+            return Err(DecodeError::Corrupt);
+        }
+        let mut p = &payload[1..];
+        let mut fields = [0u64; 3];
+        for n in fields.iter_mut() {
+            *n = match u64::decode(&mut p) {
+                Err(de) => return Err(de),
+                Ok(n) => n,
+            };
+        }
+        let point = BackupPoint { dev: fields[0], ino: fields[1], offset: fields[2] };
+        let message = match payload[0] {
+            b'S' => Message::Subscribe(point),
+            b'A' => Message::Applied(point),
+            b'R' => Message::Records(point, p.to_vec()),
+            b'T' => Message::Snapshot(point, p.to_vec()),
+            b'P' => Message::SnapshotPart(point, p.to_vec()),
+            _ => return Err(DecodeError::Corrupt),
+        };
+        Ok(Some((message, stats.read())))
+    }
+}
+
+// Sends message on stream, waiting until it has been written.
+fn send(stream: &mut TcpStream, message: &Message) -> Result<(), TableError> {
+    let mut buf: Vec<u8> = Vec::new();
+    message.encode(&mut buf);
+    match stream.write_all(&buf) {
+        Err(ioerr) => Err(TableError::IOError(ioerr)),
+        Ok(()) => Ok(()),
+    }
+}
+
+// Writes what of buf stream will take without waiting, returning how
+// much that was.
+fn write_available(stream: &mut TcpStream, buf: &[u8]) -> io::Result<usize> {
+    let mut written = 0;
+    while written < buf.len() {
+        match stream.write(&buf[written..]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(written)
+}
+
+// Returns the length of the message at the start of buf, framing
+// included, or None if too little of it has arrived to tell.
+fn message_len(buf: &[u8]) -> Result<Option<usize>, DecodeError> {
+    let mut src = buf;
+    match u64::decode(&mut src) {
+        Err(DecodeError::EOF) | Err(DecodeError::PartialRead) => Ok(None),
+        Err(de) => Err(de),
+        Ok(n) => match (n as usize).checked_add(buf.len() - src.len() + 4) {
+            None => Err(DecodeError::Corrupt),
+            Some(len) => Ok(Some(len)),
+        },
+    }
+}
+
+// What has arrived on a connection of the messages sent on it.
+#[derive(Default)]
+struct Incoming {
+    buf: Vec<u8>,
+    // The length of the message at the start of buf, once known, so that
+    // it is decoded only once it has all arrived.
+    next: Option<usize>,
+}
+
+// Reads what has arrived on stream and returns the messages it
+// completes, or None once the other end has closed the connection.
+fn read_messages(stream: &mut TcpStream, incoming: &mut Incoming) -> Result<Option<Vec<Message>>, TableError> {
+    let mut chunk = [0u8; 65536];
+    match stream.read(&mut chunk) {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Some(Vec::new())),
+        Err(ioerr) => return Err(TableError::IOError(ioerr)),
+        Ok(0) => return Ok(None),
+        Ok(n) => incoming.buf.extend_from_slice(&chunk[..n]),
+    };
+    let mut messages = Vec::new();
+    let mut start = 0;
+    loop {
+        let len = match incoming.next {
+            Some(len) => len,
+            None => match message_len(&incoming.buf[start..]) {
+                Err(de) => return Err(TableError::DecodeError(de)),
+                Ok(None) => break,
+                Ok(Some(len)) => len,
+            },
+        };
+        if incoming.buf.len() - start < len {
+            incoming.next = Some(len);
+            break;
+        }
+        incoming.next = None;
+        match Message::decode(&incoming.buf[start..start + len]) {
+            Ok(Some((message, n))) if n == len => messages.push(message),
+            Err(de) => return Err(TableError::DecodeError(de)),
+            _ => return Err(TableError::DecodeError(DecodeError::Corrupt)),
+        }
+        start += len;
+    }
+    incoming.buf.drain(..start);
+    Ok(Some(messages))
+}
+
+// A snapshot being sent to a replica a part at a time.
+struct Shipping<K, V> {
+    snapshot: Snapshot<K, V>,
+    // The point of the log the snapshot reaches.
+    point: BackupPoint,
+    // The last key sent, once the first part has been.
+    last: Option<K>,
+}
+
+// A replica connected to a primary.
+struct Subscriber<K, V> {
+    // Non-blocking.
+    stream: TcpStream,
+    addr: SocketAddr,
+    // The point the replica has been sent the log up to, once it has
+    // subscribed.
+    sent: Option<BackupPoint>,
+    // The snapshot it is being sent, if one has been begun.
+    shipping: Option<Shipping<K, V>>,
+    // The point it last reported applying.
+    applied: Option<BackupPoint>,
+    // Messages not yet written to it.
+    output: Vec<u8>,
+    // Whether it is behind by more than has been queued for it, to be
+    // sent once the queue has been written.
+    behind: bool,
+    // Whether the reactor has been asked to say when it can be written
+    // to.
+    watched: bool,
+}
+
+/// The primary copy of a table, which ships the records appended to its
+/// log to the replicas connected to it.
+pub struct Primary<K, V> {
+    table: Table<K, V>,
+    // By the descriptor of the connection each is read from.
+    subscribers: BTreeMap<RawFd, Subscriber<K, V>>,
+    // Changes to which connections the reactor watches for being
+    // writable, for the next task run to pass on.
+    actions: Vec<ReactorAction>,
+    // About the most bytes sent in a message.
+    message_size: usize,
+}
+
+impl<K, V> Primary<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    /// Makes `table`, which should be open for writing and not
+    /// segmented, the primary copy.
+    pub fn new(table: Table<K, V>) -> Primary<K, V> {
+        Primary { table, subscribers: BTreeMap::new(), actions: Vec::new(), message_size: MESSAGE_SIZE }
+    }
+
+    pub fn table(&self) -> &Table<K, V> {
+        &self.table
+    }
+
+    /// The table, for writing. Changes reach the replicas when `ship`
+    /// is next called.
+    pub fn table_mut(&mut self) -> &mut Table<K, V> {
+        &mut self.table
+    }
+
+    /// Lists the replicas connected, with the point each last reported
+    /// applying, if any.
+    pub fn replicas(&self) -> Vec<(SocketAddr, Option<BackupPoint>)> {
+        self.subscribers.values().map(|s| (s.addr, s.applied)).collect()
+    }
+
+    /// Sends each subscribed replica the records appended to the table
+    /// log since it was last sent any, or a snapshot if the log has been
+    /// replaced since, unless what it was last sent is still queued. A
+    /// replica which is further behind than a message holds is sent the
+    /// rest as its queue is written. A replica which cannot be sent to is
+    /// disconnected.
+    pub fn ship(&mut self) -> Result<(), TableError> {
+        let fds: Vec<RawFd> = self.subscribers.keys().cloned().collect();
+        for fd in fds {
+            match self.ship_to(fd) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        Ok(())
+    }
+
+    // Returns the next message for a replica which has been sent the log
+    // up to point since, or the snapshot it is being sent, if it is
+    // behind, with the point it has then been sent the log up to and
+    // whether it is still behind.
+    fn catch_up(&mut self, since: &BackupPoint, shipping: &mut Option<Shipping<K, V>>) ->
+        Result<Option<(Message, BackupPoint, bool)>, TableError>
+    {
+        let mut s = match shipping.take() {
+            Some(s) => s,
+            None => {
+                let end = match self.table.log_point() {
+                    Err(e) => return Err(e),
+                    Ok(p) => p,
+                };
+                match self.table.appended_since(since, self.message_size) {
+                    Ok((point, _)) if point.offset == since.offset => return Ok(None),
+                    Ok((point, bytes)) => return Ok(Some((Message::Records(*since, bytes), point, point != end))),
+                    Err(TableError::BackupMismatch) => (),
+                    Err(e) => return Err(e),
+                };
+                Shipping { snapshot: self.table.snapshot(), point: end, last: None }
+            },
+        };
+        let mut bytes = Vec::new();
+        match self.table.compacted_part(&s.snapshot, s.last.as_ref(), self.message_size, &mut bytes) {
+            None => {
+                // Records appended since it was taken follow it.
+                let behind = match self.table.log_point() {
+                    Err(_) => true,
+                    Ok(end) => end != s.point,
+                };
+                Ok(Some((Message::Snapshot(s.point, bytes), s.point, behind)))
+            },
+            Some(last) => {
+                let message = Message::SnapshotPart(s.point, bytes);
+                s.last = Some(last);
+                *shipping = Some(s);
+                Ok(Some((message, *since, true)))
+            },
+        }
+    }
+
+    // Ships the log to the replica connected as fd.
+    fn ship_to(&mut self, fd: RawFd) -> Result<(), TableError> {
+        let (since, mut shipping) = match self.subscribers.get_mut(&fd) {
+            Some(sub) if sub.output.is_empty() => match sub.sent {
+                None => return Ok(()),
+                Some(p) => (p, sub.shipping.take()),
+            },
+            _ => return Ok(()),
+        };
+        let shipped = self.catch_up(&since, &mut shipping);
+        if let Some(sub) = self.subscribers.get_mut(&fd) {
+            sub.shipping = shipping;
+            sub.behind = false;
+            match shipped {
+                Err(e) => return Err(e),
+                Ok(None) => (),
+                Ok(Some((message, point, behind))) => {
+                    message.encode(&mut sub.output);
+                    sub.sent = Some(point);
+                    sub.behind = behind;
+                },
+            };
+        }
+        self.flush(fd);
+        Ok(())
+    }
+
+    // Writes what of the output queued for the replica connected as fd
+    // its connection will take, and has the reactor watch for it taking
+    // the rest, or more if the replica is behind. A replica which cannot
+    // be written to is disconnected.
+    fn flush(&mut self, fd: RawFd) {
+        let failed = match self.subscribers.get_mut(&fd) {
+            None => return,
+            Some(sub) => match write_available(&mut sub.stream, &sub.output) {
+                Err(_) => {
+                    // So that its reader sees the connection close.
+                    let _ = sub.stream.shutdown(Shutdown::Both);
+                    true
+                },
+                Ok(n) => {
+                    sub.output.drain(..n);
+                    let pending = !sub.output.is_empty() || sub.behind;
+                    if pending != sub.watched {
+                        sub.watched = pending;
+                        self.actions.push(ReactorAction::WatchWrites(fd, pending));
+                    }
+                    false
+                },
+            },
+        };
+        if failed {
+            self.subscribers.remove(&fd);
+        }
+    }
+
+    // Takes the actions for the reactor queued since last called.
+    fn take_actions(&mut self) -> Vec<ReactorAction> {
+        mem::replace(&mut self.actions, Vec::new())
+    }
+
+    // Handles a message from the replica connected as fd.
+    fn receive(&mut self, fd: RawFd, message: Message) -> Result<(), TableError> {
+        match (message, self.subscribers.get_mut(&fd)) {
+            (_, None) => Ok(()),
+            (Message::Subscribe(point), Some(sub)) => {
+                sub.sent = Some(point);
+                sub.shipping = None;
+                self.ship_to(fd)
+            },
+            (Message::Applied(point), Some(sub)) => {
+                sub.applied = Some(point);
+                Ok(())
+            },
+            _ => Err(TableError::DecodeError(DecodeError::Corrupt)),
+        }
+    }
+}
+
+/// Accepts connections from replicas to a primary, as a reactor `Reader`.
+pub struct PrimaryListener<K, V> {
+    listener: TcpListener,
+    primary: Rc<RefCell<Primary<K, V>>>,
+}
+
+impl<K, V> PrimaryListener<K, V> {
+    pub fn new(listener: TcpListener, primary: &Rc<RefCell<Primary<K, V>>>) -> PrimaryListener<K, V> {
+        PrimaryListener { listener, primary: primary.clone() }
+    }
+}
+
+impl<K, V> AsRawFd for PrimaryListener<K, V> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl<K, V> Task for PrimaryListener<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone + 'static,
+          V: Encode + Decode + TypeDescriptor + 'static
+{
+    fn run(&mut self) -> Vec<ReactorAction> {
+        let accepted = self.listener.accept().and_then(|(stream, addr)| {
+            stream.set_nonblocking(true)?;
+            let writer = stream.try_clone()?;
+            Ok((stream, writer, addr))
+        });
+        match accepted {
+            Err(_) => vec![ReactorAction::Remove(self.listener.as_raw_fd())],
+            Ok((stream, writer, addr)) => {
+                let sub = Subscriber {
+                    stream: writer,
+                    addr,
+                    sent: None,
+                    shipping: None,
+                    applied: None,
+                    output: Vec::new(),
+                    behind: false,
+                    watched: false,
+                };
+                self.primary.borrow_mut().subscribers.insert(stream.as_raw_fd(), sub);
+                let reader = SubscriberReader { stream, incoming: Incoming::default(), primary: self.primary.clone() };
+                vec![ReactorAction::Add(Box::new(reader))]
+            },
+        }
+    }
+}
+
+impl<K, V> Reader for PrimaryListener<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone + 'static,
+          V: Encode + Decode + TypeDescriptor + 'static
+{}
+
+// Reads the messages a replica sends to its primary.
+struct SubscriberReader<K, V> {
+    stream: TcpStream,
+    incoming: Incoming,
+    primary: Rc<RefCell<Primary<K, V>>>,
+}
+
+impl<K, V> AsRawFd for SubscriberReader<K, V> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl<K, V> Task for SubscriberReader<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    fn run(&mut self) -> Vec<ReactorAction> {
+        let fd = self.stream.as_raw_fd();
+        let mut primary = self.primary.borrow_mut();
+        let received = match read_messages(&mut self.stream, &mut self.incoming) {
+            Ok(Some(messages)) => messages.into_iter().all(|m| primary.receive(fd, m).is_ok()),
+            _ => false,
+        };
+        let mut actions = primary.take_actions();
+        if !received {
+            primary.subscribers.remove(&fd);
+            actions.push(ReactorAction::Remove(fd));
+        }
+        actions
+    }
+}
+
+impl<K, V> Reader for SubscriberReader<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone + 'static,
+          V: Encode + Decode + TypeDescriptor + 'static
+{
+    fn writable(&mut self) -> Vec<ReactorAction> {
+        let fd = self.stream.as_raw_fd();
+        let mut primary = self.primary.borrow_mut();
+        primary.flush(fd);
+        // Once all it was sent has been written, it can be sent more;
+        // a failure to read the log is tried again when next shipped.
+        let _ = primary.ship_to(fd);
+        primary.take_actions()
+    }
+}
+
+/// Ships a primary's log to its replicas every `interval`, as a reactor
+/// `Scheduler`.
+pub struct Shipper<K, V> {
+    primary: Rc<RefCell<Primary<K, V>>>,
+    interval: Duration,
+    next: Instant,
+}
+
+impl<K, V> Shipper<K, V> {
+    pub fn new(primary: &Rc<RefCell<Primary<K, V>>>, interval: Duration) -> Shipper<K, V> {
+        Shipper { primary: primary.clone(), interval, next: Instant::now() + interval }
+    }
+}
+
+impl<K, V> Task for Shipper<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    fn run(&mut self) -> Vec<ReactorAction> {
+        let mut primary = self.primary.borrow_mut();
+        // A failure to read the log is tried again next time.
+        let _ = primary.ship();
+        self.next = Instant::now() + self.interval;
+        primary.take_actions()
+    }
+}
+
+impl<K, V> Scheduler for Shipper<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    fn due(&self) -> i32 {
+        let now = Instant::now();
+        if now >= self.next {
+            0
+        } else {
+            let pause = self.next - now;
+            let pause_ms = pause.as_secs() * 1000 + pause.subsec_millis() as u64;
+            if pause_ms > (i32::max_value() as u64) { i32::max_value() } else { pause_ms as i32 }
+        }
+    }
+}
+
+/// A read-only copy of a table kept up to date by a primary.
+///
+/// The replica keeps its copy as a log of its own at `path`, with the
+/// point of the primary's log it has applied recorded beside it (see
+/// `backup::backup_point_path`), so that once reopened it catches up
+/// from there.
+pub struct Replica<K, V> {
+    path: String,
+    options: TableOptions,
+    table: Table<K, V>,
+    point: BackupPoint,
+    // The point of the snapshot whose parts are being written, once the
+    // first has been.
+    installing: Option<BackupPoint>,
+    stream: Option<TcpStream>,
+    incoming: Incoming,
+}
+
+impl<K, V> Replica<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor + Clone
+{
+    /// Opens the replica kept at `path`, creating it if need be. The
+    /// options must give the codecs of the primary's table; the copy is
+    /// opened read-only.
+    pub fn open(options: &TableOptions, path: &str) -> Result<Replica<K, V>, TableError> {
+        let mut options = options.clone();
+        options.write(false);
+        let point = match BackupPoint::read(&backup_point_path(path)) {
+            Ok(p) if metadata(path).is_ok() => p,
+            _ => {
+                let mut buf: Vec<u8> = Vec::new();
+                options.header::<K, V>().encode(&mut buf).unwrap();
+                match write_synced(path, &buf) {
+                    Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                    Ok(()) => NOWHERE,
+                }
+            },
+        };
+        let table = match options.open(path) {
+            Err(e) => return Err(e),
+            Ok(t) => t,
+        };
+        Ok(Replica {
+            path: path.to_string(),
+            options,
+            table,
+            point,
+            installing: None,
+            stream: None,
+            incoming: Incoming::default(),
+        })
+    }
+
+    pub fn table(&self) -> &Table<K, V> {
+        &self.table
+    }
+
+    /// The point of the primary's log the replica has applied.
+    pub fn point(&self) -> BackupPoint {
+        self.point
+    }
+
+    /// Connects to the primary at `addr` and subscribes to its log from
+    /// the point the replica has applied.
+    pub fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), TableError> {
+        let mut stream = match TcpStream::connect(addr) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(s) => s,
+        };
+        match send(&mut stream, &Message::Subscribe(self.point)) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        self.stream = Some(stream);
+        self.incoming = Incoming::default();
+        self.installing = None;
+        Ok(())
+    }
+
+    /// Reads what the primary has sent, waiting until something has
+    /// arrived, and applies it. Returns false once the connection has
+    /// closed.
+    pub fn receive(&mut self) -> Result<bool, TableError> {
+        let received = match self.stream {
+            None => return Ok(false),
+            Some(ref mut stream) => read_messages(stream, &mut self.incoming),
+        };
+        let messages = match received {
+            Err(e) => {
+                self.stream = None;
+                return Err(e);
+            },
+            Ok(None) => {
+                self.stream = None;
+                return Ok(false);
+            },
+            Ok(Some(messages)) => messages,
+        };
+        for message in messages {
+            match self.apply(message) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        Ok(true)
+    }
+
+    // Applies a message from the primary and reports the point reached.
+    fn apply(&mut self, message: Message) -> Result<(), TableError> {
+        let reply = match message {
+            Message::Records(start, ref bytes) if start == self.point => match self.append(bytes) {
+                Err(e) => return Err(e),
+                Ok(()) => Message::Applied(self.point),
+            },
+            // Records which do not follow on from what the replica has
+            // mean starting again.
+            Message::Records(..) => Message::Subscribe(NOWHERE),
+            Message::Snapshot(point, ref bytes) => match self.install(point, bytes, true) {
+                Err(e) => return Err(e),
+                Ok(()) => Message::Applied(self.point),
+            },
+            // Nothing has been applied until the snapshot is complete.
+            Message::SnapshotPart(point, ref bytes) => return self.install(point, bytes, false),
+            _ => return Err(TableError::DecodeError(DecodeError::Corrupt)),
+        };
+        match self.stream {
+            None => Ok(()),
+            Some(ref mut stream) => send(stream, &reply),
+        }
+    }
+
+    // Appends records from the primary's log to the replica's log and
+    // applies them to the table.
+    fn append(&mut self, bytes: &[u8]) -> Result<(), TableError> {
+        let durable = self.options.durability != Durability::Os;
+        let written = OpenOptions::new().append(true).open(&self.path).and_then(|mut f| {
+            f.write_all(bytes)?;
+            if durable { f.sync_data() } else { Ok(()) }
+        });
+        match written {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        match self.table.apply_log(bytes) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        // Should the point not be recorded, the records are sent again,
+        // which leaves the table as it was.
+        self.point.offset += bytes.len() as u64;
+        match self.point.write(&backup_point_path(&self.path)) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    // Writes a part of a snapshot of the primary's table at point to a
+    // new log beside the replica's, and once the snapshot is complete
+    // replaces the replica's copy of the table with it.
+    fn install(&mut self, point: BackupPoint, bytes: &[u8], complete: bool) -> Result<(), TableError> {
+        let mut newpath = self.path.clone();
+        newpath.push('~');
+        let continued = self.installing == Some(point);
+        self.installing = None;
+        let written = OpenOptions::new().write(true).create(true).append(continued).truncate(!continued)
+            .open(&newpath)
+            .and_then(|mut f| {
+                f.write_all(bytes)?;
+                if complete { f.sync_all() } else { Ok(()) }
+            });
+        match written {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        if !complete {
+            self.installing = Some(point);
+            return Ok(());
+        }
+        match rename(&newpath, &self.path) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        let _ = remove_file(hint_path(&self.path));
+        self.table = match self.options.open(&self.path) {
+            Err(e) => return Err(e),
+            Ok(t) => t,
+        };
+        self.point = point;
+        match point.write(&backup_point_path(&self.path)) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+/// Receives what a primary sends to a replica, as a reactor `Reader`.
+pub struct ReplicaReader<K, V> {
+    replica: Rc<RefCell<Replica<K, V>>>,
+    fd: RawFd,
+}
+
+impl<K, V> ReplicaReader<K, V> {
+    /// Reads for `replica`, which must be connected.
+    pub fn new(replica: &Rc<RefCell<Replica<K, V>>>) -> Result<ReplicaReader<K, V>, TableError> {
+        let fd = match replica.borrow().stream {
+            None => return Err(TableError::IOError(io::Error::new(io::ErrorKind::NotConnected, "replica not connected"))),
+            Some(ref stream) => stream.as_raw_fd(),
+        };
+        Ok(ReplicaReader { replica: replica.clone(), fd })
+    }
+}
+
+impl<K, V> AsRawFd for ReplicaReader<K, V> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl<K, V> Task for ReplicaReader<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor + Clone
+{
+    fn run(&mut self) -> Vec<ReactorAction> {
+        match self.replica.borrow_mut().receive() {
+            Ok(true) => Vec::new(),
+            _ => vec![ReactorAction::Remove(self.fd)],
+        }
+    }
+}
+
+impl<K, V> Reader for ReplicaReader<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone + 'static,
+          V: Encode + Decode + TypeDescriptor + Clone + 'static
+{}
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_replication() {
+    use std::os::unix::fs::MetadataExt;
+    use table::WriteBatch;
+
+    // Ships from primary to replica, subscribed as sub, until it has
+    // applied the log of primary.
+    fn catch_up(replica: &Rc<RefCell<Replica<i64, Vec<u8>>>>, primary: &Rc<RefCell<Primary<i64, Vec<u8>>>>,
+                sub: &mut Box<dyn Reader>) {
+        let target = primary.borrow_mut().table_mut().log_point().unwrap();
+        while replica.borrow().point() != target {
+            sub.writable();
+            assert!(replica.borrow_mut().receive().unwrap());
+        }
+    }
+    fn contents(t: &Table<i64, Vec<u8>>) -> Vec<(i64, Vec<u8>)> {
+        t.into_iter().map(|(k, v)| (*k, v.clone())).collect()
+    }
+
+    let mut buf = Vec::new();
+    let point = BackupPoint { dev: 1, ino: 2, offset: 300 };
+    Message::Records(point, b"records".to_vec()).encode(&mut buf);
+    Message::Applied(point).encode(&mut buf);
+    assert_eq!(Message::decode(&buf[..5]).unwrap(), None);
+    let (m, n) = Message::decode(&buf).unwrap().unwrap();
+    assert_eq!(m, Message::Records(point, b"records".to_vec()));
+    assert_eq!(Message::decode(&buf[n..]).unwrap(), Some((Message::Applied(point), buf.len() - n)));
+    assert_eq!(message_len(&buf[..1]).unwrap(), Some(n));
+    assert_eq!(message_len(&buf[n..]).unwrap(), Some(buf.len() - n));
+    assert_eq!(message_len(&[]).unwrap(), None);
+
+    let path = temp_path("primary");
+    let rpath = temp_path("replica");
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    for i in 0..20 {
+        t.insert(i, vec![i as u8; 20]).unwrap();
+    }
+    let primary = Rc::new(RefCell::new(Primary::new(t)));
+    // So that snapshots are sent in parts and records a few at a time.
+    primary.borrow_mut().message_size = 64;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut accept = PrimaryListener::new(listener, &primary);
+
+    // A new replica is sent a snapshot.
+    let replica = Rc::new(RefCell::new(Replica::open(&TableOptions::new(), &rpath).unwrap()));
+    replica.borrow_mut().connect(addr).unwrap();
+    let mut sub = match accept.run().pop() {
+        Some(ReactorAction::Add(r)) => r,
+        _ => panic!("expected a subscriber"),
+    };
+    match sub.run().pop() {
+        Some(ReactorAction::WatchWrites(_, true)) => (),
+        _ => panic!("expected to watch for writes"),
+    }
+    let mut reader = ReplicaReader::new(&replica).unwrap();
+    assert!(reader.run().is_empty());
+    catch_up(&replica, &primary, &mut sub);
+    assert_eq!(contents(replica.borrow().table()), contents(primary.borrow().table()));
+    assert!(sub.run().is_empty());
+    assert_eq!(primary.borrow().replicas()[0].1, Some(replica.borrow().point()));
+
+    // Then the records appended to the log.
+    {
+        let mut p = primary.borrow_mut();
+        p.table_mut().remove(&3).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(30, b"thirty".to_vec());
+        batch.remove(4);
+        p.table_mut().write(batch).unwrap();
+        p.table_mut().insert_auto(b"auto".to_vec()).unwrap();
+        p.ship().unwrap();
+    }
+    let ino = metadata(&rpath).unwrap().ino();
+    catch_up(&replica, &primary, &mut sub);
+    assert_eq!(metadata(&rpath).unwrap().ino(), ino);
+    assert_eq!(contents(replica.borrow().table()), contents(primary.borrow().table()));
+    assert_eq!(replica.borrow().table().get(&4), None);
+
+    // And a snapshot again once the log is compacted.
+    {
+        let mut p = primary.borrow_mut();
+        p.table_mut().compact(&path).unwrap();
+        p.table_mut().insert(40, b"forty".to_vec()).unwrap();
+        p.ship().unwrap();
+    }
+    catch_up(&replica, &primary, &mut sub);
+    assert_eq!(contents(replica.borrow().table()), contents(primary.borrow().table()));
+    assert!(sub.run().is_empty());
+    let applied = replica.borrow().point();
+    assert_eq!(primary.borrow().replicas()[0].1, Some(applied));
+
+    // A replica which disconnects is dropped...
+    drop(reader);
+    drop(replica);
+    assert_eq!(sub.run().len(), 1);
+    assert!(primary.borrow().replicas().is_empty());
+
+    // ...and catches up from where it left off when reopened.
+    primary.borrow_mut().table_mut().insert(50, b"fifty".to_vec()).unwrap();
+    let replica = Rc::new(RefCell::new(Replica::open(&TableOptions::new(), &rpath).unwrap()));
+    assert_eq!(replica.borrow().point(), applied);
+    let ino = metadata(&rpath).unwrap().ino();
+    replica.borrow_mut().connect(addr).unwrap();
+    let mut sub = match accept.run().pop() {
+        Some(ReactorAction::Add(r)) => r,
+        _ => panic!("expected a subscriber"),
+    };
+    assert!(sub.run().is_empty());
+    catch_up(&replica, &primary, &mut sub);
+    assert_eq!(metadata(&rpath).unwrap().ino(), ino);
+    assert_eq!(replica.borrow().table().get(&50), Some(&b"fifty".to_vec()));
+    assert_eq!(contents(replica.borrow().table()), contents(primary.borrow().table()));
+
+    drop(sub);
+    drop(replica);
+    drop(primary);
+    remove_table(&path);
+    remove_table(&rpath);
+}
+
+#[test]
+fn test_slow_replica() {
+    use std::thread;
+
+    let path = temp_path("slow");
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    for i in 0..200 {
+        t.insert(i, vec![i as u8; 128 * 1024]).unwrap();
+    }
+    let primary = Rc::new(RefCell::new(Primary::new(t)));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut accept = PrimaryListener::new(listener, &primary);
+
+    // A snapshot more than the connection will take is queued rather
+    // than waited on, a part at a time.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    send(&mut stream, &Message::Subscribe(NOWHERE)).unwrap();
+    let mut sub = match accept.run().pop() {
+        Some(ReactorAction::Add(r)) => r,
+        _ => panic!("expected a subscriber"),
+    };
+    let fd = sub.as_raw_fd();
+    match sub.run().pop() {
+        Some(ReactorAction::WatchWrites(f, true)) => assert_eq!(f, fd),
+        _ => panic!("expected to watch for writes"),
+    }
+    let end = {
+        let mut p = primary.borrow_mut();
+        for i in 0..20 {
+            p.table_mut().insert(300 + i, vec![i as u8; 100 * 1024]).unwrap();
+        }
+        assert!(p.ship().is_ok());
+        p.table_mut().log_point().unwrap()
+    };
+
+    // The rest is written as the replica reads it, then what has been
+    // appended since, a message's worth at a time.
+    let reader = thread::spawn(move || {
+        let mut incoming = Incoming::default();
+        let mut messages = Vec::new();
+        loop {
+            match messages.last() {
+                Some(&Message::Records(p, ref bytes)) if p.offset + bytes.len() as u64 == end.offset => return messages,
+                _ => messages.extend(read_messages(&mut stream, &mut incoming).unwrap().unwrap()),
+            }
+        }
+    });
+    loop {
+        match sub.writable().pop() {
+            Some(ReactorAction::WatchWrites(_, false)) => break,
+            _ => thread::yield_now(),
+        }
+    }
+    let mut messages = reader.join().unwrap().into_iter();
+    let mut snapshot = Vec::new();
+    let mut parts = 0;
+    let point = loop {
+        match messages.next() {
+            Some(Message::SnapshotPart(_, bytes)) => {
+                assert!(bytes.len() < MESSAGE_SIZE + 129 * 1024);
+                snapshot.extend(bytes);
+                parts += 1;
+            },
+            Some(Message::Snapshot(point, bytes)) => {
+                snapshot.extend(bytes);
+                break point;
+            },
+            _ => panic!("expected a snapshot"),
+        }
+    };
+    assert!(parts > 1);
+    assert!(snapshot.len() > 200 * 128 * 1024);
+    let mut offset = point.offset;
+    for message in messages {
+        match message {
+            Message::Records(p, bytes) => {
+                assert_eq!(p, BackupPoint { offset, ..point });
+                assert!(bytes.len() <= MESSAGE_SIZE);
+                offset += bytes.len() as u64;
+            },
+            _ => panic!("expected records"),
+        }
+    }
+    assert!(offset > point.offset + MESSAGE_SIZE as u64);
+    assert_eq!(offset, end.offset);
+
+    drop(sub);
+    drop(primary);
+    remove_table(&path);
+}
@@ -0,0 +1,419 @@
+//! Salvaging what can be read of a damaged table log.
+//!
+//! Opening a table fails if its log is damaged anywhere but at the very
+//! end. `TableOptions::check` reads a log as far as it can instead:
+//! where a record cannot be read it skips ahead to the next offset at
+//! which one can, which must be framed with a matching checksum and
+//! decode as a record of the table. To keep the search linear, no
+//! record longer than a megabyte is looked for there, so one may be
+//! lost along with the damage before it. The bytes skipped are reported,
+//! with the keys of the records lost in them where those can still be
+//! read, along with the keys of any batch left incomplete by them.
+//! `TableOptions::salvage` then writes a clean table from everything
+//! recovered.
+//!
+//! A key written again after the damage is recovered as last written,
+//! but one whose latest record was lost may be recovered with an older
+//! value, or not at all.
+
+use std::collections::BTreeMap;
+use std::fs::{File, metadata};
+use std::io;
+use std::io::prelude::*;
+
+use backup::write_synced;
+use codec::Codecs;
+use checkpoint::log_checkpoint;
+use encode::{Encode, Control, COMPRESSED, frame_control};
+use decode::*;
+use expiry::Expiries;
+use header::{Header, TypeDescriptor};
+use table::{TableError, TableOptions};
+
+/// A part of a table log which could not be read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Damage<K> {
+    /// Offset of the first byte lost...
+    pub start: usize,
+    /// ...and of the first byte after them.
+    pub end: usize,
+    /// The keys of the records lost, where they could still be read.
+    pub keys: Vec<K>,
+}
+
+/// What was recovered from a table log by `TableOptions::check`.
+#[derive(Clone, Debug)]
+pub struct SalvageReport<K> {
+    /// Length of the log.
+    pub length: usize,
+    /// Number of records recovered...
+    pub records: usize,
+    /// ...and of entries in the table they make.
+    pub entries: usize,
+    pub damage: Vec<Damage<K>>,
+}
+
+impl<K> SalvageReport<K> {
+    /// Whether the whole log could be read.
+    pub fn is_clean(&self) -> bool {
+        self.damage.is_empty()
+    }
+
+    /// Number of bytes of the log lost.
+    pub fn lost(&self) -> usize {
+        self.damage.iter().map(|d| d.end - d.start).sum()
+    }
+}
+
+// The contents of a table recovered from its log.
+struct Salvaged<K, V> {
+    map: BTreeMap<K, V>,
+    expiries: Expiries<K>,
+    sequence: u64,
+    records: usize,
+}
+
+impl<K: Encode + Ord + Clone, V: Encode> LogMap<K, V> for Salvaged<K, V> {
+    fn replay_insert(&mut self, key: K, value: V, loc: ValueLoc) -> Option<usize> {
+        self.records += 1;
+        self.expiries.set(&key, loc.expires);
+        self.map.replay_insert(key, value, loc)
+    }
+
+    fn replay_remove(&mut self, key: &K) -> Option<usize> {
+        self.records += 1;
+        self.expiries.set(key, None);
+        self.map.replay_remove(key)
+    }
+
+    fn replay_sequence(&mut self, next: u64) {
+        self.sequence = self.sequence.max(next);
+    }
+}
+
+// A record of an open batch: its key, value and expiry.
+type Pending<K, V> = (K, Option<V>, Option<u64>);
+
+fn apply<K: Encode + Ord + Clone, V: Encode>(data: &mut Salvaged<K, V>, rec: Pending<K, V>) {
+    let (key, value, expires) = rec;
+    match value {
+        Some(value) => {
+            let loc = ValueLoc { offset: 0, len: 0, compressed: false, expires };
+            data.replay_insert(key, value, loc);
+        },
+        None => {
+            data.replay_remove(&key);
+        },
+    }
+}
+
+// The longest record looked for past damage.
+const RESYNC_WINDOW: usize = 1 << 20;
+
+// Returns the keys of the records framed in the damaged part of a log
+// in buf which can still be read.
+fn damaged_keys<K: Decode>(buf: &[u8]) -> Vec<K> {
+    let mut keys = Vec::new();
+    let mut rest = buf;
+    while let Some((len, payload)) = frame_at(rest) {
+        if rest[0] != 0xFF {
+            let mut p = if payload.first() == Some(&COMPRESSED) { &payload[1..] } else { payload };
+            if let Ok(key) = K::decode(&mut p) {
+                keys.push(key);
+            }
+        }
+        rest = &rest[len..];
+    }
+    keys
+}
+
+// A batch being read: where it starts, the number of records it holds,
+// those read so far and, once damage is found within it, the keys of
+// those lost there.
+struct Batch<K, V> {
+    start: usize,
+    size: u64,
+    records: Vec<Pending<K, V>>,
+    damaged: Option<Vec<K>>,
+}
+
+impl<K, V> Batch<K, V> {
+    // Notes that the batch, which ends at end, cannot be applied.
+    fn lose(self, end: usize, damage: &mut Vec<Damage<K>>) {
+        let mut keys: Vec<K> = self.records.into_iter().map(|r| r.0).collect();
+        keys.extend(self.damaged.unwrap_or_default());
+        damage.push(Damage { start: self.start, end, keys });
+    }
+
+    // Whether damage within the batch has taken the place of the rest
+    // of its records.
+    fn is_full(&self) -> bool {
+        match self.damaged {
+            Some(ref keys) => (self.records.len() + keys.len()) as u64 >= self.size,
+            None => false,
+        }
+    }
+}
+
+// Replays what can be read of the log in buf from offset start, whose
+// values are stored with codecs, into data, noting the damage skipped.
+fn scan<K, V>(buf: &[u8], start: usize, codecs: &Codecs, data: &mut Salvaged<K, V>, damage: &mut Vec<Damage<K>>)
+    where K: Encode + Decode + Ord + Clone, V: Decode + Encode
+{
+    let mut batch: Option<Batch<K, V>> = None;
+    let mut expires: Option<u64> = None;
+    let mut pos = start;
+    while pos < buf.len() {
+        let (entry, len) = match entry_at::<K, V>(&buf[pos..], codecs) {
+            Some(r) => r,
+            None => {
+                let limit = |p: usize| buf.len().min(p + RESYNC_WINDOW);
+                let end = (pos + 1..limit(pos)).find(|p| entry_at::<K, V>(&buf[*p..limit(*p)], codecs).is_some())
+                    .unwrap_or(limit(pos));
+                let keys = damaged_keys(&buf[pos..end]);
+                // Damage within a batch loses the whole batch, and damage
+                // longer than the window is searched a window at a time.
+                match (batch.as_mut(), damage.last_mut()) {
+                    (Some(b), _) => b.damaged.get_or_insert(Vec::new()).extend(keys),
+                    (None, Some(ref mut d)) if d.end == pos => {
+                        d.end = end;
+                        d.keys.extend(keys);
+                    },
+                    (None, _) => damage.push(Damage { start: pos, end, keys }),
+                };
+                expires = None;
+                pos = end;
+                continue;
+            },
+        };
+        let at = pos;
+        pos += len;
+        let commit = matches!(entry, Entry::Control(Control::BatchCommit(_)));
+        if !commit && batch.as_ref().is_some_and(|b| b.is_full()) {
+            batch.take().unwrap().lose(at, damage);
+        }
+        match entry {
+            Entry::Control(Control::Expires(t)) => expires = Some(t),
+            Entry::Control(Control::BatchBegin(n)) => {
+                if let Some(b) = batch.take() {
+                    b.lose(at, damage);
+                }
+                batch = Some(Batch { start: at, size: n, records: Vec::new(), damaged: None });
+            },
+            Entry::Control(Control::BatchCommit(n)) => match batch.take() {
+                Some(ref mut b) if b.damaged.is_none() && b.records.len() as u64 == n => {
+                    for rec in b.records.drain(..) {
+                        apply(data, rec);
+                    }
+                },
+                Some(b) => b.lose(pos, damage),
+                None => (),
+            },
+            Entry::Control(Control::Sequence(n)) => data.replay_sequence(n),
+            Entry::Control(Control::Checkpoint(_)) => (),
+            Entry::Record(key, value, _, _) => {
+                let rec = (key, value, expires.take());
+                match batch {
+                    Some(ref mut b) => b.records.push(rec),
+                    None => apply(data, rec),
+                };
+            },
+        };
+    }
+    if let Some(b) = batch {
+        b.lose(buf.len(), damage);
+    }
+}
+
+impl TableOptions {
+    // Reads what can be read of the table log at path into a map.
+    fn recover<K, V>(&self, path: &str) -> Result<(Salvaged<K, V>, SalvageReport<K>), TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        let mut buf = Vec::new();
+        match File::open(path).and_then(|mut f| f.read_to_end(&mut buf)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(_) => (),
+        };
+        let mut data = Salvaged { map: BTreeMap::new(), expiries: Expiries::new(), sequence: 0, records: 0 };
+        let mut damage = Vec::new();
+        let mut stats = DecodeStats::default();
+        let expected = self.header::<K, V>();
+        // Logs from before headers were written have no codecs.
+        let none = Codecs::new();
+        let (start, codecs) = match Header::decode_stats(&mut io::Cursor::new(&buf[..]), &mut stats) {
+            Ok(None) => (0, &none),
+            Ok(Some(h)) => {
+                if h.key_type != expected.key_type || h.value_type != expected.value_type {
+                    return Err(TableError::TypeMismatch { key: h.key_type, value: h.value_type });
+                }
+                if h.codecs != expected.codecs {
+                    return Err(TableError::CodecMismatch(h.codecs));
+                }
+                (stats.read(), &self.codecs)
+            },
+            // A damaged header is taken to be the one expected.
+            Err(_) => {
+                damage.push(Damage { start: 0, end: 1, keys: Vec::new() });
+                (1, &self.codecs)
+            },
+        };
+        if let Ok(Some(gen)) = log_checkpoint(path) {
+            match self.recover_checkpoint(path, gen, &mut data) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        scan(&buf, start, codecs, &mut data, &mut damage);
+
+        // Skipping a damaged header runs into the damage after it.
+        if damage.len() > 1 && damage[0].end == damage[1].start {
+            let d = damage.remove(1);
+            damage[0].end = d.end;
+            damage[0].keys = d.keys;
+        }
+        let report = SalvageReport {
+            length: buf.len(),
+            records: data.records,
+            entries: data.map.len(),
+            damage,
+        };
+        Ok((data, report))
+    }
+
+    /// Reads what can be read of the table log at `path`, reporting the
+    /// damage found without changing anything.
+    pub fn check<K, V>(&self, path: &str) -> Result<SalvageReport<K>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        match self.recover::<K, V>(path) {
+            Err(e) => Err(e),
+            Ok((_, report)) => Ok(report),
+        }
+    }
+
+    /// Writes everything which can be recovered from the table log at
+    /// `path` to a clean table at `to`, reporting the damage found.
+    /// Fails with `TableError::Exists` if there is already a file at
+    /// `to`.
+    pub fn salvage<K, V>(&self, path: &str, to: &str) -> Result<SalvageReport<K>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        if metadata(to).is_ok() {
+            return Err(TableError::Exists(to.to_string()));
+        }
+        let (data, report) = match self.recover::<K, V>(path) {
+            Err(e) => return Err(e),
+            Ok(r) => r,
+        };
+        let mut buf: Vec<u8> = Vec::new();
+        self.header::<K, V>().encode(&mut buf).unwrap();
+        let mut compression = CompressionStats::default();
+        for (key, value) in &data.map {
+            if let Some(t) = data.expiries.get(key) {
+                frame_control(&mut buf, Control::Expires(t));
+            }
+            self.frame_record(&mut buf, key, Some(value), &mut compression);
+        }
+        if data.sequence > 0 {
+            frame_control(&mut buf, Control::Sequence(data.sequence));
+        }
+        match write_synced(to, &buf) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(report),
+        }
+    }
+}
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_salvage() {
+    use std::fs::OpenOptions;
+    use table::{Table, WriteBatch};
+
+    let path = temp_path("damaged");
+    let to = temp_path("salvaged");
+    let mut offsets = Vec::new();
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        for i in 0..10 {
+            offsets.push(t.stats().valid());
+            t.insert(i, vec![i as u8; 30]).unwrap();
+        }
+        offsets.push(t.stats().valid());
+        let mut batch = WriteBatch::new();
+        batch.insert(20, b"twenty".to_vec());
+        batch.insert(21, b"twenty-one".to_vec());
+        t.write(batch).unwrap();
+        offsets.push(t.stats().valid());
+        t.insert(2, b"two again".to_vec()).unwrap();
+        t.insert_auto(b"auto".to_vec()).unwrap();
+    }
+    let clean = TableOptions::new().check::<i64, Vec<u8>>(&path).unwrap();
+    assert!(clean.is_clean());
+    assert_eq!((clean.records, clean.entries), (14, 13));
+
+    // Damage the values of records 3 and 4, and the middle of the batch.
+    {
+        let mut f = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf).unwrap();
+        for at in &[offsets[3] + 10, offsets[4] + 12, offsets[10] + 20] {
+            buf[*at] ^= 0x55;
+        }
+        f.seek(io::SeekFrom::Start(0)).unwrap();
+        f.write_all(&buf).unwrap();
+    }
+    assert!(Table::<i64, Vec<u8>>::open(&path).is_err());
+
+    let report = TableOptions::new().salvage::<i64, Vec<u8>>(&path, &to).unwrap();
+    assert_eq!(report.damage.len(), 2);
+    assert_eq!(report.damage[0], Damage { start: offsets[3], end: offsets[5], keys: vec![3, 4] });
+    // Damage within a batch loses the whole batch.
+    assert_eq!(report.damage[1], Damage { start: offsets[10], end: offsets[11], keys: vec![21, 20] });
+    assert_eq!(report.lost(), offsets[5] - offsets[3] + offsets[11] - offsets[10]);
+
+    let t: Table<i64, Vec<u8>> = Table::open(&to).unwrap();
+    let keys: Vec<i64> = (&t).into_iter().map(|(k, _)| *k).collect();
+    assert_eq!(keys, vec![0, 1, 2, 5, 6, 7, 8, 9, 22]);
+    assert_eq!(t.get(&2), Some(&b"two again".to_vec()));
+    drop(t);
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&to).unwrap();
+    assert_eq!(t.insert_auto(b"next".to_vec()).unwrap(), 23);
+    drop(t);
+
+    match TableOptions::new().salvage::<i64, Vec<u8>>(&path, &to) {
+        Err(TableError::Exists(_)) => (),
+        _ => panic!("expected the salvaged table to exist"),
+    };
+    match TableOptions::new().check::<Vec<u8>, Vec<u8>>(&path) {
+        Err(TableError::TypeMismatch { .. }) => (),
+        _ => panic!("expected a type mismatch"),
+    };
+
+    // Damage longer than the search window is one region.
+    remove_table(&path);
+    let mut offsets = Vec::new();
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        for i in 0..3 {
+            offsets.push(t.stats().valid());
+            t.insert(i, vec![i as u8; 30]).unwrap();
+        }
+    }
+    let mut buf = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut buf).unwrap();
+    let garbage = vec![0u8; RESYNC_WINDOW + 100];
+    let tail = buf.split_off(offsets[1]);
+    buf.extend(&garbage);
+    buf.extend(tail);
+    File::create(&path).unwrap().write_all(&buf).unwrap();
+    let report = TableOptions::new().check::<i64, Vec<u8>>(&path).unwrap();
+    assert_eq!(report.damage, vec![Damage { start: offsets[1], end: offsets[1] + garbage.len(), keys: vec![] }]);
+    assert_eq!(report.entries, 3);
+
+    remove_table(&path);
+    remove_table(&to);
+}
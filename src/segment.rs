@@ -0,0 +1,90 @@
+//! Naming and listing the log segments of a segmented table.
+//!
+//! A segmented table is a directory of logs, each starting with a
+//! table header, which are replayed in order. A segment is named for
+//! the range of sequence numbers it holds: a new segment holds just
+//! its own, while merging segments writes one holding the range of
+//! them all, which supersedes any other segment within that range.
+
+use std::fs::read_dir;
+use std::io;
+
+/// The lock file of a segmented table, which is never replaced.
+pub const LOCK_FILE: &str = "LOCK";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SegmentId {
+    pub lo: u64,
+    pub hi: u64,
+}
+
+impl SegmentId {
+    /// The id of a new segment with sequence number `seq`.
+    pub fn new(seq: u64) -> SegmentId {
+        SegmentId { lo: seq, hi: seq }
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{:08}-{:08}.bt", self.lo, self.hi)
+    }
+
+    /// Returns the id of the segment with file name `name`, if it is one.
+    pub fn parse(name: &str) -> Option<SegmentId> {
+        if !name.ends_with(".bt") {
+            return None;
+        }
+        let mut parts = name[..name.len() - 3].splitn(2, '-');
+        let lo = parts.next().and_then(|s| s.parse().ok());
+        let hi = parts.next().and_then(|s| s.parse().ok());
+        match (lo, hi) {
+            (Some(lo), Some(hi)) if lo <= hi => Some(SegmentId { lo, hi }),
+            _ => None,
+        }
+    }
+
+    /// Whether this segment supersedes `other`.
+    pub fn covers(&self, other: &SegmentId) -> bool {
+        self != other && self.lo <= other.lo && other.hi <= self.hi
+    }
+}
+
+/// Returns the path of segment `id` of the table in `dir`.
+pub fn segment_path(dir: &str, id: SegmentId) -> String {
+    format!("{}/{}", dir, id.file_name())
+}
+
+/// Lists the segments in `dir` in the order they are replayed, along
+/// with any superseded by a merge which was interrupted before it
+/// could remove them.
+pub fn list_segments(dir: &str) -> io::Result<(Vec<SegmentId>, Vec<SegmentId>)> {
+    let mut ids = Vec::new();
+    for entry in read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(id) = name.to_str().and_then(SegmentId::parse) {
+            ids.push(id);
+        }
+    }
+    let (mut live, mut superseded) = (Vec::new(), Vec::new());
+    for id in &ids {
+        if ids.iter().any(|other| other.covers(id)) {
+            superseded.push(*id);
+        } else {
+            live.push(*id);
+        }
+    }
+    live.sort_by_key(|id| id.hi);
+    superseded.sort();
+    Ok((live, superseded))
+}
+
+#[test]
+fn test_segment_ids() {
+    let id = SegmentId { lo: 3, hi: 12 };
+    assert_eq!(id.file_name(), "00000003-00000012.bt");
+    assert_eq!(SegmentId::parse(&id.file_name()), Some(id));
+    assert_eq!(SegmentId::parse("00000003-00000012.bt~"), None);
+    assert_eq!(SegmentId::parse(LOCK_FILE), None);
+    assert!(id.covers(&SegmentId::new(12)));
+    assert!(!id.covers(&id));
+    assert!(!id.covers(&SegmentId::new(13)));
+}
@@ -1,157 +1,2924 @@
-use std::fs::{File, OpenOptions, rename};
+use std::fs::{File, OpenOptions, create_dir_all, hard_link, metadata, remove_file, rename};
 use std::io;
 use std::io::prelude::*;
-// use std::io::Write;
-use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::collections::{BTreeMap, Bound};
+use std::collections::btree_map::Range;
+use std::iter::Rev;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use encode::Encode;
+use backup::{BackupPoint, backup_point_path, write_increment, write_synced};
+use codec::{Codecs, ValueCodec};
+use compress::Compression;
+use diagnostics::{Diagnostics, Sink};
+use encode::{Encode, Control, frame_control, frame_record};
 use decode::*;
+use checkpoint::{checkpoint_path, checkpoints, load_checkpoint, log_checkpoint, previous_log_path, write_checkpoint};
+use expiry::{Expiries, Unexpired, expiry_after, now_millis};
+use header::{Header, TypeDescriptor, VERSION};
+use hint::{Hint, KeyLocations, hint_path};
+use lock::{LockKind, lock};
+use log::LogWriter;
+use segment::{LOCK_FILE, SegmentId, list_segments, segment_path};
 
-type DataMap = BTreeMap<i64,Vec<u8>>;
+pub use log::Durability;
 
-pub struct Table {
-    file: Option<File>,
-    map: DataMap,
+/// A policy for compacting a table automatically as it is written.
+#[derive(Clone, Copy, Debug)]
+pub struct AutoCompact {
+    /// Compaction starts when the fraction of the log taken by
+    /// superseded records (see `DecodeStats::garbage_ratio`) reaches
+    /// this value...
+    pub ratio: f64,
+    /// ...and the log is at least this many bytes long.
+    pub min_size: usize,
+    /// Number of entries copied to the new log by each write while
+    /// compaction is in progress.
+    pub step: usize,
 }
 
-#[derive(Debug)]
-pub enum TableError {
-    IOError(io::Error),
-    DecodeError(DecodeError),
-    NotWritable,
+/// Options for opening a `Table`, in the style of `std::fs::OpenOptions`.
+#[derive(Clone, Debug)]
+pub struct TableOptions {
+    pub(crate) write: bool,
+    create: bool,
+    pub(crate) durability: Durability,
+    pub(crate) buffer_size: usize,
+    pub(crate) auto_compact: Option<AutoCompact>,
+    lock_timeout: Duration,
+    pub(crate) cache_size: usize,
+    pub(crate) segment_size: Option<usize>,
+    pub(crate) compression: Compression,
+    pub(crate) codecs: Codecs,
+    pub(crate) diagnostics: Sink,
+}
+
+impl Default for TableOptions {
+    fn default() -> TableOptions {
+        TableOptions::new()
+    }
 }
 
-impl Table {
-    pub fn open(path: &str) -> Result<Table, TableError> {
-        let mut f = match File::open(path) {
+impl TableOptions {
+    /// Read-only options with `Durability::Os` and an 8K write buffer.
+    pub fn new() -> TableOptions {
+        TableOptions {
+            write: false,
+            create: false,
+            durability: Durability::Os,
+            buffer_size: 8192,
+            auto_compact: None,
+            lock_timeout: Duration::from_secs(0),
+            cache_size: 1024,
+            segment_size: None,
+            compression: Compression::None,
+            codecs: Codecs::new(),
+            diagnostics: Sink::default(),
+        }
+    }
+
+    /// Opens the table for writing; implies `create`.
+    pub fn write(&mut self, write: bool) -> &mut TableOptions {
+        self.write = write;
+        self.create = write;
+        self
+    }
+
+    /// Whether a missing file is created when opening for writing.
+    pub fn create(&mut self, create: bool) -> &mut TableOptions {
+        self.create = create;
+        self
+    }
+
+    pub fn durability(&mut self, durability: Durability) -> &mut TableOptions {
+        self.durability = durability;
+        self
+    }
+
+    /// Capacity of the buffer holding writes not yet given to the OS.
+    pub fn buffer_size(&mut self, size: usize) -> &mut TableOptions {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Compacts the table online whenever `policy` says it is due. A
+    /// `LazyTable` is compacted all at once, so ignores `step`.
+    pub fn auto_compact(&mut self, policy: AutoCompact) -> &mut TableOptions {
+        self.auto_compact = Some(policy);
+        self
+    }
+
+    /// How long to wait for another process to release its lock on the
+    /// table before failing with `TableError::Locked`. By default
+    /// opening fails at once.
+    pub fn wait_for_lock(&mut self, timeout: Duration) -> &mut TableOptions {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Stores the table as a directory of log segments, starting a new
+    /// segment once the current one reaches `max_size` bytes. Older
+    /// segments are rewritten together by `Table::merge`.
+    pub fn segmented(&mut self, max_size: usize) -> &mut TableOptions {
+        self.segment_size = Some(max_size);
+        self
+    }
+
+    /// How values written to the table are compressed. Records written
+    /// with and without compression can be mixed in one log.
+    pub fn compression(&mut self, compression: Compression) -> &mut TableOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Adds `codec` to the chain values are passed through as they are
+    /// stored (see `codec`). A table must always be opened with the
+    /// chain it was created with.
+    pub fn codec<C: ValueCodec + 'static>(&mut self, codec: C) -> &mut TableOptions {
+        self.codecs.push(codec);
+        self
+    }
+
+    /// Where tables report what they read on opening and how long
+    /// compaction takes (see `diagnostics`). Nothing is reported by
+    /// default.
+    pub fn diagnostics(&mut self, diagnostics: Arc<dyn Diagnostics>) -> &mut TableOptions {
+        self.diagnostics = Sink::new(diagnostics);
+        self
+    }
+
+    /// Number of values a `LazyTable` keeps in memory.
+    pub fn cache_size(&mut self, size: usize) -> &mut TableOptions {
+        self.cache_size = size;
+        self
+    }
+
+    // Opens and locks the file at path: shared for reading and
+    // exclusive for writing.
+    fn open_locked(&self, path: &str) -> Result<File, TableError> {
+        let kind = if self.write { LockKind::Exclusive } else { LockKind::Shared };
+        loop {
+            let f_or_e = OpenOptions::new().read(true).write(self.write).create(self.write && self.create).open(path);
+            let f = match f_or_e {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(f) => f,
+            };
+            match lock(&f, kind, self.lock_timeout) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(false) => return Err(TableError::Locked),
+                Ok(true) => (),
+            };
+
+            // A compaction may have replaced the file while we waited
+            // for the lock, in which case we try again with the new one.
+            let (opened, current) = match (f.metadata(), metadata(path)) {
+                (Ok(m1), Ok(m2)) => (m1, m2),
+                (Err(ioerr), _) => return Err(TableError::IOError(ioerr)),
+                (_, Err(ioerr)) => return Err(TableError::IOError(ioerr)),
+            };
+            if opened.dev() == current.dev() && opened.ino() == current.ino() {
+                return Ok(f);
+            }
+        }
+    }
+
+    // Returns the header new logs are written with.
+    pub(crate) fn header<K: TypeDescriptor, V: TypeDescriptor>(&self) -> Header {
+        Header::new::<K, V>().with_codecs(self.codecs.names())
+    }
+
+    // Appends a framed record to buf with the value stored as the
+    // options say, adding any compressed value to stats.
+    pub(crate) fn frame_record<K: Encode, V: Encode>(&self, buf: &mut Vec<u8>, key: &K, value: Option<&V>,
+                                                     stats: &mut CompressionStats)
+    {
+        match value.and_then(|v| self.codecs.apply(v)) {
+            Some(stored) => self.compression.frame_stats(buf, key, Some(&stored), stats),
+            None => self.compression.frame_stats(buf, key, value, stats),
+        }
+    }
+
+    // Appends the records copying an entry which expires at expires, if
+    // ever, to a new log, unless it has expired by now. Returns the size
+    // of the record holding the value, if it was copied.
+    fn frame_entry<K: Encode, V: Encode>(&self, buf: &mut Vec<u8>, key: &K, value: &V, expires: Option<u64>, now: u64,
+                                         stats: &mut CompressionStats) -> Option<usize>
+    {
+        match expires {
+            Some(t) if t <= now => return None,
+            Some(t) => frame_control(buf, Control::Expires(t)),
+            None => (),
+        };
+        let start = buf.len();
+        self.frame_record(buf, key, Some(value), stats);
+        Some(buf.len() - start)
+    }
+
+    // Checks that a log with header h can be opened as a table from K
+    // to V with these options.
+    fn check_header<K: TypeDescriptor, V: TypeDescriptor>(&self, h: &Header) -> Result<(), TableError> {
+        if h.version > VERSION {
+            return Err(TableError::UnsupportedVersion(h.version));
+        }
+        let expected = self.header::<K, V>();
+        if h.key_type != expected.key_type || h.value_type != expected.value_type {
+            return Err(TableError::TypeMismatch { key: h.key_type.clone(), value: h.value_type.clone() });
+        }
+        if h.codecs != expected.codecs {
+            return Err(TableError::CodecMismatch(h.codecs.clone()));
+        }
+        Ok(())
+    }
+
+    // Checks the header of the log in f and replays it into data,
+    // starting from the hint at hint_path if given and good.
+    // Returns whether the log has a header.
+    fn read_log<K, V, M>(&self, f: &File, hint_path: Option<&str>, data: &mut M, stats: &mut DecodeStats) ->
+        Result<bool, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Decode + TypeDescriptor, M: LogMap<K, V>
+    {
+        let mut r = io::BufReader::new(f);
+        let start = stats.read();
+        let header = match Header::decode_stats(&mut r, stats) {
+            Err(de) => return Err(TableError::DecodeError(de)),
+            Ok(h) => h,
+        };
+        if let Some(ref h) = header {
+            match self.check_header::<K, V>(h) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        // Logs from before headers were written have no codecs.
+        let none = Codecs::new();
+        if header.is_none() {
+            // Records were framed before headers were written, and a
+            // log which does not start with a frame is older still.
+            let mut buf = Vec::new();
+            match r.read_to_end(&mut buf) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(_) => (),
+            };
+            let replayed = if buf.is_empty() || entry_at::<K, V>(&buf, &none).is_some() {
+                replay_coded(&mut &buf[..], stats, data, &none)
+            } else {
+                replay_legacy(&mut &buf[..], stats, data)
+            };
+            return match replayed {
+                Err(de) => Err(TableError::DecodeError(de)),
+                Ok(()) => Ok(false),
+            };
+        }
+        let codecs = &self.codecs;
+        if let Some(path) = hint_path {
+            if let Some(hint) = Hint::<K>::read(path, f) {
+                let covered = hint.covered;
+                stats.append(start + covered - stats.read(), hint.discarded);
+                match hint.apply(f, data, stats, codecs) {
+                    Err(de) => return Err(TableError::DecodeError(de)),
+                    Ok(()) => (),
+                };
+                match r.seek(SeekFrom::Start(covered as u64)) {
+                    Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                    Ok(_) => (),
+                };
+            }
+        }
+        match replay_coded(&mut r, stats, data, codecs) {
+            Err(de) => return Err(TableError::DecodeError(de)),
+            Ok(()) => (),
+        };
+        Ok(header.is_some())
+    }
+
+    // Drops any torn record left at the end of the log in f by a crash
+    // and positions f so that new records are appended after the last
+    // complete one.
+    fn prepare_append(f: &mut File, stats: &DecodeStats) -> Result<(), TableError> {
+        if stats.truncated() > 0 {
+            match f.set_len(stats.valid() as u64) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+        }
+        match f.seek(SeekFrom::Start(stats.valid() as u64)) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    // Opens the log at path, checks its header and replays it into
+    // data. Returns the file, positioned after the last complete
+    // record when writable, and whether the log has a header.
+    pub(crate) fn open_log<K, V, M>(&self, path: &str, data: &mut M, stats: &mut DecodeStats) ->
+        Result<(File, bool), TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Decode + TypeDescriptor, M: LogMap<K, V>
+    {
+        let mut f = match self.open_locked(path) {
+            Err(e) => return Err(e),
+            Ok(f) => f,
+        };
+        let has_header = match self.load_log(path, &f, data, stats) {
+            Err(e) => return Err(e),
+            Ok(h) => h,
+        };
+        self.diagnostics.log_read(path, stats);
+        if self.write {
+            match TableOptions::prepare_append(&mut f, stats) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        Ok((f, has_header))
+    }
+
+    // Replays the log at path, already opened as f, into data as
+    // open_log does, without locking it.
+    pub(crate) fn load_log<K, V, M>(&self, path: &str, f: &File, data: &mut M, stats: &mut DecodeStats) ->
+        Result<bool, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Decode + TypeDescriptor, M: LogMap<K, V>
+    {
+        match log_checkpoint(path) {
+            Err(de) => return Err(TableError::DecodeError(de)),
+            Ok(None) => (),
+            Ok(Some(gen)) => {
+                // The checkpoint is only loaded if the log says it can be.
+                match Header::decode_stats(&mut io::BufReader::new(f), &mut DecodeStats::default()) {
+                    Err(de) => return Err(TableError::DecodeError(de)),
+                    Ok(None) => (),
+                    Ok(Some(h)) => match self.check_header::<K, V>(&h) {
+                        Err(e) => return Err(e),
+                        Ok(()) => (),
+                    },
+                };
+                let mut r = f;
+                match r.seek(SeekFrom::Start(0)) {
+                    Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                    Ok(_) => (),
+                };
+                match self.recover_checkpoint(path, gen, data) {
+                    Err(e) => return Err(e),
+                    Ok(()) => data.replay_checkpoint(),
+                }
+            },
+        };
+        data.replay_file(path);
+        self.read_log(f, Some(&hint_path(path)), data, stats)
+    }
+
+    // Loads the state which a log continuing from checkpoint gen starts
+    // from into data: the newest good checkpoint at least as new or,
+    // failing that, the log that checkpoint replaced.
+    pub(crate) fn recover_checkpoint<K, V, M>(&self, path: &str, gen: u64, data: &mut M) -> Result<(), TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Decode + TypeDescriptor, M: LogMap<K, V>
+    {
+        let gens = match checkpoints(path) {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(gens) => gens,
+        };
+        for g in gens.into_iter().filter(|g| *g >= gen) {
+            match load_checkpoint(path, g, data, &self.codecs) {
+                Err(de) => return Err(TableError::DecodeError(de)),
+                Ok(true) => return Ok(()),
+                Ok(false) => (),
+            };
+        }
+
+        // The previous log may itself continue from the checkpoint before.
+        let prev = previous_log_path(path);
+        let f = match File::open(&prev) {
+            Err(_) => return Err(TableError::DecodeError(DecodeError::Corrupt)),
             Ok(f) => f,
         };
-        let mut stats = DecodeStats::default();
-        let m = match DataMap::decode_stats(&mut f, &mut stats) {
+        match log_checkpoint(&prev) {
             Err(de) => return Err(TableError::DecodeError(de)),
-            Ok(m) => m,
+            Ok(None) => (),
+            Ok(Some(g)) => match load_checkpoint(path, g, data, &self.codecs) {
+                Err(de) => return Err(TableError::DecodeError(de)),
+                Ok(true) => (),
+                Ok(false) => return Err(TableError::DecodeError(DecodeError::Corrupt)),
+            },
         };
-        println!("read: {} discarded: {}", stats.read(), stats.discarded());
-        Ok(Table { file: None, map: m })
+        let mut stats = DecodeStats::default();
+        data.replay_file(&prev);
+        match self.read_log(&f, None, data, &mut stats) {
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
     }
 
-    pub fn open_rw(path: &str) -> Result<Table, TableError> {
-        let f_or_e = OpenOptions::new().read(true).write(true).create(true).open(path);
+    // Replaces the log at path, whose contents have been written to
+    // checkpoint gen, with a new one continuing from the checkpoint and
+    // from the sequence, locked like the one it replaces. The old log is
+    // kept as the previous one. Returns the new log and its stats.
+    pub(crate) fn restart_log<K: TypeDescriptor, V: TypeDescriptor>(&self, path: &str, gen: u64, sequence: u64) ->
+        Result<(LogWriter, DecodeStats), TableError>
+    {
+        let durable = self.durability != Durability::Os;
+        let mut newpath = path.to_string();
+        newpath.push('~');
+        let f_or_e = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&newpath);
         let mut f = match f_or_e {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
             Ok(f) => f,
         };
+        match lock(&f, LockKind::Exclusive, Duration::from_secs(0)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(false) => return Err(TableError::Locked),
+            Ok(true) => (),
+        };
+        let mut buf: Vec<u8> = Vec::new();
+        self.header::<K, V>().encode(&mut buf).unwrap();
+        let header_size = buf.len();
+        frame_control(&mut buf, Control::Checkpoint(gen));
+        if sequence > 0 {
+            frame_control(&mut buf, Control::Sequence(sequence));
+        }
+        match f.write_all(&buf) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        if durable {
+            match f.sync_all() {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+        }
+
+        // Keep the old log as the previous one, which is linked rather
+        // than moved so that there is always a log at path.
+        let prev = previous_log_path(path);
+        let mut prevtmp = prev.clone();
+        prevtmp.push('~');
+        let _ = remove_file(&prevtmp);
+        match hard_link(path, &prevtmp).and_then(|()| rename(&prevtmp, &prev)).and_then(|()| rename(&newpath, path)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(()) => (),
+        };
+        // Only the checkpoint before is needed with the previous log,
+        // and the hint was for the old log.
+        if gen > 1 {
+            if let Ok(gens) = checkpoints(path) {
+                for g in gens.into_iter().filter(|g| *g < gen - 1) {
+                    let _ = remove_file(checkpoint_path(path, g));
+                }
+            }
+        }
+        let _ = remove_file(hint_path(path));
+
         let mut stats = DecodeStats::default();
-        let m = match DataMap::decode_stats(&mut f, &mut stats) {
-            Err(de) => return Err(TableError::DecodeError(de)),
-            Ok(m) => m,
+        stats.append(buf.len(), buf.len() - header_size);
+        Ok((LogWriter::new(f, self.buffer_size, self.durability), stats))
+    }
+
+    // Opens the segmented table in the directory dir, replaying its
+    // segments in order.
+    fn open_segmented<K, V>(&self, dir: &str, max_size: usize) -> Result<Table<K, V>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        if self.write && self.create {
+            match create_dir_all(dir) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+        }
+        let lock_file = match self.open_locked(&format!("{}/{}", dir, LOCK_FILE)) {
+            Err(e) => return Err(e),
+            Ok(f) => f,
+        };
+        let (mut live, superseded) = match list_segments(dir) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(r) => r,
         };
-        println!("read: {} discarded: {}", stats.read(), stats.discarded());
-        Ok(Table { file: Some(f), map: m })
+        // Finish tidying up after an interrupted merge.
+        if self.write {
+            for id in superseded {
+                let seg_path = segment_path(dir, id);
+                match remove_file(&seg_path) {
+                    Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                    Ok(()) => (),
+                };
+                // Not every segment has a hint.
+                let _ = remove_file(hint_path(&seg_path));
+            }
+        }
+
+        let mut m = Contents::new();
+        let mut stats = DecodeStats::default();
+        let mut active: Option<(File, DecodeStats, bool)> = None;
+        for (i, id) in live.iter().enumerate() {
+            let last = i + 1 == live.len();
+            let seg_path = segment_path(dir, *id);
+            let f = match OpenOptions::new().read(true).write(self.write && last).open(&seg_path) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(f) => f,
+            };
+            let mut seg_stats = DecodeStats::default();
+            let has_header = match self.read_log(&f, Some(&hint_path(&seg_path)), &mut m, &mut seg_stats) {
+                Err(e) => return Err(e),
+                Ok(h) => h,
+            };
+            // Only the active segment can have been cut short, and only
+            // it can lack a header, if it was created just before a crash.
+            if !last && (seg_stats.truncated() > 0 || !has_header) {
+                return Err(TableError::DecodeError(DecodeError::Corrupt));
+            }
+            if !has_header && seg_stats.valid() > 0 {
+                return Err(TableError::DecodeError(DecodeError::Corrupt));
+            }
+            stats.add(&seg_stats);
+            if last {
+                active = Some((f, seg_stats, has_header));
+            }
+        }
+        self.diagnostics.log_read(dir, &stats);
+
+        let mut t = Table::new(dir, None, m, stats, self.clone());
+        t.shared = Some(lock_file);
+        let mut segments = Segments { dir: dir.to_string(), max_size, live: Vec::new(), active_start: 0 };
+        if !self.write {
+            segments.live = live;
+            t.segments = Some(segments);
+            return Ok(t);
+        }
+        match active {
+            Some((mut f, seg_stats, has_header)) => {
+                match TableOptions::prepare_append(&mut f, &seg_stats) {
+                    Err(e) => return Err(e),
+                    Ok(()) => (),
+                };
+                segments.active_start = t.stats.valid() - seg_stats.valid();
+                segments.live = live;
+                t.segments = Some(segments);
+                t.file = Some(LogWriter::new(f, self.buffer_size, self.durability));
+                if !has_header {
+                    match t.write_header(&self.header::<K, V>()) {
+                        Err(e) => return Err(e),
+                        Ok(()) => (),
+                    };
+                }
+            },
+            None => {
+                live.clear();
+                segments.live = live;
+                t.segments = Some(segments);
+                match t.roll() {
+                    Err(e) => return Err(e),
+                    Ok(()) => (),
+                };
+            },
+        }
+        Ok(t)
     }
 
+    /// Opens the table at `path`, which is a directory for a segmented
+    /// table. A file written before tables had headers is upgraded by
+    /// compacting it when opened for writing.
+    pub fn open<K, V>(&self, path: &str) -> Result<Table<K, V>, TableError>
+        where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+    {
+        if let Some(max_size) = self.segment_size {
+            return self.open_segmented(path, max_size);
+        }
+        let mut m = Contents::new();
+        let mut stats = DecodeStats::default();
+        let (f, has_header) = match self.open_log(path, &mut m, &mut stats) {
+            Err(e) => return Err(e),
+            Ok(r) => r,
+        };
+        if !self.write {
+            let mut t = Table::new(path, None, m, stats, self.clone());
+            t.shared = Some(f);
+            return Ok(t);
+        }
+        let w = LogWriter::new(f, self.buffer_size, self.durability);
+        let mut t = Table::new(path, Some(w), m, stats, self.clone());
+        if !has_header {
+            let upgraded = if t.stats.valid() == 0 {
+                t.write_header(&self.header::<K, V>())
+            } else {
+                t.compact(path)
+            };
+            match upgraded {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        Ok(t)
+    }
+}
+
+// The contents of a table as replayed from its log: its entries, when
+// those written with a time to live expire, the size of the record in
+// the log holding each value, and its sequence.
+struct Contents<K, V> {
+    map: BTreeMap<K, V>,
+    expiries: Expiries<K>,
+    sizes: BTreeMap<K, usize>,
+    sequence: u64,
+}
+
+impl<K: Ord + Clone, V> Contents<K, V> {
+    fn new() -> Contents<K, V> {
+        Contents { map: BTreeMap::new(), expiries: Expiries::new(), sizes: BTreeMap::new(), sequence: 0 }
+    }
+}
+
+impl<K: Encode + Ord + Clone, V> LogMap<K, V> for Contents<K, V> {
+    fn replay_insert(&mut self, key: K, value: V, loc: ValueLoc) -> Option<usize> {
+        self.expiries.set(&key, loc.expires);
+        let size = loc.record_size(key.encode_size());
+        self.map.insert(key.clone(), value);
+        self.sizes.insert(key, size)
+    }
+
+    fn replay_remove(&mut self, key: &K) -> Option<usize> {
+        self.expiries.set(key, None);
+        self.map.remove(key);
+        self.sizes.remove(key)
+    }
+
+    fn replay_sequence(&mut self, next: u64) {
+        self.sequence = self.sequence.max(next);
+    }
+
+    fn replay_checkpoint(&mut self) {
+        self.sizes.clear();
+    }
+}
+
+// The state of an online compaction. Entries are copied to the new log
+// in key order; once a key has been copied, later changes to it are
+// written to both logs.
+struct Compaction<K> {
+    path: String,
+    file: io::BufWriter<File>,
+    // The last key copied, if any.
+    copied: Option<K>,
+    stats: DecodeStats,
+    started: Instant,
+}
+
+impl<K: Ord> Compaction<K> {
+    fn has_copied(&self, key: &K) -> bool {
+        match self.copied {
+            Some(ref last) => key <= last,
+            None => false,
+        }
+    }
+}
+
+// The segments of a segmented table, the last of which is active.
+struct Segments {
+    dir: String,
+    max_size: usize,
+    live: Vec<SegmentId>,
+    // Length of the table log, over all segments, where the active
+    // segment starts.
+    active_start: usize,
+}
+
+/// A persistent map from keys of type `K` to values of type `V`.
+///
+/// The table is held in memory and every change is appended to a log
+/// file, which is replayed when the table is opened. Both `K` and `V`
+/// must be `Encode + Decode`; see `Decode for BTreeMap` for the
+/// constraint this places on the encoding of `V`.
+///
+/// Buffered writes are flushed, and synced unless the durability is
+/// `Durability::Os`, when the table is dropped.
+pub struct Table<K, V> {
+    path: String,
+    file: Option<LogWriter>,
+    // Shared with any snapshots, and copied on write while they live.
+    map: Arc<BTreeMap<K, V>>,
+    expiries: Arc<Expiries<K>>,
+    // The size of the record in the log holding each value, so that
+    // what replacing it makes redundant is known.
+    sizes: BTreeMap<K, usize>,
+    // The next key `insert_auto` may issue.
+    sequence: u64,
+    stats: DecodeStats,
+    options: TableOptions,
+    // Kept open by a read-only table to hold its shared lock, and by a
+    // segmented table to hold the lock on its directory.
+    shared: Option<File>,
+    compaction: Option<Compaction<K>>,
+    segments: Option<Segments>,
+}
+
+#[derive(Debug)]
+pub enum TableError {
+    IOError(io::Error),
+    DecodeError(DecodeError),
+    NotWritable,
+    /// Another process has the table open for writing or, when opening
+    /// for writing, has it open at all.
+    Locked,
+    /// The file was written by a newer version of the log format.
+    UnsupportedVersion(u64),
+    /// The file holds a table with the given key and value types.
+    TypeMismatch { key: String, value: String },
+    /// The file holds a table whose values are stored with the named
+    /// codecs.
+    CodecMismatch(Vec<String>),
+    /// The operation is not supported by this kind of table.
+    Unsupported(&'static str),
+    /// The name given is not usable for the thing it names.
+    InvalidName(String),
+    /// Something with the given name already exists.
+    Exists(String),
+    /// Nothing with the given name exists.
+    NotFound(String),
+    /// A backup does not follow on from the point given for it: the
+    /// log has been replaced since, or an increment is missing.
+    BackupMismatch,
+    /// The sequence of the table has no keys left to issue.
+    SequenceExhausted,
+}
+
+// Returns the generation of the next checkpoint of the table at path.
+pub(crate) fn next_checkpoint(path: &str) -> Result<u64, TableError> {
+    match checkpoints(path) {
+        Err(ioerr) => Err(TableError::IOError(ioerr)),
+        Ok(gens) => match gens.first() {
+            Some(g) => Ok(g + 1),
+            None => Ok(1),
+        },
+    }
+}
+
+/// Writes a hint file for the complete records of the log at
+/// `log_path`, whose values are stored with `codecs`, which later opens
+/// of the log start from.
+pub fn write_hint<K, V>(log_path: &str, codecs: &Codecs) -> Result<(), TableError>
+    where K: Encode + Decode + Ord + Clone, V: Decode
+{
+    let f = match File::open(log_path) {
+        Err(ioerr) => return Err(TableError::IOError(ioerr)),
+        Ok(f) => f,
+    };
+    let hint = match Hint::<K>::build::<V>(&f, codecs) {
+        Err(de) => return Err(TableError::DecodeError(de)),
+        Ok(hint) => hint,
+    };
+    match hint.write(&hint_path(log_path), &f) {
+        Err(ioerr) => Err(TableError::IOError(ioerr)),
+        Ok(()) => Ok(()),
+    }
+}
+
+impl<K, V> Table<K, V> {
+    fn new(path: &str, file: Option<LogWriter>, contents: Contents<K, V>, stats: DecodeStats, options: TableOptions) ->
+        Table<K, V>
+    {
+        Table {
+            path: path.to_string(),
+            file,
+            map: Arc::new(contents.map),
+            expiries: Arc::new(contents.expiries),
+            sizes: contents.sizes,
+            sequence: contents.sequence,
+            stats,
+            options,
+            shared: None,
+            compaction: None,
+            segments: None,
+        }
+    }
+
+    /// Hands any buffered writes to the OS.
+    pub fn flush(&mut self) -> Result<(), TableError> {
+        match self.file {
+            None => Err(TableError::NotWritable),
+            Some(ref mut w) => match w.flush() {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(()) => Ok(()),
+            },
+        }
+    }
+
+    /// Flushes buffered writes and waits until they are on disk.
+    pub fn sync(&mut self) -> Result<(), TableError> {
+        match self.file {
+            None => Err(TableError::NotWritable),
+            Some(ref mut w) => match w.sync() {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(()) => Ok(()),
+            },
+        }
+    }
+
+    /// Returns the number of milliseconds until writes held back by
+    /// `Durability::GroupCommit` are due to be synced, 0 if they are,
+    /// or -1 if none are, as the reactor's `Scheduler::due` does, so
+    /// that a scheduler can sync them once the table falls idle.
+    pub fn sync_due(&self) -> i32 {
+        match self.file {
+            None => -1,
+            Some(ref w) => w.sync_due(),
+        }
+    }
+
+    /// Syncs the writes held back by `Durability::GroupCommit` if they
+    /// are due, returning whether it did.
+    pub fn sync_pending(&mut self) -> Result<bool, TableError> {
+        match self.file {
+            None => Ok(false),
+            Some(ref mut w) => match w.sync_pending() {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(synced) => Ok(synced),
+            },
+        }
+    }
+
+    // Called after each write to apply the durability policy.
+    fn committed(&mut self) -> Result<(), TableError> {
+        match self.file {
+            None => Err(TableError::NotWritable),
+            Some(ref mut w) => match w.committed() {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(()) => Ok(()),
+            },
+        }
+    }
+}
+
+impl<K, V> Table<K, V>
+    where K: Encode + Decode + TypeDescriptor + Ord + Clone, V: Encode + Decode + TypeDescriptor
+{
+    pub fn open(path: &str) -> Result<Table<K, V>, TableError> {
+        TableOptions::new().open(path)
+    }
+
+    pub fn open_rw(path: &str) -> Result<Table<K, V>, TableError> {
+        TableOptions::new().write(true).open(path)
+    }
+
+    /// Returns the statistics gathered when the table log was read,
+    /// kept up to date as the table is written and compacted.
+    pub fn stats(&self) -> &DecodeStats {
+        &self.stats
+    }
+
+    /// Rewrites the log to `path` holding only the current contents of
+    /// the table, finishing any compaction already in progress.
     pub fn compact(&mut self, path: &str) -> Result<(), TableError> {
-        // We must be open rw: close the file.
+        // A segmented table is compacted by merging all its segments,
+        // which the active one joins by being rolled over.
+        if self.segments.is_some() {
+            match self.roll() {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+            return self.merge();
+        }
+        if self.compaction.is_none() {
+            match self.start_compaction_to(path) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        loop {
+            match self.compact_step(1000) {
+                Err(e) => return Err(e),
+                Ok(true) => return Ok(()),
+                Ok(false) => (),
+            };
+        }
+    }
+
+    // Starts a new, empty log with the table header.
+    fn write_header(&mut self, header: &Header) -> Result<(), TableError> {
+        let mut buf: Vec<u8> = Vec::new();
+        header.encode(&mut buf).unwrap();
+        match self.append(&buf, buf.len(), 0, &CompressionStats::default()) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        self.flush()
+    }
+
+    /// Whether an online compaction is in progress.
+    pub fn is_compacting(&self) -> bool {
+        self.compaction.is_some()
+    }
+
+    /// Begins an online compaction of the table. The table stays
+    /// writable while `compact_step` is called to make progress. For a
+    /// segmented table this merges its older segments at once.
+    pub fn start_compaction(&mut self) -> Result<(), TableError> {
+        let path = self.path.clone();
+        self.start_compaction_to(&path)
+    }
+
+    fn start_compaction_to(&mut self, path: &str) -> Result<(), TableError> {
         match self.file {
             None => return Err(TableError::NotWritable),
-            Some(ref _f) => (),
+            Some(_) => (),
         };
-        self.file = None;
+        if self.segments.is_some() {
+            return self.merge();
+        }
+        if self.compaction.is_some() {
+            return Ok(());
+        }
 
         // Path for temporary file is path + "~".
         let mut newpath = path.to_string();
         newpath.push('~');
 
         let f_or_e = OpenOptions::new().write(true).create(true).truncate(true).open(&newpath);
-        let mut f = match f_or_e {
+        let f = match f_or_e {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
             Ok(f) => f,
         };
-
-        // Write contents of map to the temporary file.
-        match self.map.encode(&mut f) {
+        // The new file replaces the one we have locked, so it needs
+        // the same lock before anyone else can open it.
+        match lock(&f, LockKind::Exclusive, Duration::from_secs(0)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(false) => return Err(TableError::Locked),
+            Ok(true) => (),
+        };
+        let mut c = Compaction {
+            path: path.to_string(),
+            file: io::BufWriter::with_capacity(self.options.buffer_size, f),
+            copied: None,
+            stats: DecodeStats::default(),
+            started: Instant::now(),
+        };
+        let header = self.options.header::<K, V>();
+        match header.encode(&mut c.file) {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
             Ok(()) => (),
         };
+        c.stats.append(header.encode_size(), 0);
+        self.compaction = Some(c);
+        Ok(())
+    }
+
+    /// Copies up to `n` more entries to the new log of an online
+    /// compaction, returning true once it is complete and the new log
+    /// has replaced the old.
+    pub fn compact_step(&mut self, n: usize) -> Result<bool, TableError> {
+        {
+            let c = match self.compaction {
+                None => return Ok(true),
+                Some(ref mut c) => c,
+            };
+            let mut buf: Vec<u8> = Vec::new();
+            let mut compression = CompressionStats::default();
+            let mut last = None;
+            {
+                let start = match c.copied {
+                    Some(ref key) => Bound::Excluded(key),
+                    None => Bound::Unbounded,
+                };
+                // Expired entries are left behind.
+                let now = now_millis();
+                for (key, value) in self.map.range((start, Bound::Unbounded)).take(n) {
+                    let expires = self.expiries.get(key);
+                    match self.options.frame_entry(&mut buf, key, value, expires, now, &mut compression) {
+                        Some(size) => self.sizes.insert(key.clone(), size),
+                        None => self.sizes.remove(key),
+                    };
+                    last = Some(key.clone());
+                }
+            }
+            match c.file.write_all(&buf) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+            c.stats.append(buf.len(), 0);
+            c.stats.add_compression(&compression);
+            if last.is_some() {
+                c.copied = last;
+                return Ok(false);
+            }
+        }
+        match self.finish_compaction() {
+            Err(e) => Err(e),
+            Ok(()) => Ok(true),
+        }
+    }
+
+    fn finish_compaction(&mut self) -> Result<(), TableError> {
+        let mut c = match self.compaction.take() {
+            None => return Ok(()),
+            Some(c) => c,
+        };
+        // The sequence, which may have moved on since compaction began,
+        // ends the new log.
+        if self.sequence > 0 {
+            let mut buf: Vec<u8> = Vec::new();
+            frame_control(&mut buf, Control::Sequence(self.sequence));
+            match c.file.write_all(&buf) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+            c.stats.append(buf.len(), buf.len());
+        }
+        let f = match c.file.into_inner() {
+            Err(e) => return Err(TableError::IOError(e.into())),
+            Ok(f) => f,
+        };
 
-        // On all unixes we ought to be able to do the rename
-        // and hold onto f as our file handle, but we will just close
-        // before we rename for now.
-        drop(f);
+        // Unless the OS is left to manage durability, make sure the
+        // new contents are on disk before they replace the old.
+        if self.options.durability != Durability::Os {
+            match f.sync_all() {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+        }
 
-        // Rename it to be the main file.
-        match rename(newpath, path) {
+        // Rename it to be the main file. The new file stays open
+        // across the rename and becomes the one we append to.
+        let mut newpath = c.path.clone();
+        newpath.push('~');
+        match rename(newpath, &c.path) {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
             Ok(()) => (),
         };
+        self.file = Some(LogWriter::new(f, self.options.buffer_size, self.options.durability));
+        self.stats = c.stats;
+        match write_hint::<K, V>(&c.path, &self.options.codecs) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        self.options.diagnostics.compacted(&c.path, c.started.elapsed());
+        Ok(())
+    }
 
-        // Open it again.
-        let f_or_e = OpenOptions::new().append(true).open(path);
-        match f_or_e {
+    /// Writes the table to a new checkpoint and restarts the log from
+    /// it, so that opening the table replays only the records written
+    /// since. Returns the generation of the checkpoint.
+    pub fn checkpoint(&mut self) -> Result<u64, TableError> {
+        match self.file {
+            None => return Err(TableError::NotWritable),
+            Some(_) => (),
+        };
+        if self.segments.is_some() {
+            return Err(TableError::Unsupported("checkpointing a segmented table"));
+        }
+        let path = self.path.clone();
+        if self.compaction.is_some() {
+            match self.compact(&path) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        let durable = self.options.durability != Durability::Os;
+        match if durable { self.sync() } else { self.flush() } {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        let gen = match next_checkpoint(&path) {
+            Err(e) => return Err(e),
+            Ok(gen) => gen,
+        };
+        match write_checkpoint(&path, gen, &self.map, &self.expiries, &self.options.codecs, durable) {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
-            Ok(f) => { self.file = Some(f); },
+            Ok(()) => (),
+        };
+        let (file, stats) = match self.options.restart_log::<K, V>(&path, gen, self.sequence) {
+            Err(e) => return Err(e),
+            Ok(r) => r,
         };
+        self.file = Some(file);
+        self.stats = stats;
+        Ok(gen)
+    }
 
-        Ok(())
+    // Returns the point the table log has reached, flushing any
+    // buffered writes.
+    pub(crate) fn log_point(&mut self) -> Result<BackupPoint, TableError> {
+        if self.file.is_some() {
+            match self.flush() {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        match metadata(&self.path) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(m) => Ok(BackupPoint { dev: m.dev(), ino: m.ino(), offset: self.stats.valid() as u64 }),
+        }
     }
 
-    pub fn get(&self, key: &i64) -> Option<&Vec<u8>> {
-        self.map.get(key)
+    // Returns the current contents of the table as a compacted log.
+    pub(crate) fn compacted(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        self.compacted_part(&self.snapshot(), None, usize::max_value(), &mut buf);
+        buf
     }
 
-    pub fn insert(&mut self, key: i64, value: Vec<u8>) -> Result<Option<Vec<u8>>, TableError> {
-        // Get the file handle (which is rw if present).
-        let mut f = match self.file {
-            None => return Err(TableError::NotWritable),
-            Some(ref mut f) => f,
+    // Appends to buf the entries of snapshot, as a compacted log of the
+    // table would hold them, which follow the entry for key after, or
+    // the header of the log if after is None, until buf holds at least
+    // max bytes. Returns the last key appended, or None once the end of
+    // the log has been appended.
+    pub(crate) fn compacted_part(&self, snapshot: &Snapshot<K, V>, after: Option<&K>, max: usize, buf: &mut Vec<u8>) ->
+        Option<K>
+    {
+        let start = match after {
+            None => {
+                self.options.header::<K, V>().encode(buf).unwrap();
+                Bound::Unbounded
+            },
+            Some(key) => Bound::Excluded(key),
         };
-        // Append the new value to the file.
-        match key.encode(&mut f) {
+        let mut compression = CompressionStats::default();
+        for (key, value) in snapshot.map.range((start, Bound::Unbounded)) {
+            self.options.frame_entry(buf, key, value, snapshot.expiries.get(key), snapshot.now, &mut compression);
+            if buf.len() >= max {
+                return Some(key.clone());
+            }
+        }
+        if snapshot.sequence > 0 {
+            frame_control(buf, Control::Sequence(snapshot.sequence));
+        }
+        None
+    }
+
+    // Returns the point the table log has reached and the bytes
+    // appended to it since point since, or fails with BackupMismatch if
+    // the log has been replaced since. No more than max bytes are read,
+    // ending with a whole record outside any batch, unless the first
+    // record or batch is longer, when the point returned is where they
+    // end.
+    pub(crate) fn appended_since(&mut self, since: &BackupPoint, max: usize) ->
+        Result<(BackupPoint, Vec<u8>), TableError>
+    {
+        if self.segments.is_some() {
+            return Err(TableError::Unsupported("reading the log of a segmented table"));
+        }
+        let point = match self.log_point() {
+            Err(e) => return Err(e),
+            Ok(p) => p,
+        };
+        if !point.follows(since) {
+            return Err(TableError::BackupMismatch);
+        }
+        let f = match File::open(&self.path) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(f) => f,
+        };
+        let appended = (point.offset - since.offset) as usize;
+        let mut len = appended.min(max.max(1));
+        loop {
+            let mut bytes = vec![0u8; len];
+            match f.read_exact_at(&mut bytes, since.offset) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+            if len == appended {
+                return Ok((point, bytes));
+            }
+            let whole = whole_records(&bytes);
+            if whole > 0 {
+                bytes.truncate(whole);
+                return Ok((BackupPoint { offset: since.offset + whole as u64, ..point }, bytes));
+            }
+            len = appended.min(len * 2);
+        }
+    }
+
+    // Applies records appended to the table log elsewhere, such as by
+    // the primary of a replica, to the table. The records must be
+    // complete.
+    pub(crate) fn apply_log(&mut self, bytes: &[u8]) -> Result<(), TableError>
+        where V: Clone
+    {
+        let mut contents = Contents {
+            map: ::std::mem::replace(Arc::make_mut(&mut self.map), BTreeMap::new()),
+            expiries: ::std::mem::replace(Arc::make_mut(&mut self.expiries), Expiries::new()),
+            sizes: ::std::mem::replace(&mut self.sizes, BTreeMap::new()),
+            sequence: self.sequence,
+        };
+        let truncated = self.stats.truncated();
+        let replayed = replay_coded(&mut &bytes[..], &mut self.stats, &mut contents, &self.options.codecs);
+        *Arc::make_mut(&mut self.map) = contents.map;
+        *Arc::make_mut(&mut self.expiries) = contents.expiries;
+        self.sizes = contents.sizes;
+        self.sequence = contents.sequence;
+        match replayed {
+            Err(de) => Err(TableError::DecodeError(de)),
+            Ok(()) if self.stats.truncated() > truncated => Err(TableError::DecodeError(DecodeError::Corrupt)),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Writes the current contents of the table to `path` as a compacted
+    /// log, which is synced to disk, and records beside it the point
+    /// the table log had reached (see `backup`), which is returned.
+    pub fn backup_to(&mut self, path: &str) -> Result<BackupPoint, TableError> {
+        let point = match self.log_point() {
+            Err(e) => return Err(e),
+            Ok(p) => p,
+        };
+        match write_synced(path, &self.compacted()).and_then(|()| point.write(&backup_point_path(path))) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(point),
+        }
+    }
+
+    /// Copies the records appended to the table log since point `since`
+    /// to an increment at `path`, returning the point it goes up to.
+    /// Fails with `TableError::BackupMismatch` if the log has been
+    /// replaced since by a compaction or checkpoint.
+    pub fn backup_since(&mut self, since: &BackupPoint, path: &str) -> Result<BackupPoint, TableError> {
+        let (point, bytes) = match self.appended_since(since, usize::max_value()) {
+            Err(e) => return Err(e),
+            Ok(r) => r,
+        };
+        match write_increment(path, since, &point, &bytes) {
+            Err(ioerr) => Err(TableError::IOError(ioerr)),
+            Ok(()) => Ok(point),
+        }
+    }
+
+    // Starts a new active segment of a segmented table.
+    fn roll(&mut self) -> Result<(), TableError> {
+        let (path, id) = match self.segments {
+            None => return Ok(()),
+            Some(ref segs) => {
+                let seq = match segs.live.last() {
+                    Some(id) => id.hi + 1,
+                    None => 1,
+                };
+                (segment_path(&segs.dir, SegmentId::new(seq)), SegmentId::new(seq))
+            },
+        };
+        // The full segment is made durable before the next one starts,
+        // and given a hint now that it will not change.
+        if self.file.is_some() {
+            let done = if self.options.durability == Durability::Os { self.flush() } else { self.sync() };
+            match done {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+            if let Some(ref segs) = self.segments {
+                if let Some(id) = segs.live.last() {
+                    match write_hint::<K, V>(&segment_path(&segs.dir, *id), &self.options.codecs) {
+                        Err(e) => return Err(e),
+                        Ok(()) => (),
+                    };
+                }
+            }
+        }
+        let f_or_e = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path);
+        let f = match f_or_e {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(f) => f,
+        };
+        if let Some(ref mut segs) = self.segments {
+            segs.live.push(id);
+            segs.active_start = self.stats.valid();
+        }
+        self.file = Some(LogWriter::new(f, self.options.buffer_size, self.options.durability));
+        self.write_header(&self.options.header::<K, V>())
+    }
+
+    /// Rewrites all the segments of a segmented table but the active
+    /// one as a single segment, holding only the entries whose latest
+    /// record is in them. A table which is not segmented is compacted.
+    pub fn merge(&mut self) -> Result<(), TableError> {
+        let (dir, live) = match self.segments {
+            None => {
+                let path = self.path.clone();
+                return self.compact(&path);
+            },
+            Some(ref segs) => (segs.dir.clone(), segs.live.clone()),
+        };
+        if live.len() < 2 {
+            return Ok(());
+        }
+        let started = Instant::now();
+        match self.flush() {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+
+        // Keys written since the active segment started have their
+        // latest record there, so are left out of the merged segment.
+        let active = live[live.len() - 1];
+        let mut touched = KeyLocations(BTreeMap::new(), 0);
+        let mut active_stats = DecodeStats::default();
+        let f = match File::open(segment_path(&dir, active)) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(f) => f,
+        };
+        match self.options.read_log::<K, V, _>(&f, None, &mut touched, &mut active_stats) {
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        };
+
+        let id = SegmentId { lo: live[0].lo, hi: live[live.len() - 2].hi };
+        let path = segment_path(&dir, id);
+        let mut newpath = path.clone();
+        newpath.push('~');
+        let f = match OpenOptions::new().write(true).create(true).truncate(true).open(&newpath) {
+            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+            Ok(f) => f,
+        };
+        let mut w = io::BufWriter::with_capacity(self.options.buffer_size, f);
+        let mut merged = DecodeStats::default();
+        let mut compression = CompressionStats::default();
+        let mut buf: Vec<u8> = Vec::new();
+        self.options.header::<K, V>().encode(&mut buf).unwrap();
+        let now = now_millis();
+        for (key, value) in self.map.iter() {
+            if !touched.0.contains_key(key) {
+                match self.options.frame_entry(&mut buf, key, value, self.expiries.get(key), now, &mut compression) {
+                    Some(size) => self.sizes.insert(key.clone(), size),
+                    None => self.sizes.remove(key),
+                };
+            }
+            if buf.len() >= self.options.buffer_size {
+                match w.write_all(&buf) {
+                    Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                    Ok(()) => (),
+                };
+                merged.append(buf.len(), 0);
+                buf.clear();
+            }
+        }
+        // The segments merged may hold the latest sequence.
+        let mut sequence = 0;
+        if self.sequence > 0 {
+            let start = buf.len();
+            frame_control(&mut buf, Control::Sequence(self.sequence));
+            sequence = buf.len() - start;
+        }
+        match w.write_all(&buf) {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
             Ok(()) => (),
         };
-        match value.as_slice().encode(&mut f) {
+        merged.append(buf.len(), sequence);
+        merged.add_compression(&compression);
+        let f = match w.into_inner() {
+            Err(e) => return Err(TableError::IOError(e.into())),
+            Ok(f) => f,
+        };
+        if self.options.durability != Durability::Os {
+            match f.sync_all() {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            };
+        }
+
+        // Once the merged segment is in place it supersedes the others,
+        // so a crash while they are being removed loses nothing.
+        match rename(newpath, &path) {
             Err(ioerr) => return Err(TableError::IOError(ioerr)),
             Ok(()) => (),
         };
+        for old in &live[..live.len() - 1] {
+            if *old != id {
+                let old_path = segment_path(&dir, *old);
+                match remove_file(&old_path) {
+                    Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                    Ok(()) => (),
+                };
+                let _ = remove_file(hint_path(&old_path));
+            }
+        }
+        match write_hint::<K, V>(&path, &self.options.codecs) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        if let Some(ref mut segs) = self.segments {
+            segs.live = vec![id, active];
+            segs.active_start = merged.valid();
+        }
+        merged.add(&active_stats);
+        self.stats = merged;
+        self.options.diagnostics.compacted(&dir, started.elapsed());
+        Ok(())
+    }
+
+    // Writes buf to the log, accounting for it in stats.
+    fn append(&mut self, buf: &[u8], size: usize, discarded: usize, compression: &CompressionStats) ->
+        Result<(), TableError>
+    {
+        match self.file {
+            None => return Err(TableError::NotWritable),
+            Some(ref mut f) => match f.write_all(buf) {
+                Err(ioerr) => return Err(TableError::IOError(ioerr)),
+                Ok(()) => (),
+            },
+        };
+        self.stats.append(size, discarded);
+        self.stats.add_compression(compression);
+        Ok(())
+    }
+
+    // Writes buf to the new log of an online compaction.
+    fn append_compacted(&mut self, buf: &[u8], size: usize, discarded: usize, compression: &CompressionStats) ->
+        Result<(), TableError>
+    {
+        match self.compaction {
+            None => Ok(()),
+            Some(ref mut c) => match c.file.write_all(buf) {
+                Err(ioerr) => Err(TableError::IOError(ioerr)),
+                Ok(()) => {
+                    c.stats.append(size, discarded);
+                    c.stats.add_compression(compression);
+                    Ok(())
+                },
+            },
+        }
+    }
+
+    fn has_copied(&self, key: &K) -> bool {
+        match self.compaction {
+            Some(ref c) => c.has_copied(key),
+            None => false,
+        }
+    }
+
+    // Applies the durability and compaction policies after a write.
+    fn written(&mut self) -> Result<(), TableError> {
+        match self.committed() {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        let full = match self.segments {
+            Some(ref segs) => self.stats.valid() - segs.active_start >= segs.max_size,
+            None => false,
+        };
+        if full {
+            match self.roll() {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        let policy = match self.options.auto_compact {
+            None => return Ok(()),
+            Some(policy) => policy,
+        };
+        if self.compaction.is_none() {
+            if self.stats.valid() < policy.min_size || self.stats.garbage_ratio() < policy.ratio {
+                return Ok(());
+            }
+            match self.start_compaction() {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        match self.compact_step(policy.step) {
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    // Whether key has expired, though it has not yet been swept.
+    fn is_expired(&self, key: &K) -> bool {
+        self.expiries.len() > 0 && self.expiries.is_expired(key, now_millis())
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.map.get(key)
+    }
+
+    /// Returns the largest key in the table, if any.
+    pub fn max_key(&self) -> Option<&K> {
+        match self.last() {
+            Some((key, _)) => Some(key),
+            None => None,
+        }
+    }
+
+    /// Returns the entry with the smallest key, if any.
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.into_iter().next()
+    }
+
+    /// Returns the entry with the largest key, if any.
+    pub fn last(&self) -> Option<(&K, &V)> {
+        self.into_iter().next_back()
+    }
+
+    /// Iterates in key order over the entries with keys in `range`.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Unexpired<'_, K, Range<'_, K, V>> {
+        Unexpired::new(self.map.range(range), &self.expiries)
+    }
+
+    /// Iterates in reverse key order over the entries with keys in `range`.
+    pub fn range_rev<R: RangeBounds<K>>(&self, range: R) -> Rev<Unexpired<'_, K, Range<'_, K, V>>> {
+        self.range(range).rev()
+    }
+
+    /// Iterates in key order over the entries from `key` onwards.
+    pub fn iter_from(&self, key: &K) -> Unexpired<'_, K, Range<'_, K, V>> {
+        self.range((Bound::Included(key), Bound::Unbounded))
+    }
+
+    /// Returns when the next key to expire does, if any key has a time
+    /// to live.
+    pub fn next_expiry(&self) -> Option<SystemTime> {
+        self.expiries.next().map(|t| UNIX_EPOCH + Duration::from_millis(t))
+    }
+
+    /// Returns the number of milliseconds until `sweep_expired` has
+    /// keys to remove, or -1 if none will expire, as the reactor's
+    /// `Scheduler::due` does, so that a scheduler can sweep the table
+    /// as its keys expire.
+    pub fn sweep_due(&self) -> i32 {
+        self.expiries.due()
+    }
+
+    /// Removes the keys which have expired, as a single batch, and
+    /// returns how many there were.
+    pub fn sweep_expired(&mut self) -> Result<usize, TableError>
+        where V: Clone
+    {
+        let keys = self.expiries.expired(now_millis());
+        let n = keys.len();
+        if n == 0 {
+            return Ok(0);
+        }
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.remove(key);
+        }
+        match self.write(batch) {
+            Err(e) => Err(e),
+            Ok(()) => Ok(n),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, TableError>
+        where V: Clone
+    {
+        self.insert_record(key, value, None, None)
+    }
+
+    /// Sets the value for `key` until `ttl` from now, after which it is
+    /// hidden until it is swept by `sweep_expired` or compaction.
+    pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> Result<Option<V>, TableError>
+        where V: Clone
+    {
+        self.insert_record(key, value, Some(expiry_after(ttl)), None)
+    }
+
+    // Inserts the value for key, which expires at expires if set, and
+    // records the sequence as having moved on to sequence if set.
+    fn insert_record(&mut self, key: K, value: V, expires: Option<u64>, sequence: Option<u64>) ->
+        Result<Option<V>, TableError>
+        where V: Clone
+    {
+        // Append the new value to the file.
+        let mut buf: Vec<u8> = Vec::new();
+        let mut compression = CompressionStats::default();
+        if let Some(n) = sequence {
+            frame_control(&mut buf, Control::Sequence(n));
+        }
+        // Like other control records, the sequence is counted as garbage.
+        let control = buf.len();
+        if let Some(t) = expires {
+            frame_control(&mut buf, Control::Expires(t));
+        }
+        let start = buf.len();
+        self.options.frame_record(&mut buf, &key, Some(&value), &mut compression);
+        let size = buf.len() - start;
+        let discarded = self.sizes.get(&key).cloned().unwrap_or(0) + control;
+        match self.append(&buf, buf.len(), discarded, &compression) {
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        };
+        if self.has_copied(&key) {
+            match self.append_compacted(&buf, buf.len(), discarded, &compression) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
         // Update the map in memory.
-        Ok(self.map.insert(key, value))
+        if let Some(n) = sequence {
+            self.sequence = n;
+        }
+        let expired = self.is_expired(&key);
+        if expires.is_some() || self.expiries.get(&key).is_some() {
+            Arc::make_mut(&mut self.expiries).set(&key, expires);
+        }
+        self.sizes.insert(key.clone(), size);
+        let old = Arc::make_mut(&mut self.map).insert(key, value);
+        let old = if expired { None } else { old };
+        match self.written() {
+            Err(e) => Err(e),
+            Ok(()) => Ok(old),
+        }
     }
 
-    pub fn remove(&mut self, key: &i64) -> Result<Option<Vec<u8>>, TableError> {
-        // Get the file handle (which is rw if present).
-        let mut f = match self.file {
+    /// Applies all the changes in `batch` as a single atomic write: if
+    /// the write is interrupted, none of them will be seen when the
+    /// table is next opened.
+    pub fn write(&mut self, batch: WriteBatch<K, V>) -> Result<(), TableError>
+        where V: Clone
+    {
+        if batch.ops.len() == 0 {
+            return Ok(());
+        }
+        match self.file {
             None => return Err(TableError::NotWritable),
-            Some(ref mut f) => f,
+            Some(_) => (),
         };
-        // Append (key, None) to the file.
-        match key.encode(&mut f) {
-            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+
+        // The batch as a whole goes to the log, and the part of it
+        // for keys already copied to the new log of a compaction.
+        let mut buf: Vec<u8> = Vec::new();
+        let mut cbuf: Vec<u8> = Vec::new();
+        let mut discarded = 0;
+        let (mut cdiscarded, mut ccount) = (0, 0);
+        let (mut compression, mut ccompression) = (CompressionStats::default(), CompressionStats::default());
+        // The size of the record of each change.
+        let mut sizes = Vec::with_capacity(batch.ops.len());
+        {
+            // Later changes in the batch replace earlier ones, and a
+            // removal leaves no record to be replaced.
+            let mut pending: BTreeMap<&K, Option<usize>> = BTreeMap::new();
+            for &(ref key, ref value) in &batch.ops {
+                let start = buf.len();
+                self.options.frame_record(&mut buf, key, value.as_ref(), &mut compression);
+                let size = buf.len() - start;
+                let old = match pending.get(key) {
+                    Some(s) => *s,
+                    None => self.sizes.get(key).cloned(),
+                };
+                let d = old.unwrap_or(0) + if value.is_none() { size } else { 0 };
+                discarded += d;
+                if self.has_copied(key) {
+                    self.options.frame_record(&mut cbuf, key, value.as_ref(), &mut ccompression);
+                    cdiscarded += d;
+                    ccount += 1;
+                }
+                pending.insert(key, if value.is_some() { Some(size) } else { None });
+                sizes.push(size);
+            }
+        }
+        let n = batch.ops.len() as u64;
+        let mut framed: Vec<u8> = Vec::new();
+        frame_control(&mut framed, Control::BatchBegin(n));
+        let size = buf.len();
+        framed.extend(buf);
+        frame_control(&mut framed, Control::BatchCommit(n));
+        let controls = framed.len() - size;
+        match self.append(&framed, framed.len(), discarded + controls, &compression) {
+            Err(e) => return Err(e),
             Ok(()) => (),
         };
-        match None.encode(&mut f) {
-            Err(ioerr) => return Err(TableError::IOError(ioerr)),
+        if ccount > 0 {
+            let mut cframed: Vec<u8> = Vec::new();
+            frame_control(&mut cframed, Control::BatchBegin(ccount));
+            let csize = cbuf.len();
+            cframed.extend(cbuf);
+            frame_control(&mut cframed, Control::BatchCommit(ccount));
+            let ccontrols = cframed.len() - csize;
+            match self.append_compacted(&cframed, cframed.len(), cdiscarded + ccontrols, &ccompression) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+
+        for &(ref key, _) in &batch.ops {
+            if self.expiries.get(key).is_some() {
+                Arc::make_mut(&mut self.expiries).set(key, None);
+            }
+        }
+        {
+            let map = Arc::make_mut(&mut self.map);
+            for ((key, value), size) in batch.ops.into_iter().zip(sizes) {
+                match value {
+                    Some(value) => {
+                        self.sizes.insert(key.clone(), size);
+                        map.insert(key, value);
+                    },
+                    None => {
+                        self.sizes.remove(&key);
+                        map.remove(&key);
+                    },
+                }
+            }
+        }
+        self.written()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>, TableError>
+        where V: Clone
+    {
+        // Append (key, None) to the file.
+        let mut buf: Vec<u8> = Vec::new();
+        frame_record(&mut buf, key, None::<&V>);
+        // The removal is as redundant as what it removes.
+        let size = buf.len();
+        let discarded = self.sizes.get(key).cloned().unwrap_or(0) + size;
+        let none = CompressionStats::default();
+        match self.append(&buf, size, discarded, &none) {
+            Err(e) => return Err(e),
             Ok(()) => (),
         };
-        Ok(self.map.remove(key))
+        if self.has_copied(key) {
+            match self.append_compacted(&buf, size, discarded, &none) {
+                Err(e) => return Err(e),
+                Ok(()) => (),
+            };
+        }
+        let expired = self.is_expired(key);
+        if self.expiries.get(key).is_some() {
+            Arc::make_mut(&mut self.expiries).set(key, None);
+        }
+        self.sizes.remove(key);
+        let old = Arc::make_mut(&mut self.map).remove(key);
+        let old = if expired { None } else { old };
+        match self.written() {
+            Err(e) => Err(e),
+            Ok(()) => Ok(old),
+        }
+    }
+
+    /// Sets `key` to `new`, or removes it if `new` is None, provided its
+    /// value is `expected`, where None means it has none.
+    pub fn compare_and_swap(&mut self, key: K, expected: Option<&V>, new: Option<V>) ->
+        Result<WriteOutcome<V>, TableError>
+        where V: PartialEq + Clone
+    {
+        if self.get(&key) != expected {
+            return Ok(WriteOutcome::NotWritten(self.get(&key).cloned()));
+        }
+        self.write_value(key, new)
+    }
+
+    /// Sets `key` to `value` unless it already has a value.
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> Result<WriteOutcome<V>, TableError>
+        where V: Clone
+    {
+        if let Some(current) = self.get(&key) {
+            return Ok(WriteOutcome::NotWritten(Some(current.clone())));
+        }
+        self.write_value(key, Some(value))
+    }
+
+    /// Sets `key` to the value `f` returns given its current value, or
+    /// removes it if `f` returns None.
+    pub fn update_with<F>(&mut self, key: K, f: F) -> Result<WriteOutcome<V>, TableError>
+        where F: FnOnce(Option<&V>) -> Option<V>, V: Clone
+    {
+        let new = f(self.get(&key));
+        self.write_value(key, new)
+    }
+
+    // Sets or removes the value for key. Removing a key which has no
+    // value writes nothing, but is reported as written, since the key is
+    // left as asked.
+    fn write_value(&mut self, key: K, value: Option<V>) -> Result<WriteOutcome<V>, TableError>
+        where V: Clone
+    {
+        let result = match value {
+            Some(value) => self.insert(key, value),
+            None if self.get(&key).is_none() => return Ok(WriteOutcome::Written(None)),
+            None => self.remove(&key),
+        };
+        match result {
+            Err(e) => Err(e),
+            Ok(old) => Ok(WriteOutcome::Written(old)),
+        }
     }
 }
 
-impl IntoIterator for Table {
-    type Item = (i64, Vec<u8>);
-    type IntoIter = ::std::collections::btree_map::IntoIter<i64,Vec<u8>>;
+impl<V> Table<i64, V>
+    where V: Encode + Decode + TypeDescriptor + Clone
+{
+    /// Inserts `value` under the next key of the table's sequence and
+    /// returns the key. The sequence is recorded in the log, so a key
+    /// is never issued twice, even if its row has since been removed;
+    /// it starts above the largest key in the table, and at 0. Fails
+    /// with `TableError::SequenceExhausted` once `i64::max_value()` has
+    /// been issued or is in the table.
+    pub fn insert_auto(&mut self, value: V) -> Result<i64, TableError> {
+        if self.sequence > i64::max_value() as u64 {
+            return Err(TableError::SequenceExhausted);
+        }
+        let mut key = self.sequence as i64;
+        if let Some((max, _)) = self.map.iter().next_back() {
+            if *max >= key {
+                key = match max.checked_add(1) {
+                    None => return Err(TableError::SequenceExhausted),
+                    Some(k) => k,
+                };
+            }
+        }
+        match self.insert_record(key, value, None, Some(key as u64 + 1)) {
+            Err(e) => Err(e),
+            Ok(_) => Ok(key),
+        }
+    }
+}
+
+impl<K, V> Table<K, V> {
+    /// Returns a read-only view of the table as it is now, which is
+    /// unaffected by later writes.
+    ///
+    /// Taking a snapshot is cheap, but the first write to the table
+    /// while any snapshot is alive copies the table in memory.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        Snapshot {
+            map: self.map.clone(),
+            expiries: self.expiries.clone(),
+            now: now_millis(),
+            position: self.stats.valid(),
+            sequence: self.sequence,
+        }
+    }
+}
+
+impl<V> Table<Vec<u8>, V> {
+    /// Iterates in key order over the entries whose keys start with
+    /// `prefix`.
+    pub fn prefix(&self, prefix: &[u8]) -> Unexpired<'_, Vec<u8>, Range<'_, Vec<u8>, V>> {
+        // The first key after the prefix range is the prefix with its
+        // last byte below 0xFF incremented and the rest dropped.
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&0xFF) {
+            end.pop();
+        }
+        let upper = match end.pop() {
+            None => Bound::Unbounded,
+            Some(b) => {
+                end.push(b + 1);
+                Bound::Excluded(end)
+            },
+        };
+        Unexpired::new(self.map.range((Bound::Included(prefix.to_vec()), upper)), &self.expiries)
+    }
+}
+
+/// A point-in-time view of a table, returned by `Table::snapshot`.
+///
+/// Keys which had expired when the snapshot was taken are hidden, as
+/// they are by the table; those which expire later are not.
+pub struct Snapshot<K, V> {
+    map: Arc<BTreeMap<K, V>>,
+    expiries: Arc<Expiries<K>>,
+    // When the snapshot was taken.
+    now: u64,
+    position: usize,
+    sequence: u64,
+}
+
+impl<K: Ord + Clone, V> Snapshot<K, V> {
+    /// The length of the table log when the snapshot was taken.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn len(&self) -> usize {
+        if self.expiries.len() == 0 {
+            return self.map.len();
+        }
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if self.expiries.is_expired(key, self.now) {
+            return None;
+        }
+        self.map.get(key)
+    }
+
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.iter().next()
+    }
+
+    pub fn last(&self) -> Option<(&K, &V)> {
+        self.iter().next_back()
+    }
+
+    pub fn iter(&self) -> Unexpired<'_, K, ::std::collections::btree_map::Iter<'_, K, V>> {
+        Unexpired::at(self.map.iter(), &self.expiries, self.now)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Unexpired<'_, K, Range<'_, K, V>> {
+        Unexpired::at(self.map.range(range), &self.expiries, self.now)
+    }
+
+    pub fn range_rev<R: RangeBounds<K>>(&self, range: R) -> Rev<Unexpired<'_, K, Range<'_, K, V>>> {
+        self.range(range).rev()
+    }
+
+    pub fn iter_from(&self, key: &K) -> Unexpired<'_, K, Range<'_, K, V>> {
+        self.range((Bound::Included(key), Bound::Unbounded))
+    }
+}
+
+impl<K, V> Clone for Snapshot<K, V> {
+    fn clone(&self) -> Snapshot<K, V> {
+        Snapshot {
+            map: self.map.clone(),
+            expiries: self.expiries.clone(),
+            now: self.now,
+            position: self.position,
+            sequence: self.sequence,
+        }
+    }
+}
+
+impl<'a, K: Ord + Clone, V> IntoIterator for &'a Snapshot<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Unexpired<'a, K, ::std::collections::btree_map::Iter<'a, K, V>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.map.into_iter()
+        self.iter()
+    }
+}
+
+/// The result of a conditional write, such as by
+/// `Table::compare_and_swap`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriteOutcome<V> {
+    /// The write was made, replacing the value held, if any.
+    Written(Option<V>),
+    /// The write was not made. Holds the value the key has, if any.
+    NotWritten(Option<V>),
+}
+
+impl<V> WriteOutcome<V> {
+    /// Whether the write was made.
+    pub fn is_written(&self) -> bool {
+        match *self {
+            WriteOutcome::Written(_) => true,
+            WriteOutcome::NotWritten(_) => false,
+        }
+    }
+}
+
+/// A group of inserts and removals to be applied atomically by
+/// `Table::write` or `LazyTable::write`.
+pub struct WriteBatch<K, V> {
+    pub(crate) ops: Vec<(K, Option<V>)>,
+}
+
+impl<K, V> WriteBatch<K, V> {
+    pub fn new() -> WriteBatch<K, V> {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.ops.push((key, Some(value)));
+    }
+
+    pub fn remove(&mut self, key: K) {
+        self.ops.push((key, None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl<K, V> Default for WriteBatch<K, V> {
+    fn default() -> WriteBatch<K, V> {
+        WriteBatch::new()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> IntoIterator for Table<K, V> {
+    type Item = (K, V);
+    type IntoIter = ::std::collections::btree_map::IntoIter<K, V>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        // The map is only copied if a snapshot still shares it.
+        let mut map = match Arc::try_unwrap(::std::mem::replace(&mut self.map, Arc::new(BTreeMap::new()))) {
+            Ok(map) => map,
+            Err(shared) => (*shared).clone(),
+        };
+        for key in self.expiries.expired(now_millis()) {
+            map.remove(&key);
+        }
+        map.into_iter()
     }
 }
 
-impl<'a> IntoIterator for &'a Table {
-    type Item = (&'a i64, &'a Vec<u8>);
-    type IntoIter = ::std::collections::btree_map::Iter<'a, i64, Vec<u8>>;
+impl<'a, K: Ord + Clone, V> IntoIterator for &'a Table<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Unexpired<'a, K, ::std::collections::btree_map::Iter<'a, K, V>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.map.iter()
+        Unexpired::new(self.map.iter(), &self.expiries)
+    }
+}
+
+#[cfg(test)]
+use lazy_table::LazyTable;
+
+#[cfg(test)]
+use test_util::{remove_table, temp_path};
+
+#[test]
+fn test_i64_values() {
+    let path = temp_path("i64-values");
+    {
+        let mut t: Table<i64, i64> = Table::open_rw(&path).unwrap();
+        t.insert(1, 5).unwrap();
+        t.insert(2, -1).unwrap();
+        t.remove(&1).unwrap();
+    }
+    // -1 must not be mistaken for the removal marker when replayed.
+    let t: Table<i64, i64> = Table::open(&path).unwrap();
+    assert_eq!(t.get(&1), None);
+    assert_eq!(t.get(&2), Some(&-1));
+    assert_eq!(t.into_iter().count(), 1);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_torn_tail_recovery() {
+    let path = temp_path("torn");
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        t.insert(1, b"one".to_vec()).unwrap();
+        t.insert(2, b"two".to_vec()).unwrap();
+    }
+    // Simulate a crash part way through appending a third record.
+    {
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[0x05, 0x12, 0x34]).unwrap();
+    }
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.stats().truncated(), 3);
+        t.insert(3, b"three".to_vec()).unwrap();
+    }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t.stats().truncated(), 0);
+    assert_eq!(t.get(&2), Some(&b"two".to_vec()));
+    assert_eq!(t.get(&3), Some(&b"three".to_vec()));
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_damaged_length() {
+    let path = temp_path("length");
+    let mut offsets = Vec::new();
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        for i in 0..10 {
+            offsets.push(t.stats().valid());
+            t.insert(i, vec![i as u8; 20]).unwrap();
+        }
+    }
+    // A length running past the end of the file in the middle of the
+    // log is corruption, not a torn tail to be cut off.
+    let mut buf = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut buf).unwrap();
+    buf[offsets[5]] = 0xFD;
+    buf[offsets[5] + 1] = 0xFF;
+    File::create(&path).unwrap().write_all(&buf).unwrap();
+    match Table::<i64, Vec<u8>>::open_rw(&path) {
+        Err(TableError::DecodeError(DecodeError::Corrupt)) => (),
+        _ => panic!("expected the damage to be found"),
+    };
+    assert_eq!(::std::fs::metadata(&path).unwrap().len(), buf.len() as u64);
+    // As is a length too large to be read at all.
+    buf[offsets[5]] = 0xFE;
+    for b in &mut buf[offsets[5] + 1..offsets[5] + 9] {
+        *b = 0xFF;
+    }
+    File::create(&path).unwrap().write_all(&buf).unwrap();
+    match Table::<i64, Vec<u8>>::open(&path) {
+        Err(TableError::DecodeError(DecodeError::Corrupt)) => (),
+        _ => panic!("expected the damage to be found"),
+    };
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_group_commit() {
+    let path = temp_path("group");
+    let len = |path: &str| ::std::fs::metadata(path).unwrap().len();
+    {
+        let mut t: Table<i64, Vec<u8>> = TableOptions::new()
+            .write(true)
+            .durability(Durability::GroupCommit { records: 3, interval: Duration::from_secs(3600) })
+            .open(&path)
+            .unwrap();
+        let empty = len(&path);
+        t.insert(1, b"one".to_vec()).unwrap();
+        t.insert(2, b"two".to_vec()).unwrap();
+        assert_eq!(len(&path), empty);
+        t.remove(&1).unwrap();
+        assert!(len(&path) > empty);
+
+        t.insert(3, b"three".to_vec()).unwrap();
+        let synced = len(&path);
+        t.flush().unwrap();
+        assert!(len(&path) > synced);
+    }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t.get(&1), None);
+    assert_eq!(t.get(&3), Some(&b"three".to_vec()));
+    drop(t);
+
+    // Writes left pending by a table which falls idle are synced once
+    // the interval has passed, by whoever watches sync_due.
+    let mut t: Table<i64, Vec<u8>> = TableOptions::new()
+        .write(true)
+        .durability(Durability::GroupCommit { records: 100, interval: Duration::from_millis(50) })
+        .open(&path)
+        .unwrap();
+    assert_eq!(t.sync_due(), -1);
+    let before = len(&path);
+    t.insert(4, b"four".to_vec()).unwrap();
+    let due = t.sync_due();
+    assert!(due > 0 && due <= 50);
+    assert!(!t.sync_pending().unwrap());
+    ::std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(t.sync_due(), 0);
+    assert!(t.sync_pending().unwrap());
+    assert!(len(&path) > before);
+    assert_eq!(t.sync_due(), -1);
+    drop(t);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_write_batch() {
+    let path = temp_path("batch");
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        t.insert(1, b"one".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(2, b"two".to_vec());
+        batch.remove(1);
+        t.write(batch).unwrap();
+        assert_eq!(t.get(&1), None);
+    }
+    let committed = ::std::fs::metadata(&path).unwrap().len();
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.get(&1), None);
+        assert_eq!(t.get(&2), Some(&b"two".to_vec()));
+        let mut batch = WriteBatch::new();
+        batch.insert(3, b"three".to_vec());
+        batch.insert(4, b"four".to_vec());
+        t.write(batch).unwrap();
+    }
+    // Cut off the second batch's commit record.
+    {
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = f.metadata().unwrap().len();
+        f.set_len(len - 2).unwrap();
+    }
+    {
+        let t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.get(&3), None);
+        assert_eq!(t.get(&4), None);
+        assert_eq!(t.stats().valid() as u64, committed);
+    }
+    assert_eq!(::std::fs::metadata(&path).unwrap().len(), committed);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_online_compaction() {
+    let path = temp_path("online");
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    for i in 0..10 {
+        t.insert(i, format!("v{}", i).into_bytes()).unwrap();
+        t.insert(i, format!("w{}", i).into_bytes()).unwrap();
+    }
+    assert!(t.stats().garbage_ratio() > 0.4);
+
+    t.start_compaction().unwrap();
+    assert_eq!(t.compact_step(3).unwrap(), false);
+    // Changes to keys before and after those copied so far.
+    t.insert(1, b"x1".to_vec()).unwrap();
+    t.remove(&2).unwrap();
+    t.insert(7, b"x7".to_vec()).unwrap();
+    t.remove(&8).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert(0, b"y0".to_vec());
+    batch.insert(9, b"y9".to_vec());
+    t.write(batch).unwrap();
+    while !t.compact_step(3).unwrap() {}
+    assert!(!t.is_compacting());
+    t.insert(10, b"x10".to_vec()).unwrap();
+    let (read, discarded) = (t.stats().read(), t.stats().discarded());
+    let expected: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+
+    // The live stats match those of reading the new log.
+    let t2: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t2.stats().read(), read);
+    assert_eq!(t2.stats().discarded(), discarded);
+    assert_eq!(::std::fs::metadata(&path).unwrap().len() as usize, read);
+    let items: Vec<(i64, Vec<u8>)> = t2.into_iter().collect();
+    assert_eq!(items, expected);
+    assert_eq!(items.len(), 9);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_auto_compaction() {
+    let path = temp_path("auto");
+    {
+        let mut t: Table<i64, Vec<u8>> = TableOptions::new()
+            .write(true)
+            .auto_compact(AutoCompact { ratio: 0.5, min_size: 100, step: 2 })
+            .open(&path)
+            .unwrap();
+        for i in 0..1000 {
+            t.insert(i % 5, format!("value {}", i).into_bytes()).unwrap();
+        }
+        assert!(t.stats().read() < 400);
+    }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t.get(&4), Some(&b"value 999".to_vec()));
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_header_checks() {
+    let path = temp_path("header");
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        t.insert(1, b"one".to_vec()).unwrap();
+    }
+    match Table::<Vec<u8>, Vec<u8>>::open(&path) {
+        Err(TableError::TypeMismatch { key, value }) => {
+            assert_eq!(key, "i64");
+            assert_eq!(value, "bytes");
+        },
+        _ => panic!("expected a type mismatch"),
+    };
+
+    // A file without a header is upgraded when opened for writing.
+    let mut data: BTreeMap<i64, Vec<u8>> = BTreeMap::new();
+    data.insert(1, b"one".to_vec());
+    data.insert(2, b"two".to_vec());
+    {
+        let mut f = File::create(&path).unwrap();
+        data.encode(&mut f).unwrap();
+    }
+    {
+        let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+        assert_eq!(t.get(&2), Some(&b"two".to_vec()));
+    }
+    {
+        let t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.get(&2), Some(&b"two".to_vec()));
+    }
+    let mut f = File::open(&path).unwrap();
+    let mut stats = DecodeStats::default();
+    let h = Header::decode_stats(&mut io::BufReader::new(&mut f), &mut stats).unwrap();
+    assert_eq!(h, Some(Header::new::<i64, Vec<u8>>()));
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+    assert_eq!(items, data.into_iter().collect::<Vec<(i64, Vec<u8>)>>());
+
+    // So is one written by the original table, before records were
+    // framed: here `set 1 one`, `set 2 two`, `set 300 three-hundred`,
+    // `set -5 minus-five`, `remove 2`, `set 1 uno` and `set 100000` to
+    // 300 bytes.
+    let mut legacy: Vec<u8> = vec![
+        0x01, 0x03, b'o', b'n', b'e',
+        0x02, 0x03, b't', b'w', b'o',
+        0x81, 0x01, 0x2c, 0x0d, b't', b'h', b'r', b'e', b'e', b'-', b'h', b'u', b'n', b'd', b'r', b'e', b'd',
+        0xfb, 0x0a, b'm', b'i', b'n', b'u', b's', b'-', b'f', b'i', b'v', b'e',
+        0x02, 0xff,
+        0x01, 0x03, b'u', b'n', b'o',
+        0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x86, 0xa0, 0xfd, 0x01, 0x2c,
+    ];
+    legacy.extend(vec![b'x'; 300]);
+    File::create(&path).unwrap().write_all(&legacy).unwrap();
+    let expected = vec![
+        (-5, b"minus-five".to_vec()),
+        (1, b"uno".to_vec()),
+        (300, b"three-hundred".to_vec()),
+        (100000, vec![b'x'; 300]),
+    ];
+    {
+        let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+        assert_eq!(t.stats().read(), legacy.len());
+        assert_eq!((&t).into_iter().map(|(k, v)| (*k, v.clone())).collect::<Vec<_>>(), expected);
+    }
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        t.insert(2, b"two again".to_vec()).unwrap();
+    }
+    let mut f = File::open(&path).unwrap();
+    let h = Header::decode_stats(&mut io::BufReader::new(&mut f), &mut DecodeStats::default()).unwrap();
+    assert_eq!(h, Some(Header::new::<i64, Vec<u8>>()));
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t.get(&2), Some(&b"two again".to_vec()));
+    assert_eq!(t.get(&100000), Some(&vec![b'x'; 300]));
+    assert_eq!((&t).into_iter().count(), 5);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_locking() {
+    let path = temp_path("lock");
+    {
+        let _w: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        match Table::<i64, Vec<u8>>::open_rw(&path) {
+            Err(TableError::Locked) => (),
+            _ => panic!("expected the table to be locked"),
+        };
+        match Table::<i64, Vec<u8>>::open(&path) {
+            Err(TableError::Locked) => (),
+            _ => panic!("expected the table to be locked"),
+        };
+    }
+    {
+        let _r1: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+        let _r2: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+        let r = TableOptions::new().write(true).wait_for_lock(Duration::from_millis(30)).open::<i64, Vec<u8>>(&path);
+        match r {
+            Err(TableError::Locked) => (),
+            _ => panic!("expected the table to be locked"),
+        };
+    }
+    // The lock follows the file across a compaction.
+    let mut w: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    w.insert(1, b"one".to_vec()).unwrap();
+    w.compact(&path).unwrap();
+    match Table::<i64, Vec<u8>>::open(&path) {
+        Err(TableError::Locked) => (),
+        _ => panic!("expected the table to be locked"),
+    };
+    drop(w);
+    let r: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(r.get(&1), Some(&b"one".to_vec()));
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_scans() {
+    let path = temp_path("scans");
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.first(), None);
+        for i in 0..10 {
+            t.insert(i, format!("v{}", i).into_bytes()).unwrap();
+        }
+        let keys = |it: &mut dyn Iterator<Item = (&i64, &Vec<u8>)>| it.map(|(k, _)| *k).collect::<Vec<i64>>();
+        assert_eq!(keys(&mut t.range(3..6)), vec![3, 4, 5]);
+        assert_eq!(keys(&mut t.range(..=2)), vec![0, 1, 2]);
+        assert_eq!(keys(&mut t.range_rev(7..)), vec![9, 8, 7]);
+        assert_eq!(keys(&mut t.iter_from(&8)), vec![8, 9]);
+        assert_eq!(t.first(), Some((&0, &b"v0".to_vec())));
+        assert_eq!(t.last(), Some((&9, &b"v9".to_vec())));
+    }
+    ::std::fs::remove_file(&path).unwrap();
+
+    let mut t: Table<Vec<u8>, Vec<u8>> = Table::open_rw(&path).unwrap();
+    for k in &[&b"a"[..], b"ab", b"ab\xff", b"ab\xff\x00", b"ac", b"b"] {
+        t.insert(k.to_vec(), b"".to_vec()).unwrap();
+    }
+    let keys = |prefix: &[u8]| t.prefix(prefix).map(|(k, _)| k.clone()).collect::<Vec<Vec<u8>>>();
+    assert_eq!(keys(b"ab"), vec![b"ab".to_vec(), b"ab\xff".to_vec(), b"ab\xff\x00".to_vec()]);
+    assert_eq!(keys(b"ab\xff"), vec![b"ab\xff".to_vec(), b"ab\xff\x00".to_vec()]);
+    assert_eq!(keys(b"").len(), 6);
+    assert_eq!(keys(b"c").len(), 0);
+    drop(t);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_snapshot() {
+    let path = temp_path("snapshot");
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    t.insert(1, b"one".to_vec()).unwrap();
+    t.insert(2, b"two".to_vec()).unwrap();
+    let snap = t.snapshot();
+    assert_eq!(snap.position(), t.stats().valid());
+    t.insert(1, b"uno".to_vec()).unwrap();
+    t.remove(&2).unwrap();
+    t.insert(3, b"three".to_vec()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.insert(4, b"four".to_vec());
+    t.write(batch).unwrap();
+
+    let items: Vec<(&i64, &Vec<u8>)> = snap.iter().collect();
+    assert_eq!(items, vec![(&1, &b"one".to_vec()), (&2, &b"two".to_vec())]);
+    assert_eq!(snap.get(&3), None);
+    assert_eq!(t.get(&1), Some(&b"uno".to_vec()));
+    assert_eq!((&t).into_iter().count(), 3);
+    drop(t);
+    assert_eq!(snap.len(), 2);
+    ::std::fs::remove_file(&path).unwrap();
+
+    // A key which expires after the snapshot is taken stays in it.
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    t.insert_with_ttl(1, b"one".to_vec(), Duration::from_millis(50)).unwrap();
+    let snap = t.snapshot();
+    ::std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(t.get(&1), None);
+    assert_eq!(snap.get(&1), Some(&b"one".to_vec()));
+    assert_eq!(snap.iter().count(), 1);
+    assert_eq!(snap.len(), 1);
+    drop(t);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_segments() {
+    let path = temp_path("segments");
+    let count = |path: &str| ::std::fs::read_dir(path).unwrap().count();
+    let mut opts = TableOptions::new();
+    opts.write(true).segmented(200);
+    {
+        let mut t: Table<i64, Vec<u8>> = opts.open(&path).unwrap();
+        for i in 0..50 {
+            t.insert(i % 10, format!("value {}", i).into_bytes()).unwrap();
+        }
+        t.remove(&3).unwrap();
+        assert!(count(&path) > 5);
+    }
+    {
+        let mut t: Table<i64, Vec<u8>> = opts.open(&path).unwrap();
+        assert_eq!(t.get(&9), Some(&b"value 49".to_vec()));
+        assert_eq!(t.get(&3), None);
+        t.insert(1, b"one".to_vec()).unwrap();
+        t.merge().unwrap();
+        // The lock file, the merged segment and its hint, and the
+        // active segment.
+        assert_eq!(count(&path), 4);
+        t.insert(2, b"two".to_vec()).unwrap();
+        let (read, discarded) = (t.stats().read(), t.stats().discarded());
+        drop(t);
+
+        let t: Table<i64, Vec<u8>> = TableOptions::new().segmented(200).open(&path).unwrap();
+        assert_eq!(t.stats().read(), read);
+        assert_eq!(t.stats().discarded(), discarded);
+        let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+        assert_eq!(items.len(), 9);
+        assert_eq!(items[1], (1, b"one".to_vec()));
+        assert_eq!(items[2], (2, b"two".to_vec()));
+        assert_eq!(items[8], (9, b"value 49".to_vec()));
+    }
+    ::std::fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn test_hints() {
+    let path = temp_path("hints");
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        for i in 0..40 {
+            t.insert(i % 10, format!("value {}", i).into_bytes()).unwrap();
+        }
+        t.remove(&2).unwrap();
+        t.compact(&path).unwrap();
+        t.insert(1, b"one".to_vec()).unwrap();
+        t.remove(&3).unwrap();
+    }
+    assert!(::std::fs::metadata(hint_path(&path)).is_ok());
+    // Opening from the hint gives the same stats as replaying the log.
+    let stats = |path: &str| {
+        let t: Table<i64, Vec<u8>> = Table::open(path).unwrap();
+        (t.stats().read(), t.stats().discarded())
+    };
+    let hinted = stats(&path);
+    rename(hint_path(&path), format!("{}~", path)).unwrap();
+    assert_eq!(stats(&path), hinted);
+    rename(format!("{}~", path), hint_path(&path)).unwrap();
+
+    // Damage the checksum of the first record after the header, which
+    // only replaying the log would notice.
+    let header = Header::new::<i64, Vec<u8>>().encode_size() as u64;
+    {
+        let f = OpenOptions::new().write(true).open(&path).unwrap();
+        ::std::os::unix::fs::FileExt::write_all_at(&f, &[0], header + 1).unwrap();
+    }
+    let (read, discarded) = {
+        let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+        assert_eq!(t.get(&1), Some(&b"one".to_vec()));
+        assert_eq!(t.get(&3), None);
+        assert_eq!(t.get(&4), Some(&b"value 34".to_vec()));
+        (t.stats().read(), t.stats().discarded())
+    };
+    assert_eq!(read, ::std::fs::metadata(&path).unwrap().len() as usize);
+    let t: LazyTable<i64, Vec<u8>> = LazyTable::open(&path).unwrap();
+    assert_eq!(t.len(), 8);
+    assert_eq!(t.stats().discarded(), discarded);
+    drop(t);
+
+    // A copy of the log has a different inode, so the hint is ignored.
+    let copy = format!("{}.copy", path);
+    ::std::fs::copy(&path, &copy).unwrap();
+    rename(&copy, &path).unwrap();
+    match Table::<i64, Vec<u8>>::open(&path) {
+        Err(TableError::DecodeError(DecodeError::Corrupt)) => (),
+        _ => panic!("expected the damage to be found"),
+    };
+    remove_table(&path);
+}
+
+#[test]
+fn test_checkpoints() {
+    let path = temp_path("checkpoints");
+    let expected: Vec<(i64, Vec<u8>)>;
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        for i in 0..20 {
+            t.insert(i, format!("v{}", i).into_bytes()).unwrap();
+        }
+        assert_eq!(t.checkpoint().unwrap(), 1);
+        t.insert(1, b"one".to_vec()).unwrap();
+        t.remove(&2).unwrap();
+        assert_eq!(t.checkpoint().unwrap(), 2);
+        t.insert(3, b"three".to_vec()).unwrap();
+        t.remove(&4).unwrap();
+        expected = (&t).into_iter().map(|(k, v)| (*k, v.clone())).collect();
+    }
+    {
+        // Only the records since the last checkpoint are replayed.
+        let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+        assert!(t.stats().read() < 100);
+        let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+        assert_eq!(items, expected);
+    }
+    // A lazy table reads the values written before the checkpoint
+    // from it.
+    let lazy_items = |path: &str| -> Vec<(i64, Vec<u8>)> {
+        let t: LazyTable<i64, Vec<u8>> = LazyTable::open(path).unwrap();
+        t.iter().map(|e| e.map(|(k, v)| (*k, (*v).clone()))).collect::<Result<_, _>>().unwrap()
+    };
+    assert_eq!(lazy_items(&path), expected);
+
+    // With the newest checkpoint damaged, the one before is used along
+    // with the log it was replaced by.
+    {
+        let f = OpenOptions::new().write(true).open(checkpoint_path(&path, 2)).unwrap();
+        let len = f.metadata().unwrap().len();
+        ::std::os::unix::fs::FileExt::write_all_at(&f, &[0xAA], len - 1).unwrap();
+    }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+    assert_eq!(items, expected);
+    assert_eq!(lazy_items(&path), expected);
+
+    remove_table(&path);
+}
+
+#[test]
+fn test_compression() {
+    let path = temp_path("compression");
+    let value = |i: i64| format!("{{\"id\": {}, \"name\": \"item\", \"tags\": [\"a\", \"b\", \"c\"]}}", i).repeat(4).into_bytes();
+    let compression;
+    {
+        let mut t: Table<i64, Vec<u8>> = TableOptions::new().write(true).create(true)
+            .compression(Compression::Lz).open(&path).unwrap();
+        for i in 0..20 {
+            t.insert(i, value(i)).unwrap();
+        }
+        // Small values are stored as they are.
+        t.insert(100, b"small".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(20, value(20));
+        batch.remove(0);
+        t.write(batch).unwrap();
+        compression = *t.stats().compression();
+        assert_eq!(compression.records, 21);
+        assert!(compression.ratio() < 0.5);
+    }
+    {
+        // Compressed and plain records can be mixed in one log.
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(*t.stats().compression(), compression);
+        t.insert(1, value(1)).unwrap();
+        assert_eq!(t.get(&1), Some(&value(1)));
+        assert_eq!(t.get(&20), Some(&value(20)));
+        assert_eq!(t.get(&100), Some(&b"small".to_vec()));
+        assert_eq!(t.get(&0), None);
+    }
+    {
+        let t: LazyTable<i64, Vec<u8>> = LazyTable::open(&path).unwrap();
+        assert_eq!(t.get(&20).unwrap().as_deref(), Some(&value(20)));
+        assert_eq!(t.get(&1).unwrap().as_deref(), Some(&value(1)));
+    }
+    {
+        // Compaction keeps compressed values as they are.
+        let mut t: LazyTable<i64, Vec<u8>> = TableOptions::new().write(true)
+            .compression(Compression::Lz).open_lazy(&path).unwrap();
+        t.compact().unwrap();
+        assert_eq!(t.stats().compression().records, 19);
+        assert_eq!(t.get(&2).unwrap().as_deref(), Some(&value(2)));
+    }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t.stats().compression().records, 19);
+    assert_eq!(t.get(&5), Some(&value(5)));
+    assert_eq!(t.stats().discarded(), 0);
+
+    remove_table(&path);
+}
+
+#[test]
+fn test_compressed_garbage() {
+    let path = temp_path("compressed-garbage");
+    let value = |c: u8| vec![c; 10000];
+    let discarded;
+    {
+        let mut t: Table<i64, Vec<u8>> = TableOptions::new().write(true)
+            .compression(Compression::Lz).open(&path).unwrap();
+        let start = t.stats().valid();
+        t.insert(1, value(b'a')).unwrap();
+        let first = t.stats().valid() - start;
+        assert!(first < 100);
+        // What an overwrite makes redundant is the record as stored.
+        t.insert(1, value(b'b')).unwrap();
+        assert_eq!(t.stats().discarded(), first);
+        let mut batch = WriteBatch::new();
+        batch.insert(1, value(b'c'));
+        batch.insert(2, value(b'd'));
+        t.write(batch).unwrap();
+        t.remove(&2).unwrap();
+        assert!(t.stats().garbage_ratio() < 1.0);
+        discarded = t.stats().discarded();
+    }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t.stats().discarded(), discarded);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_codecs() {
+    use codec::{Checksum, Xor};
+
+    let path = temp_path("codecs");
+    let value = |i: i64| format!("secret value {}", i).repeat(3).into_bytes();
+    let expected: Vec<(i64, Vec<u8>)>;
+    {
+        let mut t: Table<i64, Vec<u8>> = TableOptions::new().write(true).codec(Checksum).codec(Xor(0x5A))
+            .compression(Compression::Lz).open(&path).unwrap();
+        for i in 0..10 {
+            t.insert(i, value(i)).unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        batch.insert(10, value(10));
+        batch.remove(0);
+        t.write(batch).unwrap();
+        assert_eq!(t.checkpoint().unwrap(), 1);
+        t.insert(1, b"one".to_vec()).unwrap();
+        expected = (&t).into_iter().map(|(k, v)| (*k, v.clone())).collect();
+    }
+    let mut data = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+    assert!(!data.windows(3).any(|w| w == b"one"));
+
+    // The table can only be opened with the chain it was written with.
+    match Table::<i64, Vec<u8>>::open(&path) {
+        Err(TableError::CodecMismatch(names)) => assert_eq!(names, vec!["crc32".to_string(), "xor-90".to_string()]),
+        _ => panic!("expected a codec mismatch"),
+    };
+    match TableOptions::new().codec(Checksum).codec(Xor(1)).open::<i64, Vec<u8>>(&path) {
+        Err(TableError::CodecMismatch(_)) => (),
+        _ => panic!("expected a codec mismatch"),
+    };
+
+    let mut opts = TableOptions::new();
+    opts.write(true).codec(Checksum).codec(Xor(0x5A));
+    {
+        let mut t: Table<i64, Vec<u8>> = opts.open(&path).unwrap();
+        let items: Vec<(i64, Vec<u8>)> = (&t).into_iter().map(|(k, v)| (*k, v.clone())).collect();
+        assert_eq!(items, expected);
+        t.compact(&path).unwrap();
+    }
+    // Reopened from the hint written by compaction.
+    let t: Table<i64, Vec<u8>> = opts.open(&path).unwrap();
+    let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+    assert_eq!(items, expected);
+    let mut t: LazyTable<i64, Vec<u8>> = opts.open_lazy(&path).unwrap();
+    assert_eq!(t.get(&1).unwrap().as_deref(), Some(&b"one".to_vec()));
+    t.insert(11, value(11)).unwrap();
+    assert_eq!(t.get(&11).unwrap().as_deref(), Some(&value(11)));
+    drop(t);
+    let t: Table<i64, Vec<u8>> = opts.open(&path).unwrap();
+    assert_eq!(t.get(&11), Some(&value(11)));
+    drop(t);
+
+    remove_table(&path);
+}
+
+#[test]
+fn test_ttl() {
+    let path = temp_path("ttl");
+    let hour = Duration::from_secs(3600);
+    let keys = |t: &Table<i64, Vec<u8>>| -> Vec<i64> { t.into_iter().map(|(k, _)| *k).collect() };
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.sweep_due(), -1);
+        t.insert(1, b"one".to_vec()).unwrap();
+        t.insert_with_ttl(2, b"two".to_vec(), Duration::from_millis(0)).unwrap();
+        t.insert_with_ttl(3, b"three".to_vec(), hour).unwrap();
+        t.insert_with_ttl(4, b"four".to_vec(), Duration::from_millis(0)).unwrap();
+        // An expired key is hidden, and replacing it returns nothing.
+        assert_eq!(t.get(&2), None);
+        assert_eq!(t.get(&3), Some(&b"three".to_vec()));
+        assert_eq!(keys(&t), vec![1, 3]);
+        assert_eq!(t.last(), Some((&3, &b"three".to_vec())));
+        assert_eq!(t.insert(4, b"FOUR".to_vec()).unwrap(), None);
+        assert_eq!(t.get(&4), Some(&b"FOUR".to_vec()));
+        let s = t.snapshot();
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.get(&2), None);
+        assert_eq!(t.sweep_due(), 0);
+    }
+    // A lazy table hides expired keys too.
+    {
+        let t: LazyTable<i64, Vec<u8>> = LazyTable::open(&path).unwrap();
+        assert_eq!(t.len(), 3);
+        assert_eq!(t.keys().cloned().collect::<Vec<i64>>(), vec![1, 3, 4]);
+        assert_eq!(t.keys().next_back(), Some(&4));
+        assert_eq!(t.get(&2).unwrap(), None);
+    }
+    // Expiry times survive reopening.
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(keys(&t), vec![1, 3, 4]);
+        let next = t.next_expiry().unwrap();
+        assert!(next <= SystemTime::now());
+        assert_eq!(t.sweep_expired().unwrap(), 1);
+        assert_eq!(t.sweep_expired().unwrap(), 0);
+        let due = t.sweep_due();
+        assert!(due > 3500 * 1000 && due <= 3600 * 1000);
+        t.insert_with_ttl(5, b"five".to_vec(), Duration::from_millis(0)).unwrap();
+        t.compact(&path).unwrap();
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        assert!(!data.windows(4).any(|w| w == b"five"));
+        t.insert(3, b"three".to_vec()).unwrap();
+        // The key compaction left out is hidden until it is swept.
+        assert_eq!(t.get(&5), None);
+        assert_eq!(t.sweep_expired().unwrap(), 1);
+        assert_eq!(t.sweep_due(), -1);
+    }
+    // Expiry is cleared by writing the key without a time to live.
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert!(t.map.get(&5).is_none());
+        assert_eq!(keys(&t), vec![1, 3, 4]);
+        assert_eq!(t.next_expiry(), None);
+        t.insert_with_ttl(6, b"six".to_vec(), hour).unwrap();
+        t.insert_with_ttl(7, b"seven".to_vec(), Duration::from_millis(0)).unwrap();
+        t.checkpoint().unwrap();
+    }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(keys(&t), vec![1, 3, 4, 6]);
+    assert!(t.next_expiry().unwrap() <= SystemTime::now());
+    assert!(t.expiries.get(&6).is_some());
+    let items: Vec<i64> = t.into_iter().map(|(k, _)| k).collect();
+    assert_eq!(items, vec![1, 3, 4, 6]);
+    let t: LazyTable<i64, Vec<u8>> = LazyTable::open(&path).unwrap();
+    assert_eq!(t.keys().cloned().collect::<Vec<i64>>(), vec![1, 3, 4, 6]);
+    assert!(t.next_expiry().unwrap() <= SystemTime::now());
+    drop(t);
+
+    remove_table(&path);
+}
+
+#[test]
+fn test_insert_auto() {
+    let path = temp_path("insert-auto");
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.insert_auto(b"a".to_vec()).unwrap(), 0);
+        assert_eq!(t.insert_auto(b"b".to_vec()).unwrap(), 1);
+        assert_eq!(t.insert_auto(b"c".to_vec()).unwrap(), 2);
+        t.remove(&2).unwrap();
+        // A removed key is not issued again.
+        assert_eq!(t.insert_auto(b"d".to_vec()).unwrap(), 3);
+        t.remove(&3).unwrap();
+        // Nor is one below a key inserted directly.
+        t.insert(10, b"x".to_vec()).unwrap();
+        assert_eq!(t.insert_auto(b"e".to_vec()).unwrap(), 11);
+        t.remove(&11).unwrap();
+        t.remove(&10).unwrap();
+    }
+    // The sequence survives reopening, compaction and checkpoints.
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.insert_auto(b"f".to_vec()).unwrap(), 12);
+        t.remove(&12).unwrap();
+        t.compact(&path).unwrap();
+    }
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.insert_auto(b"g".to_vec()).unwrap(), 13);
+        t.remove(&13).unwrap();
+        t.checkpoint().unwrap();
+    }
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.insert_auto(b"h".to_vec()).unwrap(), 14);
+        t.remove(&14).unwrap();
+        // Keys issued during an online compaction are not lost by it.
+        t.start_compaction().unwrap();
+        t.compact_step(1).unwrap();
+        assert_eq!(t.insert_auto(b"i".to_vec()).unwrap(), 15);
+        t.remove(&15).unwrap();
+        t.compact(&path).unwrap();
+    }
+    let mut t: LazyTable<i64, Vec<u8>> = TableOptions::new().write(true).open_lazy(&path).unwrap();
+    t.compact().unwrap();
+    drop(t);
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    assert_eq!(t.sequence, 16);
+    let items: Vec<i64> = t.into_iter().map(|(k, _)| k).collect();
+    assert_eq!(items, vec![0, 1]);
+
+    remove_table(&path);
+
+    // The sequence of a segmented table survives merging.
+    let dir = temp_path("auto-segments");
+    let _ = ::std::fs::remove_dir_all(&dir);
+    {
+        let mut t: Table<i64, Vec<u8>> = TableOptions::new().write(true).segmented(64).open(&dir).unwrap();
+        for i in 0..10 {
+            assert_eq!(t.insert_auto(vec![b'v'; 20]).unwrap(), i);
+            t.remove(&i).unwrap();
+        }
+        t.merge().unwrap();
+    }
+    let mut t: Table<i64, Vec<u8>> = TableOptions::new().write(true).segmented(64).open(&dir).unwrap();
+    assert_eq!(t.insert_auto(b"v".to_vec()).unwrap(), 10);
+    drop(t);
+    let _ = ::std::fs::remove_dir_all(&dir);
+
+    // The sequence ends at the largest key rather than wrapping.
+    let path = temp_path("auto-max");
+    let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+    t.insert(i64::max_value() - 1, b"x".to_vec()).unwrap();
+    assert_eq!(t.insert_auto(b"y".to_vec()).unwrap(), i64::max_value());
+    t.remove(&i64::max_value()).unwrap();
+    match t.insert_auto(b"z".to_vec()) {
+        Err(TableError::SequenceExhausted) => (),
+        r => panic!("expected the sequence to be exhausted, got {:?}", r),
+    };
+    // Nor can a key follow the largest when it was inserted directly.
+    t.sequence = 0;
+    t.insert(i64::max_value(), b"x".to_vec()).unwrap();
+    match t.insert_auto(b"z".to_vec()) {
+        Err(TableError::SequenceExhausted) => (),
+        r => panic!("expected the sequence to be exhausted, got {:?}", r),
+    };
+    assert_eq!((&t).into_iter().count(), 2);
+    drop(t);
+    ::std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_conditional_writes() {
+    use self::WriteOutcome::*;
+
+    let path = temp_path("conditional");
+    let one = b"one".to_vec();
+    let two = b"two".to_vec();
+    {
+        let mut t: Table<i64, Vec<u8>> = Table::open_rw(&path).unwrap();
+        assert_eq!(t.insert_if_absent(1, one.clone()).unwrap(), Written(None));
+        assert_eq!(t.insert_if_absent(1, two.clone()).unwrap(), NotWritten(Some(one.clone())));
+
+        assert_eq!(t.compare_and_swap(1, Some(&two), Some(b"three".to_vec())).unwrap(), NotWritten(Some(one.clone())));
+        assert_eq!(t.compare_and_swap(1, None, Some(two.clone())).unwrap(), NotWritten(Some(one.clone())));
+        assert_eq!(t.compare_and_swap(1, Some(&one), Some(two.clone())).unwrap(), Written(Some(one.clone())));
+        assert_eq!(t.compare_and_swap(2, None, Some(one.clone())).unwrap(), Written(None));
+        assert_eq!(t.compare_and_swap(2, Some(&one), None).unwrap(), Written(Some(one.clone())));
+        let valid = t.stats().valid();
+        assert_eq!(t.compare_and_swap(2, None, None).unwrap(), Written(None));
+        assert_eq!(t.stats().valid(), valid);
+        assert!(!t.compare_and_swap(3, Some(&one), None).unwrap().is_written());
+
+        let append = |v: Option<&Vec<u8>>| {
+            let mut v = v.cloned().unwrap_or_default();
+            v.push(b'!');
+            Some(v)
+        };
+        assert_eq!(t.update_with(1, append).unwrap(), Written(Some(two.clone())));
+        assert_eq!(t.update_with(4, append).unwrap(), Written(None));
+        assert_eq!(t.update_with(4, |_| None).unwrap(), Written(Some(b"!".to_vec())));
+        assert_eq!(t.update_with(4, |_| None).unwrap(), Written(None));
+
+        // A key which has expired has no value to compare.
+        t.insert_with_ttl(5, one.clone(), Duration::from_millis(0)).unwrap();
+        assert_eq!(t.insert_if_absent(5, two.clone()).unwrap(), Written(None));
     }
+    let t: Table<i64, Vec<u8>> = Table::open(&path).unwrap();
+    let items: Vec<(i64, Vec<u8>)> = t.into_iter().collect();
+    assert_eq!(items, vec![(1, b"two!".to_vec()), (5, two.clone())]);
+    let _ = ::std::fs::remove_file(&path);
 }
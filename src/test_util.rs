@@ -0,0 +1,31 @@
+//! Helpers shared by the tests of the other modules.
+
+use std::fs::remove_file;
+
+use backup::backup_point_path;
+use checkpoint::{checkpoint_path, checkpoints, previous_log_path};
+use hint::hint_path;
+
+/// Returns a path in the temporary directory for the table a test
+/// names `name`, removing any left there by an earlier run.
+pub fn temp_path(name: &str) -> String {
+    let mut p = ::std::env::temp_dir();
+    p.push(format!("table-test-{}-{}.bt", name, ::std::process::id()));
+    let path = p.to_str().unwrap().to_string();
+    remove_table(&path);
+    path
+}
+
+/// Removes the table log at `path` along with the files kept beside
+/// it: its hint, backup point, previous log and checkpoints.
+pub fn remove_table(path: &str) {
+    let _ = remove_file(path);
+    let _ = remove_file(hint_path(path));
+    let _ = remove_file(backup_point_path(path));
+    let _ = remove_file(previous_log_path(path));
+    if let Ok(gens) = checkpoints(path) {
+        for g in gens {
+            let _ = remove_file(checkpoint_path(path, g));
+        }
+    }
+}